@@ -0,0 +1,45 @@
+//! Records what commands print to stdout and stderr in tests.
+
+use std::cell::RefCell;
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Captured>> = const { RefCell::new(None) };
+}
+
+/// The output printed on this thread since `start`.
+#[derive(Debug, Default)]
+pub struct Captured {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Records the output printed from this thread, instead of printing it,
+/// until `finish`.
+pub fn start() {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Captured::default()));
+}
+
+/// Stops recording and returns what was printed.
+pub fn finish() -> Captured {
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
+
+/// Records `text` as printed to stdout, returning `false` when not recording.
+pub fn stdout(text: &str) -> bool {
+    record(text, |captured| &mut captured.stdout)
+}
+
+/// Records `text` as printed to stderr, returning `false` when not recording.
+pub fn stderr(text: &str) -> bool {
+    record(text, |captured| &mut captured.stderr)
+}
+
+fn record(text: &str, stream: impl FnOnce(&mut Captured) -> &mut String) -> bool {
+    CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(captured) => {
+            stream(captured).push_str(text);
+            true
+        }
+        None => false,
+    })
+}
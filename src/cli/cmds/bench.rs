@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Args;
+
+use crate::{
+    cli::{file_or_value, CmdRunner, ContextArgs},
+    clients::{
+        providers::{MaxTokens, ProviderModel, DEFAULT_MAX_TOKENS},
+        ChatCompletionClient,
+    },
+    config::Config,
+    models::{tokenizer_for, Message, Role, Usage},
+    prompts::PromptBuilder,
+};
+
+const SYSTEM_PROMPT: &str = "You are a helpful coding assistant and senior software engineer. Provide the answer and only the answer to the user's request.";
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    /// Sets the comma-separated models to compare
+    #[arg(long, value_delimiter = ',', required = true)]
+    pub models: Vec<String>,
+
+    /// Sets the temperature value
+    #[arg(long)]
+    pub temperature: Option<f32>,
+
+    /// Sets the max tokens value, or `auto` to fit the remaining context
+    #[arg(long)]
+    pub max_tokens: Option<MaxTokens>,
+
+    /// Sets the top-p value
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// Sets the prompt, or `@path` to read it from a file
+    #[arg(short, long, value_parser = file_or_value)]
+    prompt: String,
+
+    #[command(flatten)]
+    pub context: ContextArgs,
+}
+
+struct BenchResult {
+    model: String,
+    latency: Duration,
+    usage: Usage,
+    cost: f64,
+    error: Option<String>,
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Every model gets the same context, so fit it to the smallest budget.
+        let budget = self
+            .models
+            .iter()
+            .filter_map(|name| ProviderModel::get(name.trim()))
+            .map(|model_provider| {
+                let model = model_provider.model;
+                (
+                    model.context_budget(self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS)),
+                    tokenizer_for(&model),
+                )
+            })
+            .min_by_key(|(budget, _)| *budget);
+
+        let context = self.context.read(budget)?;
+
+        let mut data = HashMap::new();
+        data.insert("prompt".to_string(), self.prompt.clone());
+        if let Some(context) = context {
+            data.insert("context".to_string(), context);
+        }
+
+        let content = PromptBuilder::new().build(&data)?;
+
+        print_results(&self.bench(Config::get(), &content).await);
+
+        Ok(())
+    }
+}
+
+impl Cmd {
+    /// Sends `content` to each model in turn, one request per model, and
+    /// returns their metrics in the order the models were given.
+    async fn bench(&self, config: &Config, content: &str) -> Vec<BenchResult> {
+        let mut results = Vec::new();
+
+        for model_name in &self.models {
+            let Some(model_provider) = ProviderModel::get(model_name.trim()) else {
+                results.push(BenchResult {
+                    model: model_name.clone(),
+                    latency: Duration::ZERO,
+                    usage: Usage::default(),
+                    cost: 0.0,
+                    error: Some("unknown model".to_string()),
+                });
+                continue;
+            };
+
+            let mut client = ChatCompletionClient::new(
+                model_provider.provider,
+                model_provider.model.clone(),
+                SYSTEM_PROMPT,
+            )
+            .temperature(self.temperature)
+            .top_p(self.top_p)
+            .max_tokens(self.max_tokens)
+            .anthropic_beta(config.anthropic_beta.clone());
+
+            let msg = Message {
+                role: Role::User,
+                content: content.to_string(),
+                reasoning: None,
+                tool_call_id: None,
+                finish_reason: None,
+                metadata: None,
+            };
+
+            let start = Instant::now();
+            let response = client.send_message(msg).await;
+            let latency = start.elapsed();
+            let usage = client.get_usage();
+
+            results.push(BenchResult {
+                model: model_provider.model.to_string(),
+                latency,
+                usage,
+                cost: config.pricing(&model_provider.model).estimate_cost(&usage),
+                error: response.err().map(|e| e.to_string()),
+            });
+        }
+
+        results
+    }
+}
+
+fn print_results(results: &[BenchResult]) {
+    println!(
+        "{:<20} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "Model", "Latency", "Input", "Output", "Total", "Cost"
+    );
+
+    for result in results {
+        println!("{}", result_row(result));
+    }
+}
+
+/// Formats one row of the results table, or the first line of its error.
+fn result_row(result: &BenchResult) -> String {
+    if let Some(error) = &result.error {
+        format!(
+            "{:<20} error: {}",
+            result.model,
+            error.lines().next().unwrap_or_default()
+        )
+    } else {
+        format!(
+            "{:<20} {:>9.2}s {:>10} {:>10} {:>10} {:>10}",
+            result.model,
+            result.latency.as_secs_f64(),
+            result.usage.input_tokens,
+            result.usage.output_tokens,
+            result.usage.total_tokens(),
+            format!("${:.4}", result.cost),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        clients::{
+            mock_server::{MockResponse, MockServer},
+            providers::Provider,
+        },
+        config::DataDir,
+    };
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        bench: Cmd,
+    }
+
+    #[tokio::test]
+    async fn sends_one_request_per_model_and_reports_each() {
+        DataDir::set_save_history(false);
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        std::env::set_var(Provider::Anthropic.key_var(), "test-key");
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                &json!({
+                    "choices": [{ "message": { "role": "assistant", "content": "Hi." }, "finish_reason": "stop" }],
+                    "usage": { "prompt_tokens": 1000, "completion_tokens": 500 }
+                }),
+            ),
+            MockResponse::json(
+                200,
+                &json!({
+                    "role": "assistant",
+                    "content": [{ "type": "text", "text": "Hello." }],
+                    "stop_reason": "end_turn",
+                    "usage": { "input_tokens": 2000, "output_tokens": 250 }
+                }),
+            ),
+        ])
+        .await;
+        let config: Config = serde_json::from_str(
+            r#"{"pricing": {
+                "gpt-4o": {"input": 1.0, "output": 2.0},
+                "claude-3-haiku-20240307": {"input": 0.5, "output": 4.0}
+            }}"#,
+        )
+        .unwrap();
+        let cli = TestCli::parse_from(["bench", "--models", "gpt-4o,haiku", "-p", "hi"]);
+
+        let results = cli.bench.bench(&config, "hi").await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body["model"], "gpt-4o");
+        assert_eq!(requests[1].body["model"], "claude-3-haiku-20240307");
+
+        let models: Vec<_> = results.iter().map(|result| result.model.as_str()).collect();
+        assert_eq!(models, ["GPT-4o", "Claude 3 Haiku"]);
+        assert!(results.iter().all(|result| result.error.is_none()));
+
+        let usage: Vec<_> = results
+            .iter()
+            .map(|result| (result.usage.input_tokens, result.usage.output_tokens))
+            .collect();
+        assert_eq!(usage, [(1000, 500), (2000, 250)]);
+        // 1000 * $1 + 500 * $2, then 2000 * $0.5 + 250 * $4, per million tokens.
+        assert_eq!(results[0].cost, 0.002);
+        assert_eq!(results[1].cost, 0.002);
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_models_without_sending() {
+        let server = MockServer::start(Vec::new()).await;
+        let cli = TestCli::parse_from(["bench", "--models", "no-such-model", "-p", "hi"]);
+
+        let results = cli.bench.bench(&Config::default(), "hi").await;
+
+        assert!(server.requests().is_empty());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].error.as_deref(), Some("unknown model"));
+    }
+
+    #[test]
+    fn reports_latency_tokens_and_cost() {
+        let row = result_row(&BenchResult {
+            model: "GPT-4o".to_string(),
+            latency: Duration::from_millis(1500),
+            usage: Usage {
+                input_tokens: 100,
+                output_tokens: 50,
+            },
+            cost: 0.00125,
+            error: None,
+        });
+
+        let columns: Vec<_> = row.split_whitespace().collect();
+        assert_eq!(columns, ["GPT-4o", "1.50s", "100", "50", "150", "$0.0013"]);
+    }
+
+    #[test]
+    fn reports_only_the_first_line_of_an_error() {
+        let row = result_row(&BenchResult {
+            model: "GPT-4o".to_string(),
+            latency: Duration::ZERO,
+            usage: Usage::default(),
+            cost: 0.0,
+            error: Some("request failed\ncaused by: timeout".to_string()),
+        });
+
+        assert_eq!(
+            row.trim_end(),
+            format!("{:<20} error: request failed", "GPT-4o")
+        );
+    }
+}
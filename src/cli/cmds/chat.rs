@@ -1,20 +1,26 @@
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use anyhow::Result;
 use clap::Args;
 use rustyline::{error::ReadlineError, DefaultEditor};
-use termimad::MadSkin;
+use termimad::{gray, MadSkin};
 
 use crate::{
-    cli::CmdRunner,
+    cli::{status, CmdRunner},
     clients::{
-        providers::{Model, Provider},
-        ChatCompletionClient,
+        providers::{MaxTokens, Model, Provider, ReasoningEffort},
+        ChatCompletionClient, LLMClient,
     },
-    config::DataDir,
+    config::{Config, DataDir, Profile, Theme},
     errors::CAError,
     models::{Message, Role},
-    prompts::PromptBuilder,
+    operations::session_title,
+    prompts::{provider_system_prompt, PromptBuilder},
 };
 
 #[derive(Clone, Args)]
@@ -27,19 +33,167 @@ pub struct Cmd {
     #[arg(long)]
     pub temperature: Option<f32>,
 
-    /// Sets the max tokens value
+    /// Sets the max tokens value, or `auto` to fit the remaining context
     #[arg(long)]
-    pub max_tokens: Option<u32>,
+    pub max_tokens: Option<MaxTokens>,
 
     /// Sets the top-p value
     #[arg(long)]
     pub top_p: Option<f32>,
+
+    /// Continues the most recent session
+    #[arg(long = "continue")]
+    pub continue_session: bool,
+
+    /// Re-sends the last user turn of a saved session file and prints the new
+    /// response; the file is left unchanged
+    #[arg(long, conflicts_with = "continue_session")]
+    pub replay: Option<PathBuf>,
+
+    /// Ignores piped stdin instead of sending it as context
+    #[arg(long)]
+    pub no_context: bool,
+
+    /// Sets the markdown theme, or `none` to print responses as plain text
+    #[arg(long, value_enum)]
+    pub theme: Option<Theme>,
+
+    /// Prints responses as plain text, the same as `--theme none`
+    #[arg(long, conflicts_with = "theme")]
+    pub no_markdown: bool,
+
+    /// Sets the reasoning effort for models that support it
+    #[arg(long, value_enum)]
+    pub reasoning: Option<ReasoningEffort>,
+
+    /// Names the saved session with a title generated by the configured `title_model`
+    #[arg(long)]
+    pub auto_title: bool,
+
+    /// Prints the reasoning of reasoning models, dimmed, above each answer
+    /// instead of collapsing it to a single line
+    #[arg(long)]
+    pub show_reasoning: bool,
+}
+
+impl Cmd {
+    /// Fills the options not given on the command line from `profile`.
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        self.model = self.model.take().or_else(|| profile.model.clone());
+        self.temperature = self.temperature.or(profile.temperature);
+        self.max_tokens = self
+            .max_tokens
+            .or_else(|| profile.max_tokens.map(MaxTokens::Fixed));
+        self.top_p = self.top_p.or(profile.top_p);
+    }
+
+    /// Returns the skin responses are rendered with, or `None` for plain text.
+    fn skin(&self, config: &Config) -> Option<MadSkin> {
+        if self.no_markdown {
+            return None;
+        }
+        self.theme
+            .or(config.theme)
+            .map_or_else(|| Some(MadSkin::default()), Theme::skin)
+    }
+
+    /// Sends the last user message of the session at `path` again, after the
+    /// messages that preceded it, and saves the result as a new session.
+    async fn replay(
+        &self,
+        client: ChatCompletionClient,
+        path: &Path,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let data_dir = DataDir::new();
+        let mut messages = data_dir.load_messages::<Message>(path)?;
+
+        let last_user = messages
+            .iter()
+            .rposition(|msg| matches!(msg.role, Role::User))
+            .ok_or_else(|| format!("{} has no user message to replay", path.display()))?;
+        let user_msg = messages.remove(last_user);
+        messages.truncate(last_user);
+
+        status(format!("Replaying the last turn of {}", path.display()));
+
+        let mut client = LLMClient::from(client.history(messages));
+        if let Some(msg) = client.send_message(user_msg).await? {
+            print_message(self.skin(Config::get()).as_ref(), &msg, self.show_reasoning);
+        }
+
+        self.save_session(&client.get_message_history()).await;
+
+        Ok(())
+    }
+
+    /// Saves the session, naming it when `--auto-title` is set.
+    async fn save_session(&self, messages: &[Message]) {
+        let data_dir = DataDir::new();
+        let Some(id) = data_dir.save_messages(messages) else {
+            return;
+        };
+
+        if self.auto_title && messages.iter().any(|msg| matches!(msg.role, Role::User)) {
+            data_dir.save_title(id, &session_title(messages).await);
+        }
+    }
+}
+
+/// Splits the response into its reasoning section and its answer. The
+/// reasoning is quoted in full when `show_reasoning` is set and collapsed to a
+/// single line otherwise.
+fn sections(msg: &Message, show_reasoning: bool) -> (Option<String>, &str) {
+    let reasoning = msg
+        .reasoning
+        .as_deref()
+        .map(str::trim)
+        .filter(|reasoning| !reasoning.is_empty())
+        .map(|reasoning| {
+            if show_reasoning {
+                let quoted: Vec<String> =
+                    reasoning.lines().map(|line| format!("> {line}")).collect();
+                quoted.join("\n")
+            } else {
+                let lines = reasoning.lines().count();
+                let plural = if lines == 1 { "" } else { "s" };
+                format!("> reasoning hidden ({lines} line{plural}), use --show-reasoning to see it")
+            }
+        });
+
+    (reasoning, &msg.content)
+}
+
+/// Prints the response, with any reasoning dimmed above it.
+fn print_message(skin: Option<&MadSkin>, msg: &Message, show_reasoning: bool) {
+    print!("{}", render_message(skin, msg, show_reasoning));
+}
+
+/// Renders the response with `skin`, or as plain text without one.
+fn render_message(skin: Option<&MadSkin>, msg: &Message, show_reasoning: bool) -> String {
+    let mut rendered = String::new();
+
+    let (reasoning, answer) = sections(msg, show_reasoning);
+    if let Some(reasoning) = reasoning {
+        match skin {
+            Some(skin) => {
+                let mut dimmed = skin.clone();
+                dimmed.set_fg(gray(11));
+                rendered.push_str(&dimmed.term_text(&reasoning).to_string());
+            }
+            None => rendered.push_str(&format!("{reasoning}\n")),
+        }
+        rendered.push('\n');
+    }
+    match skin {
+        Some(skin) => rendered.push_str(&skin.term_text(answer).to_string()),
+        None => rendered.push_str(&format!("{answer}\n")),
+    }
+
+    rendered
 }
 
 impl CmdRunner for Cmd {
     async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let system_prompt = "You are a helpful coding assistant. Provide answers in markdown format unless instructed otherwise. If the request is ambiguous, ask questions. If you don't know the answer, admit you don't.";
-
         let model = self.model.clone().map_or("default".to_string(), |m| m);
         let model_provider = match model.as_str() {
             "gpt-4-turbo" => (Provider::OpenAI, Model::GPT4Turbo),
@@ -49,17 +203,45 @@ impl CmdRunner for Cmd {
             "sonnet3" => (Provider::Anthropic, Model::Claude3Sonnet),
             "haiku3" => (Provider::Anthropic, Model::Claude3Haiku),
             "codestral" => (Provider::Mistral, Model::Codestral),
-            _ => (Provider::OpenAI, Model::GPT4o),
+            _ => {
+                let resolved = Config::get().resolve_model(self.model.as_deref());
+                (resolved.provider, resolved.model)
+            }
         };
 
-        let mut client =
-            ChatCompletionClient::new(model_provider.0, model_provider.1, system_prompt)
+        let system_prompt = provider_system_prompt(
+            "chat",
+            model_provider.0,
+            "You are a helpful coding assistant. Provide answers in markdown format unless instructed otherwise. If the request is ambiguous, ask questions. If you don't know the answer, admit you don't.",
+        );
+
+        let mut chat_client =
+            ChatCompletionClient::new(model_provider.0, model_provider.1.clone(), &system_prompt)
                 .temperature(self.temperature)
                 .top_p(self.top_p)
-                .max_tokens(self.max_tokens);
+                .max_tokens(self.max_tokens)
+                .reasoning_effort(self.reasoning)
+                .anthropic_beta(Config::get().anthropic_beta.clone());
+
+        if let Some(path) = &self.replay {
+            return self.replay(chat_client, path).await;
+        }
+
+        if self.continue_session {
+            let data_dir = DataDir::new();
+            if let Some(session) = data_dir.latest_session() {
+                let messages = data_dir.load_messages::<Message>(&session)?;
+                status(format!("Continuing session {}", session.display()));
+                chat_client = chat_client.history(messages);
+            } else {
+                status("No previous session found, starting a new one.");
+            }
+        }
+
+        let mut client = LLMClient::from(chat_client);
 
         let context: Result<String, CAError> = {
-            if atty::is(atty::Stream::Stdin) {
+            if self.no_context || atty::is(atty::Stream::Stdin) {
                 Err(CAError::Input)
             } else {
                 match std::io::read_to_string(std::io::stdin()) {
@@ -71,19 +253,40 @@ impl CmdRunner for Cmd {
 
         let mut rl = DefaultEditor::new()?;
 
-        let skin = MadSkin::default();
+        let config = Config::get();
+
+        let skin = self.skin(config);
 
-        let prompt_builder = PromptBuilder::new()?;
+        let prompt_builder = PromptBuilder::new();
 
         let mut is_first_iteration = true;
 
+        // Set after a Ctrl-C so that a second consecutive one exits.
+        let mut interrupted = false;
+
         loop {
             let readline = rl.readline("> ");
             match readline {
                 Ok(line) if line.trim() == "bye" => {
                     break;
                 }
+                Ok(line) if line.trim_start().starts_with("/system") => {
+                    interrupted = false;
+
+                    let instruction = line.trim_start()["/system".len()..].trim();
+                    if instruction.is_empty() {
+                        println!("Usage: /system <instruction>");
+                    } else {
+                        if client.add_system_message(instruction) {
+                            println!("System instruction added.");
+                        } else {
+                            println!("This model takes no system instructions.");
+                        }
+                    }
+                }
                 Ok(line) => {
+                    interrupted = false;
+
                     let mut data = HashMap::new();
                     data.insert("prompt".to_string(), line);
                     if is_first_iteration {
@@ -97,17 +300,44 @@ impl CmdRunner for Cmd {
                     let user_msg = Message {
                         role: Role::User,
                         content: prompt_builder.build(&data)?,
+                        reasoning: None,
+                        tool_call_id: None,
+                        finish_reason: None,
+                        metadata: None,
+                    };
+
+                    let aborted = AtomicBool::new(false);
+                    let abort = async {
+                        if tokio::signal::ctrl_c().await.is_ok() {
+                            aborted.store(true, Ordering::Relaxed);
+                        } else {
+                            std::future::pending::<()>().await;
+                        }
                     };
 
-                    let response = client.send_message(user_msg).await?;
+                    let response = client
+                        .send_message_streaming(user_msg, |_| {}, abort)
+                        .await?;
 
                     if let Some(msg) = response {
                         println!("\n");
-                        skin.print_text(&msg.content);
+                        print_message(skin.as_ref(), &msg, self.show_reasoning);
                         println!("\n");
                     }
+
+                    if aborted.load(Ordering::Relaxed) {
+                        interrupted = true;
+                        println!("[aborted, press Ctrl-C again to exit]\n");
+                    }
                 }
-                Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                Err(ReadlineError::Interrupted) => {
+                    if interrupted {
+                        break;
+                    }
+                    interrupted = true;
+                    println!("Press Ctrl-C again to exit.");
+                }
+                Err(ReadlineError::Eof) => {
                     break;
                 }
                 Err(err) => {
@@ -117,8 +347,152 @@ impl CmdRunner for Cmd {
             }
         }
 
-        DataDir::new().save_messages(&client.get_message_history());
+        self.save_session(&client.get_message_history()).await;
+
+        let usage = client.get_usage();
+        let cost = config.pricing(&model_provider.1).estimate_cost(&usage);
+        status(format!(
+            "Tokens: {} in, {} out (${cost:.4})",
+            usage.input_tokens, usage.output_tokens
+        ));
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::clients::mock_server::{MockResponse, MockServer};
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        cmd: Cmd,
+    }
+
+    fn cmd(args: &[&str]) -> Cmd {
+        TestCli::parse_from(std::iter::once("chat").chain(args.iter().copied())).cmd
+    }
+
+    fn answer(content: &str, reasoning: Option<&str>) -> Message {
+        Message {
+            role: Role::Assistant,
+            content: content.to_string(),
+            reasoning: reasoning.map(ToString::to_string),
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn prints_plain_text_without_a_theme() {
+        let msg = answer("Use **`cargo fmt`**.", Some("The user asked about style."));
+
+        let skin = Theme::None.skin();
+        let rendered = render_message(skin.as_ref(), &msg, false);
+
+        assert!(!rendered.contains('\x1b'));
+        assert_eq!(
+            rendered,
+            "> reasoning hidden (1 line), use --show-reasoning to see it\n\nUse **`cargo fmt`**.\n"
+        );
+
+        let styled = render_message(Theme::Dark.skin().as_ref(), &msg, false);
+        assert!(styled.contains('\x1b'));
+    }
+
+    #[test]
+    fn splits_reasoning_and_answer_into_two_sections() {
+        let msg = answer(
+            "Use `cargo fmt`.",
+            Some("\nThe user asked about style.\nrustfmt is the standard.\n"),
+        );
+
+        let (reasoning, content) = sections(&msg, true);
+        assert_eq!(
+            reasoning.as_deref(),
+            Some("> The user asked about style.\n> rustfmt is the standard.")
+        );
+        assert_eq!(content, "Use `cargo fmt`.");
+
+        let (collapsed, _) = sections(&msg, false);
+        assert_eq!(
+            collapsed.as_deref(),
+            Some("> reasoning hidden (2 lines), use --show-reasoning to see it")
+        );
+
+        let (none, _) = sections(&answer("Use `cargo fmt`.", Some("  ")), true);
+        assert!(none.is_none());
+
+        assert_eq!(
+            render_message(None, &msg, true),
+            "> The user asked about style.\n> rustfmt is the standard.\n\nUse `cargo fmt`.\n"
+        );
+
+        // Only the reasoning is dimmed.
+        let styled = render_message(Theme::Dark.skin().as_ref(), &msg, true);
+        let dim = format!(
+            "{}",
+            termimad::crossterm::style::SetForegroundColor(gray(11))
+        );
+        let answer_at = styled.find("Use ").unwrap();
+        assert!(styled[..answer_at].contains(&dim), "{styled:?}");
+        assert!(!styled[answer_at..].contains(&dim), "{styled:?}");
+    }
+
+    #[test]
+    fn prints_no_escape_sequences_with_no_markdown() {
+        let config = Config::default();
+        let msg = answer("# Fix\n\nUse **`cargo fmt`**.", None);
+
+        let plain = cmd(&["--no-markdown"]).skin(&config);
+        assert!(plain.is_none());
+        assert_eq!(
+            render_message(plain.as_ref(), &msg, false),
+            "# Fix\n\nUse **`cargo fmt`**.\n"
+        );
+
+        let styled = cmd(&[]).skin(&config);
+        assert!(render_message(styled.as_ref(), &msg, false).contains('\x1b'));
+    }
+
+    #[tokio::test]
+    async fn replays_the_last_turn_without_touching_the_session() {
+        DataDir::set_save_history(false);
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            &json!({
+                "choices": [{ "message": { "role": "assistant", "content": "Still 4." }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+            }),
+        )])
+        .await;
+
+        let mut question = answer("What is 2 + 2?", None);
+        question.role = Role::User;
+        let session =
+            serde_json::to_string(&[answer("Hello", None), question, answer("4", None)]).unwrap();
+        let path = std::env::temp_dir().join(format!("acai-replay-{}.json", std::process::id()));
+        std::fs::write(&path, &session).unwrap();
+
+        let client = ChatCompletionClient::new(Provider::OpenAI, Model::GPT4o, "Be brief.");
+        cmd(&["--replay", path.to_str().unwrap()])
+            .replay(client, &path)
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let sent = requests[0].body["messages"].as_array().unwrap();
+        assert_eq!(sent.last().unwrap()["content"], "What is 2 + 2?");
+        assert!(sent.iter().all(|msg| msg["content"] != "4"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), session);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -3,7 +3,11 @@ use std::error::Error;
 use anyhow::Result;
 use clap::Args;
 
-use crate::{cli::CmdRunner, operations::Complete};
+use crate::{
+    cli::{status, CmdRunner, OutputArgs},
+    clients::providers::MaxTokens,
+    operations::{Complete, Operation, OperationArgs},
+};
 
 #[derive(Clone, Args)]
 pub struct Cmd {
@@ -15,13 +19,20 @@ pub struct Cmd {
     #[arg(long)]
     pub temperature: Option<f32>,
 
-    /// Sets the max tokens value
+    /// Sets the max tokens value, or `auto` to fit the remaining context
     #[arg(long)]
-    pub max_tokens: Option<u32>,
+    pub max_tokens: Option<MaxTokens>,
 
     /// Sets the top-p value
     #[arg(long)]
     pub top_p: Option<f32>,
+
+    /// Sets the marker separating the prefix from the suffix
+    #[arg(long)]
+    pub fim_marker: Option<String>,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
 }
 
 impl CmdRunner for Cmd {
@@ -30,28 +41,26 @@ impl CmdRunner for Cmd {
             if atty::is(atty::Stream::Stdin) {
                 None
             } else {
-                match std::io::read_to_string(std::io::stdin()) {
-                    Ok(result) => Some(result),
-                    Err(_error) => None,
-                }
+                std::io::read_to_string(std::io::stdin()).ok()
             }
         };
 
-        let complete = Complete {
+        let complete = Complete(OperationArgs {
             model: self.model.clone(),
             temperature: self.temperature,
             max_tokens: self.max_tokens,
             top_p: self.top_p,
-            prompt: None,
             context,
-        };
+            fim_marker: self.fim_marker.clone(),
+            ..OperationArgs::default()
+        });
 
         let response = complete.send().await?;
 
         if let Some(msg) = response {
-            println!("{msg}");
+            self.output.sink().write(&msg.content)?;
         } else {
-            eprintln!("{response:?}");
+            status(format!("{response:?}"));
         }
 
         Ok(())
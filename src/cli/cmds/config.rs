@@ -0,0 +1,55 @@
+use std::{error::Error, io};
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+
+use crate::{
+    cli::{status, CmdRunner},
+    clients::{providers::Provider, store_api_key},
+};
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    #[command(subcommand)]
+    cmd: ConfigCmd,
+}
+
+#[derive(Clone, Subcommand)]
+enum ConfigCmd {
+    /// Stores a provider's API key in the OS keyring, read from stdin.
+    /// The provider's environment variable still takes precedence.
+    SetKey {
+        /// The provider the key belongs to
+        #[arg(value_enum)]
+        provider: Provider,
+    },
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &self.cmd {
+            ConfigCmd::SetKey { provider } => {
+                if atty::is(atty::Stream::Stdin) {
+                    status(format!("Enter the API key for {provider:?}:"));
+                }
+
+                let mut key = String::new();
+                io::stdin().read_line(&mut key)?;
+                let key = key.trim();
+                if key.is_empty() {
+                    return Err("no key given".into());
+                }
+
+                store_api_key(*provider, key)
+                    .map_err(|e| format!("cannot store the key in the keyring: {e}"))?;
+
+                status(format!(
+                    "Stored the {provider:?} key; {} still takes precedence when set",
+                    provider.key_var()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
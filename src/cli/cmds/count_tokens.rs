@@ -0,0 +1,101 @@
+use std::error::Error;
+
+use anyhow::Result;
+use clap::Args;
+use serde_json::json;
+
+use crate::{
+    cli::{to_json, CmdRunner, JsonFormatArgs},
+    config::Config,
+    models::tokenizer_for,
+};
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    /// Sets the model whose tokenizer is used
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Prints the count as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub json_format: JsonFormatArgs,
+
+    /// Reads the text from this file instead of stdin
+    pub file: Option<std::path::PathBuf>,
+}
+
+impl Cmd {
+    /// Returns the count of `text` as printed, a bare number or a JSON object.
+    fn report(&self, text: &str) -> serde_json::Result<String> {
+        let model = Config::get().resolve_model(self.model.as_deref()).model;
+
+        let tokens = tokenizer_for(&model).count(text);
+
+        if self.json {
+            let count = json!({
+                "model": model.id(),
+                "tokens": tokens,
+                "context_window": model.context_window(),
+            });
+            to_json(&count, self.json_format.is_pretty())
+        } else {
+            Ok(tokens.to_string())
+        }
+    }
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let text = match &self.file {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => std::io::read_to_string(std::io::stdin())?,
+        };
+
+        println!("{}", self.report(&text)?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::clients::providers::Model;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        cmd: Cmd,
+    }
+
+    fn cmd(args: &[&str]) -> Cmd {
+        TestCli::parse_from(std::iter::once("count-tokens").chain(args.iter().copied())).cmd
+    }
+
+    #[test]
+    fn counts_tokens_with_the_model_tokenizer() {
+        let text = "The quick brown fox jumps over the lazy dog.";
+
+        let count: u32 = cmd(&["--model", "gpt-4o"])
+            .report(text)
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((9..=11).contains(&count), "counted {count}");
+
+        let report: Value = serde_json::from_str(
+            &cmd(&["--model", "gpt-4o", "--json", "--compact"])
+                .report(text)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report["tokens"], count);
+        assert_eq!(report["model"], Model::GPT4o.id());
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    error::Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use serde::Serialize;
+
+use crate::{
+    cli::{confirm, status, to_json, CmdRunner, JsonFormatArgs},
+    config::DataDir,
+    models::{Message, Role},
+    operations::first_message_title,
+};
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    #[command(subcommand)]
+    cmd: HistoryCmd,
+}
+
+#[derive(Clone, Subcommand)]
+enum HistoryCmd {
+    /// Lists the saved sessions, oldest first
+    List {
+        /// Prints a JSON array of `{id, created_at, model, turns, title}` objects
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        json_format: JsonFormatArgs,
+    },
+    /// Prints the messages of a saved session
+    Show {
+        /// The session id shown by `history list`
+        id: u128,
+    },
+    /// Removes saved sessions older than the given age
+    Prune {
+        /// Sets the age, e.g. `30d`, `12h` or `2w`
+        #[arg(long, value_parser = parse_age)]
+        older_than: Duration,
+
+        /// Removes the sessions without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let data_dir = DataDir::new();
+
+        match &self.cmd {
+            HistoryCmd::List { json, json_format } => {
+                let summaries: Vec<SessionSummary> = data_dir
+                    .sessions()
+                    .into_iter()
+                    .map(|(id, path)| {
+                        let messages: Vec<Message> =
+                            data_dir.load_messages(&path).unwrap_or_default();
+                        SessionSummary::new(id, &messages, data_dir.load_title(id))
+                    })
+                    .collect();
+
+                if *json {
+                    println!("{}", to_json(&summaries, json_format.is_pretty())?);
+                    return Ok(());
+                }
+
+                let now = now_ms();
+                for SessionSummary {
+                    id,
+                    model,
+                    turns,
+                    title,
+                    ..
+                } in summaries
+                {
+                    println!(
+                        "{id}  {:>8}  {:<28}  {turns:>3} turns  {title}",
+                        format_age(now.saturating_sub(id)),
+                        model.as_deref().unwrap_or("-"),
+                    );
+                }
+            }
+            HistoryCmd::Show { id } => {
+                let path = data_dir
+                    .sessions()
+                    .into_iter()
+                    .find(|(session, _)| session == id)
+                    .map(|(_, path)| path)
+                    .ok_or_else(|| format!("no session with id {id}"))?;
+
+                let messages: Vec<Message> = data_dir.load_messages(&path)?;
+                for msg in messages {
+                    println!("[{}]\n{}\n", role_name(msg.role), msg.content);
+                }
+            }
+            HistoryCmd::Prune { older_than, yes } => {
+                let cutoff = now_ms().saturating_sub(older_than.as_millis());
+                let stale: Vec<_> = data_dir
+                    .sessions()
+                    .into_iter()
+                    .filter(|(id, _)| *id < cutoff)
+                    .collect();
+
+                if stale.is_empty() {
+                    status("No sessions to remove");
+                    return Ok(());
+                }
+
+                if !yes && !confirm(&format!("Remove {} sessions?", stale.len()))? {
+                    status("Nothing removed, pass --yes to skip confirmation");
+                    return Ok(());
+                }
+
+                for (id, path) in &stale {
+                    std::fs::remove_file(path)?;
+                    data_dir.remove_title(*id);
+                }
+
+                status(format!("Removed {} sessions", stale.len()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// One saved session, as listed by `history list`.
+#[derive(Serialize, Debug)]
+struct SessionSummary {
+    id: u128,
+    /// When the session was saved, in milliseconds since the Unix epoch.
+    created_at: u128,
+    /// The model of the last assistant turn, if the session recorded one.
+    model: Option<String>,
+    turns: usize,
+    /// The saved title, or else the first line of the first user message, truncated.
+    title: String,
+}
+
+impl SessionSummary {
+    fn new(id: u128, messages: &[Message], title: Option<String>) -> Self {
+        let model = messages
+            .iter()
+            .rev()
+            .find_map(|msg| msg.metadata.as_ref())
+            .map(|metadata| metadata.model.clone());
+        let title = title.unwrap_or_else(|| first_message_title(messages));
+        let turns = messages
+            .iter()
+            .filter(|msg| matches!(msg.role, Role::User))
+            .count();
+
+        // Session ids are the time the session was saved.
+        Self {
+            id,
+            created_at: id,
+            model,
+            turns,
+            title,
+        }
+    }
+}
+
+/// Parses an age such as `30d` into a duration. Supports `s`, `m`, `h`, `d` and `w`.
+fn parse_age(value: &str) -> Result<Duration, String> {
+    let unit_at = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in `{value}`, e.g. `30d`"))?;
+    let (amount, unit) = value.split_at(unit_at);
+
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid age `{value}`"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{unit}`, expected s, m, h, d or w")),
+    };
+
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("age `{value}` is too large"))
+}
+
+fn format_age(age_ms: u128) -> String {
+    let seconds = age_ms / 1000;
+    match seconds {
+        0..=59 => format!("{seconds}s ago"),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86_399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86_400),
+    }
+}
+
+const fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::System => "system",
+        Role::Assistant => "assistant",
+        Role::User => "user",
+        Role::Tool => "tool",
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(json: &str) -> Vec<Message> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parses_ages_in_every_unit() {
+        assert_eq!(parse_age("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_age("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_age("12h"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_age("30d"), Ok(Duration::from_secs(30 * 86_400)));
+        assert_eq!(parse_age("2w"), Ok(Duration::from_secs(14 * 86_400)));
+    }
+
+    #[test]
+    fn rejects_invalid_ages() {
+        for value in ["30", "d", "30y", "-1d", "1.5h", ""] {
+            assert!(parse_age(value).is_err(), "{value} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_ages_that_overflow() {
+        assert!(parse_age(&format!("{}w", u64::MAX / 2)).is_err());
+    }
+
+    #[test]
+    fn formats_ages_in_the_largest_unit() {
+        assert_eq!(format_age(59_999), "59s ago");
+        assert_eq!(format_age(60_000), "1m ago");
+        assert_eq!(format_age(2 * 3_600_000), "2h ago");
+        assert_eq!(format_age(3 * 86_400_000), "3d ago");
+    }
+
+    #[test]
+    fn summarizes_a_session() {
+        let messages = messages(
+            r#"[
+                {"role": "user", "content": "\n  Fix the parser\nand the lexer"},
+                {"role": "assistant", "content": "Done", "metadata": {"model": "gpt-4o", "max_tokens": 1024}},
+                {"role": "user", "content": "Thanks"},
+                {"role": "assistant", "content": "Welcome", "metadata": {"model": "claude-3-5-sonnet-20240620", "max_tokens": 1024}}
+            ]"#,
+        );
+
+        let summary = SessionSummary::new(1_700_000_000_000, &messages, None);
+
+        assert_eq!(summary.created_at, 1_700_000_000_000);
+        assert_eq!(summary.turns, 2);
+        assert_eq!(summary.model.as_deref(), Some("claude-3-5-sonnet-20240620"));
+        assert_eq!(summary.title, "Fix the parser");
+    }
+
+    #[test]
+    fn prefers_the_saved_title() {
+        let messages = messages(r#"[{"role": "user", "content": "hello"}]"#);
+
+        let summary = SessionSummary::new(1, &messages, Some("Greeting".to_string()));
+
+        assert_eq!(summary.title, "Greeting");
+        assert_eq!(summary.model, None);
+    }
+
+    #[test]
+    fn lists_sessions_as_json() {
+        let messages = messages(&format!(
+            r#"[
+                {{"role": "user", "content": "{}"}},
+                {{"role": "assistant", "content": "Done", "metadata": {{"model": "gpt-4o", "max_tokens": 1024}}}}
+            ]"#,
+            "Explain ".repeat(20)
+        ));
+        let summaries = [
+            SessionSummary::new(2, &messages, None),
+            SessionSummary::new(1, &[], None),
+        ];
+
+        let listing: serde_json::Value =
+            serde_json::from_str(&to_json(&summaries, false).unwrap()).unwrap();
+
+        let sessions = listing.as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        for session in sessions {
+            let fields: Vec<&str> = session
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(String::as_str)
+                .collect();
+            assert_eq!(fields, ["created_at", "id", "model", "title", "turns"]);
+            assert!(session["id"].is_u64());
+            assert!(session["created_at"].is_u64());
+            assert!(session["turns"].is_u64());
+            assert!(session["title"].is_string());
+            assert!(session["model"].is_string() || session["model"].is_null());
+        }
+        assert_eq!(sessions[0]["model"], "gpt-4o");
+        assert!(sessions[0]["title"].as_str().unwrap().ends_with("..."));
+        assert!(sessions[1]["model"].is_null());
+    }
+}
@@ -1,9 +1,16 @@
-use std::error::Error;
+use std::{error::Error, fs, io, path::Path};
 
 use anyhow::Result;
 use clap::Args;
+use similar::TextDiff;
 
-use crate::{cli::CmdRunner, operations::Instruct};
+use crate::{
+    cli::{confirm, file_or_value, status, CmdRunner, ContextArgs, OutputArgs},
+    clients::providers::{MaxTokens, ReasoningEffort, DEFAULT_MAX_TOKENS},
+    config::{Config, Profile},
+    models::tokenizer_for,
+    operations::{format_output, Instruct, Operation, OperationArgs},
+};
 
 #[derive(Clone, Args)]
 pub struct Cmd {
@@ -15,49 +22,255 @@ pub struct Cmd {
     #[arg(long)]
     pub temperature: Option<f32>,
 
-    /// Sets the max tokens value
+    /// Sets the max tokens value, or `auto` to fit the remaining context
     #[arg(long)]
-    pub max_tokens: Option<u32>,
+    pub max_tokens: Option<MaxTokens>,
 
     /// Sets the top-p value
     #[arg(long)]
     pub top_p: Option<f32>,
 
-    /// Sets the prompt
-    #[arg(short, long)]
+    /// Sets the comma-separated models to fall back to when the model's provider fails
+    #[arg(long, value_delimiter = ',')]
+    pub fallback: Vec<String>,
+
+    /// Sets the reasoning effort for models that support it
+    #[arg(long, value_enum)]
+    pub reasoning: Option<ReasoningEffort>,
+
+    /// Asks the model to continue up to N times when its answer is cut off by the token limit
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_continuations: u32,
+
+    /// Sets the prompt, or `@path` to read it from a file
+    #[arg(short, long, value_parser = file_or_value)]
     prompt: Option<String>,
+
+    /// Prints the estimated tokens and cost instead of sending the request
+    #[arg(long)]
+    pub dry_run_tokens: bool,
+
+    /// Prints the model, tokens, latency and estimated cost to stderr when done
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Writes the answer back to `--context-file` instead of printing it, as
+    /// long as the file is sent unchanged
+    #[arg(long, requires = "context_file")]
+    pub in_place: bool,
+
+    /// Shows the diff and asks before writing with `--in-place`
+    #[arg(long, requires = "in_place")]
+    pub interactive: bool,
+
+    #[command(flatten)]
+    pub context: ContextArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+impl Cmd {
+    /// Fills the options not given on the command line from `profile`.
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        self.model = self.model.take().or_else(|| profile.model.clone());
+        self.temperature = self.temperature.or(profile.temperature);
+        self.max_tokens = self
+            .max_tokens
+            .or_else(|| profile.max_tokens.map(MaxTokens::Fixed));
+        self.top_p = self.top_p.or(profile.top_p);
+        self.prompt = self.prompt.take().or_else(|| profile.prompt.clone());
+    }
+
+    /// Replaces the file's contents with `output`, first showing the diff and
+    /// asking with `confirm` when `--interactive` is set.
+    fn write_in_place(
+        &self,
+        path: &Path,
+        output: &str,
+        confirm: impl FnOnce(&str) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        let current = fs::read_to_string(path)?;
+
+        if self.interactive {
+            let name = path.display().to_string();
+            let diff = TextDiff::from_lines(current.as_str(), output);
+            eprint!("{}", diff.unified_diff().header(&name, &name));
+
+            if !confirm(&format!("Apply changes to {name}?"))? {
+                status("Changes discarded");
+                return Ok(());
+            }
+        }
+
+        fs::write(path, output)?;
+        status(format!("Wrote {}", path.display()));
+
+        Ok(())
+    }
 }
 
 impl CmdRunner for Cmd {
     async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let context: Option<String> = {
-            if atty::is(atty::Stream::Stdin) {
-                None
-            } else {
-                match std::io::read_to_string(std::io::stdin()) {
-                    Ok(result) => Some(result),
-                    Err(_error) => None,
-                }
+        let model = Config::get().resolve_model(self.model.as_deref()).model;
+        let budget = model.context_budget(self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS));
+
+        let context = self.context.read(Some((budget, tokenizer_for(&model))))?;
+
+        if self.in_place && !self.dry_run_tokens {
+            if let Some(reason) = self.context.differs_from_file(context.as_deref())? {
+                return Err(format!(
+                    "--in-place would overwrite the file with an answer to different context: {reason}"
+                )
+                .into());
             }
-        };
+        }
 
-        let op = Instruct {
+        let op = Instruct(OperationArgs {
             model: self.model.clone(),
             temperature: self.temperature,
             max_tokens: self.max_tokens,
             top_p: self.top_p,
             prompt: self.prompt.clone(),
             context,
-        };
+            fim_marker: None,
+            fallback: self.fallback.clone(),
+            reasoning: self.reasoning,
+            max_continuations: self.max_continuations,
+            best_of: 0,
+        });
+
+        if self.dry_run_tokens {
+            println!("{}", op.estimate()?);
+            return Ok(());
+        }
+
+        let response = op.send_with_stats().await?;
 
-        let response = op.send().await?;
+        if let Some((response_msg, stats)) = response {
+            let output = format_output(response_msg.content, self.context.extension()).await;
+
+            match &self.context.context_file {
+                Some(path) if self.in_place => self.write_in_place(path, &output, confirm)?,
+                _ => self.output.sink().write(&output)?,
+            }
 
-        if let Some(response_msg) = response {
-            println!("{}", response_msg.content);
+            if self.stats {
+                status(stats);
+            }
         } else {
-            eprintln!("{response:?}");
+            status("None");
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        cli::capture,
+        clients::mock_server::{MockResponse, MockServer},
+        config::DataDir,
+    };
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        cmd: Cmd,
+    }
+
+    fn cmd(args: &[&str]) -> Cmd {
+        TestCli::parse_from(std::iter::once("instruct").chain(args.iter().copied())).cmd
+    }
+
+    #[test]
+    fn fills_omitted_flags_from_the_profile() {
+        let config: Config = serde_json::from_str(
+            r#"{"profiles": {"refactor": {"model": "sonnet", "temperature": 0.2, "prompt": "Refactor."}}}"#,
+        )
+        .unwrap();
+        let profile = config.profile("refactor").unwrap();
+
+        let mut from_profile = cmd(&[]);
+        from_profile.apply_profile(profile);
+        assert_eq!(from_profile.model.as_deref(), Some("sonnet"));
+        assert_eq!(from_profile.temperature, Some(0.2));
+        assert_eq!(from_profile.prompt.as_deref(), Some("Refactor."));
+
+        let mut flagged = cmd(&["--temperature", "0.9", "--model", "gpt-4o"]);
+        flagged.apply_profile(profile);
+        assert_eq!(flagged.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(flagged.temperature, Some(0.9));
+        assert_eq!(flagged.prompt.as_deref(), Some("Refactor."));
+
+        assert!(config.profile("missing").is_err());
+    }
+
+    #[test]
+    fn writes_in_place_only_when_accepted() {
+        let path = std::env::temp_dir().join(format!("acai-in-place-{}.rs", std::process::id()));
+        let path_arg = path.to_str().unwrap();
+        fs::write(&path, "fn old() {}\n").unwrap();
+        let interactive = cmd(&["--context-file", path_arg, "--in-place", "--interactive"]);
+
+        interactive
+            .write_in_place(&path, "fn new() {}\n", |_| Ok(false))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn old() {}\n");
+
+        interactive
+            .write_in_place(&path, "fn new() {}\n", |_| Ok(true))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn new() {}\n");
+
+        cmd(&["--context-file", path_arg, "--in-place"])
+            .write_in_place(&path, "fn newer() {}\n", |_| {
+                unreachable!("only --interactive asks")
+            })
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn newer() {}\n");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prints_the_stats_on_stderr_only() {
+        DataDir::set_save_history(false);
+        std::env::set_var("OPENAI_API_KEY", "test-key");
+        let _server = MockServer::start(vec![MockResponse::json(
+            200,
+            &json!({
+                "choices": [{ "message": { "role": "assistant", "content": "Renamed." }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+            }),
+        )])
+        .await;
+
+        capture::start();
+        let result = cmd(&[
+            "--model",
+            "gpt-4o",
+            "--raw",
+            "--stats",
+            "--no-context",
+            "-p",
+            "Rename it.",
+        ])
+        .run()
+        .await;
+        let output = capture::finish();
+
+        result.unwrap();
+        assert_eq!(output.stdout, "Renamed.\n");
+        assert!(
+            output.stderr.contains("GPT-4o: 10 in, 5 out"),
+            "{}",
+            output.stderr
+        );
+    }
+}
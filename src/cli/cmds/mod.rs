@@ -1,6 +1,14 @@
+pub mod bench;
 pub mod chat;
 pub mod complete;
+pub mod config;
+pub mod count_tokens;
+pub mod history;
 pub mod instruct;
 pub mod lsp;
 pub mod pipe;
 pub mod prompt_generator;
+pub mod raw_request;
+pub mod serve;
+pub mod suggest;
+pub mod watch;
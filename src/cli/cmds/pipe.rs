@@ -1,18 +1,25 @@
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 use clap::{Args, ValueEnum};
 
 use crate::{
-    cli::CmdRunner,
+    cli::{file_or_value, status, CmdRunner, OutputArgs},
     clients::{
-        providers::{Model, Provider},
+        providers::{MaxTokens, Model, Provider},
         ChatCompletionClient,
     },
-    config::DataDir,
+    config::{Config, DataDir, Profile},
     errors::CAError,
     models::{Message, Role},
-    prompts::PromptBuilder,
+    operations::{apply_diff, DIFF_PROMPT},
+    prompts::{redaction_preview, PromptBuilder},
 };
 
 const OPTIMIZE_PROMPT: &str = "Review the code snippet below and suggest optimizations to improve performance. Focus on efficiency, speed, and resource usage while maintaining the original functionality. Provide only the optimized code.";
@@ -46,9 +53,9 @@ pub struct Cmd {
     #[arg(long)]
     pub temperature: Option<f32>,
 
-    /// Sets the max tokens value
+    /// Sets the max tokens value, or `auto` to fit the remaining context
     #[arg(long)]
-    pub max_tokens: Option<u32>,
+    pub max_tokens: Option<MaxTokens>,
 
     /// Sets the top-p value
     #[arg(long)]
@@ -58,8 +65,36 @@ pub struct Cmd {
     #[arg(long, value_enum)]
     task: Option<Task>,
 
-    /// Sets the stdin prompt
+    /// Prints token usage and estimated cost to stderr
+    #[arg(long)]
+    verbose: bool,
+
+    /// Sets the stdin prompt; words of the form `@path` are read from a file
+    #[arg(value_parser = file_or_value)]
     prompt: Vec<String>,
+
+    /// Writes the response to this file as it streams in, instead of printing it
+    #[arg(long, conflicts_with_all = ["out", "json", "raw"])]
+    pub stream_to_file: Option<PathBuf>,
+
+    /// Asks for a unified diff and prints stdin with the diff applied
+    #[arg(long, conflicts_with = "stream_to_file")]
+    pub apply_diff: bool,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+impl Cmd {
+    /// Fills the options not given on the command line from `profile`.
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        self.model = self.model.take().or_else(|| profile.model.clone());
+        self.temperature = self.temperature.or(profile.temperature);
+        self.max_tokens = self
+            .max_tokens
+            .or_else(|| profile.max_tokens.map(MaxTokens::Fixed));
+        self.top_p = self.top_p.or(profile.top_p);
+    }
 }
 
 impl CmdRunner for Cmd {
@@ -72,6 +107,11 @@ impl CmdRunner for Cmd {
             Some(Task::Document) => DOCUMENT_PROMPT,
             _ => DEFAULT_PROMPT,
         };
+        let system_prompt = if self.apply_diff {
+            format!("{system_prompt}\n\n{DIFF_PROMPT}")
+        } else {
+            system_prompt.to_string()
+        };
 
         let model_provider = match self.model.clone().unwrap_or("default".to_string()).as_str() {
             "gpt-4-turbo" => (Provider::OpenAI, Model::GPT4Turbo),
@@ -81,16 +121,20 @@ impl CmdRunner for Cmd {
             "sonnet3" => (Provider::Anthropic, Model::Claude3Sonnet),
             "haiku3" => (Provider::Anthropic, Model::Claude3Haiku),
             "codestral" => (Provider::Mistral, Model::Codestral),
-            _ => (Provider::OpenAI, Model::GPT4o),
+            _ => {
+                let resolved = Config::get().resolve_model(self.model.as_deref());
+                (resolved.provider, resolved.model)
+            }
         };
 
         let mut client =
-            ChatCompletionClient::new(model_provider.0, model_provider.1, system_prompt)
+            ChatCompletionClient::new(model_provider.0, model_provider.1.clone(), &system_prompt)
                 .temperature(self.temperature)
                 .top_p(self.top_p)
-                .max_tokens(self.max_tokens);
+                .max_tokens(self.max_tokens)
+                .anthropic_beta(Config::get().anthropic_beta.clone());
 
-        let prompt_builder = PromptBuilder::new()?;
+        let prompt_builder = PromptBuilder::new();
 
         let context: Result<String, CAError> = {
             if atty::is(atty::Stream::Stdin) {
@@ -116,6 +160,20 @@ impl CmdRunner for Cmd {
         if let Ok(prompt) = std_prompt {
             data.insert("prompt".to_string(), prompt);
         }
+        let original = match (&context, self.apply_diff) {
+            // The diff is applied to stdin as read, so the model must see it
+            // unchanged or its hunks would not apply.
+            (Ok(context), true) if redaction_preview(context) != *context => {
+                return Err(
+                    "--apply-diff needs stdin sent unchanged, but secrets in it are redacted; pass --no-redact to send them"
+                        .into(),
+                )
+            }
+            (Ok(context), true) => Some(context.clone()),
+            (Err(_), true) => return Err("--apply-diff needs the original content on stdin".into()),
+            (_, false) => None,
+        };
+
         if let Ok(context) = context {
             data.insert("context".to_string(), context);
         }
@@ -124,19 +182,129 @@ impl CmdRunner for Cmd {
             let msg = Message {
                 role: Role::User,
                 content: prompt_builder.build(&data)?,
+                reasoning: None,
+                tool_call_id: None,
+                finish_reason: None,
+                metadata: None,
             };
 
-            let response = client.send_message(msg).await?;
-
-            if let Some(response_msg) = response {
-                println!("{}", response_msg.content);
+            if let Some(path) = &self.stream_to_file {
+                stream_to_file(&mut client, msg, path).await?;
+                status(format!("Wrote {}", path.display()));
+            } else if let Some(response_msg) = client.send_message(msg).await? {
+                let output = match &original {
+                    Some(original) => apply_diff(original, &response_msg.content)?,
+                    None => response_msg.content,
+                };
+                self.output.sink().write(&output)?;
             } else {
-                eprintln!("{response:?}");
+                status("None");
             }
 
             DataDir::new().save_messages(&client.get_message_history());
+
+            if self.verbose {
+                let usage = client.get_usage();
+                let cost = Config::get()
+                    .pricing(&model_provider.1)
+                    .estimate_cost(&usage);
+                status(format!(
+                    "Tokens: {} in, {} out (${cost:.4})",
+                    usage.input_tokens, usage.output_tokens
+                ));
+
+                if let Some(rate_limit) = client.get_rate_limit() {
+                    let remaining = |value: Option<u32>| {
+                        value.map_or_else(|| "unknown".to_string(), |value| value.to_string())
+                    };
+                    status(format!(
+                        "Rate limit: {} requests, {} tokens remaining",
+                        remaining(rate_limit.remaining_requests),
+                        remaining(rate_limit.remaining_tokens)
+                    ));
+                }
+            }
         }
 
         Ok(())
     }
 }
+
+/// Streams the response to `msg` into a new file at `path`.
+async fn stream_to_file(
+    client: &mut ChatCompletionClient,
+    msg: Message,
+    path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut file = File::create(path)?;
+    let mut write_error: Option<io::Error> = None;
+
+    // Each delta is written straight through, so the output so far
+    // survives if the process dies before the response completes.
+    client
+        .send_message_streaming(
+            msg,
+            |delta| {
+                if write_error.is_none() {
+                    if let Err(e) = file.write_all(delta.as_bytes()) {
+                        write_error = Some(e);
+                    }
+                }
+            },
+            std::future::pending(),
+        )
+        .await?;
+
+    if let Some(e) = write_error {
+        return Err(format!("failed to write {}: {e}", path.display()).into());
+    }
+    file.sync_all()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::clients::mock_server::{MockResponse, MockServer};
+
+    #[tokio::test]
+    async fn writes_each_chunk_as_it_arrives() {
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let delta = |text: &str| json!({ "choices": [{ "delta": { "content": text } }] });
+        // The stream stays open, so whatever reaches the file was written early.
+        let _server = MockServer::start(vec![MockResponse::unfinished_stream(&[
+            delta("fn main() {\n"),
+            delta("}\n"),
+        ])])
+        .await;
+        let path = std::env::temp_dir().join(format!("acai-stream-{}.rs", std::process::id()));
+        let mut client = ChatCompletionClient::new(Provider::OpenAI, Model::GPT4o, "Be brief.");
+        let msg = Message {
+            role: Role::User,
+            content: "Write main.".to_string(),
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        };
+
+        let streaming = stream_to_file(&mut client, msg, &path);
+        let written = async {
+            while std::fs::read_to_string(&path).unwrap_or_default() != "fn main() {\n}\n" {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::select! {
+            result = streaming => panic!("the stream ended early: {result:?}"),
+            () = written => {}
+            () = tokio::time::sleep(Duration::from_secs(5)) => panic!("the chunks were not written"),
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
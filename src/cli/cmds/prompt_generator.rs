@@ -5,24 +5,48 @@ use std::{collections::HashMap, env, error::Error};
 use clap::Args;
 
 use crate::{
-    cli::CmdRunner,
+    cli::{file_or_value, status, to_json, CmdRunner, JsonFormatArgs},
+    config::Config,
     errors::CAError,
-    models::{Message, Role},
-    prompts::PromptBuilder,
+    models::{tokenizer_for, Message, Role, Usage},
+    prompts::{system_prompt, PromptBuilder},
 };
 use readability::extractor;
 
+const SYSTEM_PROMPT: &str = "You are a helpful coding assistant and senior software engineer. Provide the answer and only the answer to the user's request.";
+
 #[derive(Clone, Args)]
 pub struct Cmd {
-    /// Sets the stdin prompt
+    /// Sets the stdin prompt; words of the form `@path` are read from a file
+    #[arg(value_parser = file_or_value)]
     prompt: Vec<String>,
+
+    /// Prints a JSON array of the system and user messages instead of the prompt
+    #[arg(long)]
+    messages: bool,
+
+    /// Prints the token count and input cost of the prompt to stderr
+    #[arg(long)]
+    tokens: bool,
+
+    /// Sets the model whose tokenizer and pricing `--tokens` uses
+    #[arg(long, requires = "tokens")]
+    model: Option<String>,
+
+    #[command(flatten)]
+    json_format: JsonFormatArgs,
 }
 
 impl CmdRunner for Cmd {
     async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        match env::current_dir() {
-            Ok(path) => println!("The current working directory is: {}", path.display()),
-            Err(e) => println!("Error: {e}"),
+        // The messages are the only output, so they can be piped into another tool.
+        let verbose = !self.messages;
+
+        if verbose {
+            match env::current_dir() {
+                Ok(path) => println!("The current working directory is: {}", path.display()),
+                Err(e) => println!("Error: {e}"),
+            }
         }
 
         let context: Result<String, CAError> = {
@@ -36,7 +60,7 @@ impl CmdRunner for Cmd {
             }
         };
 
-        let prompt_builder = PromptBuilder::new()?;
+        let prompt_builder = PromptBuilder::new();
 
         let std_prompt: Result<String, CAError> = {
             if self.prompt.is_empty() {
@@ -52,24 +76,28 @@ impl CmdRunner for Cmd {
             data.insert("prompt".to_string(), prompt);
         }
         if let Ok(context) = context {
-            println!("{context}");
+            if verbose {
+                println!("{context}");
+            }
             let t = process_todo_comment(&context);
             if let Ok(t) = t {
-                println!("Prompt: {}", t.0);
-                for item in t.1 {
-                    println!("URL: {item}");
-
-                    match extractor::scrape(&item) {
-                        Ok(product) => {
-                            println!("------- html ------");
-                            println!("{}", product.content);
-                            println!("---- plain text ---");
-                            println!("{}", product.text);
+                if verbose {
+                    println!("Prompt: {}", t.0);
+                    for item in t.1 {
+                        println!("URL: {item}");
+
+                        match extractor::scrape(&item) {
+                            Ok(product) => {
+                                println!("------- html ------");
+                                println!("{}", product.content);
+                                println!("---- plain text ---");
+                                println!("{}", product.text);
+                            }
+                            Err(e) => println!("error occured: {e}"),
                         }
-                        Err(e) => println!("error occured: {e}"),
                     }
+                    println!("Temp: {}", t.2);
                 }
-                println!("Temp: {}", t.2);
                 data.insert("context".to_string(), t.0);
             }
         }
@@ -78,15 +106,66 @@ impl CmdRunner for Cmd {
             let msg = Message {
                 role: Role::User,
                 content: prompt_builder.build(&data)?,
+                reasoning: None,
+                tool_call_id: None,
+                finish_reason: None,
+                metadata: None,
             };
 
-            println!("Final: {}", msg.content);
+            let system = system_prompt("prompt-generator", SYSTEM_PROMPT);
+
+            if self.tokens {
+                status(token_report(
+                    Config::get(),
+                    self.model.as_deref(),
+                    &system,
+                    &msg,
+                ));
+            }
+
+            if verbose {
+                println!("Final: {}", msg.content);
+            } else {
+                println!(
+                    "{}",
+                    to_json(&message_array(system, msg), self.json_format.is_pretty())?
+                );
+            }
         }
 
         Ok(())
     }
 }
 
+/// Returns the token count of the prompt with the model's tokenizer and the
+/// cost of sending it.
+fn token_report(config: &Config, model: Option<&str>, system: &str, msg: &Message) -> String {
+    let model = config.resolve_model(model).model;
+    let tokenizer = tokenizer_for(&model);
+
+    let input_tokens = tokenizer.count(system) + msg.tokens(tokenizer);
+    let cost = config.pricing(&model).estimate_cost(&Usage {
+        input_tokens,
+        output_tokens: 0,
+    });
+    format!("{model}: {input_tokens} tokens (${cost:.4} input)")
+}
+
+/// Returns the system and user messages as a provider would receive them.
+fn message_array(system: String, user: Message) -> Vec<Message> {
+    vec![
+        Message {
+            role: Role::System,
+            content: system,
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        },
+        user,
+    ]
+}
+
 fn process_todo_comment(comment: &str) -> Result<(String, Vec<String>, f32)> {
     // Regular expressions to match the URLs and temperature
     let url_re = Regex::new(r"https?://[^\s]+")?;
@@ -121,3 +200,55 @@ fn process_todo_comment(comment: &str) -> Result<(String, Vec<String>, f32)> {
 
     Ok((cleaned_comment, urls, temp))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_tokenizer_count_of_the_prompt() {
+        let config: Config = serde_json::from_str(
+            r#"{"pricing": {"claude-3-haiku-20240307": {"input": 1.0, "output": 5.0}}}"#,
+        )
+        .unwrap();
+        let msg = Message {
+            role: Role::User,
+            content: "Rename `x` to `count` in:\nlet x = items.len();".to_string(),
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        };
+
+        let report = token_report(&config, Some("haiku"), SYSTEM_PROMPT, &msg);
+
+        let tokenizer = tokenizer_for(&crate::clients::providers::Model::Claude3Haiku);
+        let tokens = tokenizer.count(SYSTEM_PROMPT) + tokenizer.count(&msg.content);
+        let cost = f64::from(tokens) / 1_000_000.0;
+        assert_eq!(
+            report,
+            format!("Claude 3 Haiku: {tokens} tokens (${cost:.4} input)")
+        );
+    }
+
+    #[test]
+    fn emits_messages_that_deserialize_back() {
+        let user = Message {
+            role: Role::User,
+            content: "Fix the bug.".to_string(),
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        };
+
+        let json = to_json(&message_array(SYSTEM_PROMPT.to_string(), user), false).unwrap();
+        let messages: Vec<Message> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0].role, Role::System));
+        assert_eq!(messages[0].content, SYSTEM_PROMPT);
+        assert!(matches!(messages[1].role, Role::User));
+        assert_eq!(messages[1].content, "Fix the bug.");
+    }
+}
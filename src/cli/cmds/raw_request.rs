@@ -0,0 +1,60 @@
+use std::error::Error;
+
+use anyhow::Result;
+use clap::Args;
+use serde_json::Value;
+
+use crate::{
+    cli::{file_or_value, status, CmdRunner},
+    clients::{providers::Provider, send_raw},
+    config::Config,
+};
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    /// Sets the provider to send the request to
+    #[arg(long, value_enum)]
+    pub provider: Provider,
+
+    /// Sets the JSON request body, or `@path` to read it from a file. It is
+    /// sent exactly as given
+    #[arg(long, value_parser = file_or_value)]
+    pub body: String,
+
+    /// Sets the model for providers that put it in the URL, defaulting to the body's `model`
+    #[arg(long)]
+    pub model: Option<String>,
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let payload: Value = serde_json::from_str(&self.body)
+            .map_err(|e| format!("the request body is not valid JSON: {e}"))?;
+
+        let model = self
+            .model
+            .clone()
+            .or_else(|| payload["model"].as_str().map(ToString::to_string))
+            .unwrap_or_default();
+        if model.is_empty() && matches!(self.provider, Provider::Google) {
+            return Err("pass --model, Google takes the model in the URL".into());
+        }
+
+        let (code, body) = send_raw(
+            self.provider,
+            &model,
+            self.body.clone(),
+            &Config::get().anthropic_beta,
+        )
+        .await?;
+
+        status(format!("{} responded with {code}", self.provider.name()));
+        println!("{body}");
+
+        if code.is_success() {
+            Ok(())
+        } else {
+            Err(format!("request failed with {code}").into())
+        }
+    }
+}
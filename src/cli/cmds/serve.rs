@@ -0,0 +1,343 @@
+use std::{error::Error, sync::Arc};
+
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::Semaphore,
+};
+
+use crate::{
+    cli::CmdRunner,
+    clients::providers::{MaxTokens, ReasoningEffort},
+    operations::{run_batch, OperationArgs, OperationKind, OperationRequest},
+};
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    /// Listens on a Unix socket at this path instead of stdin/stdout
+    #[arg(long)]
+    pub socket: Option<std::path::PathBuf>,
+
+    /// Sets how many requests are sent to providers at once, across every
+    /// connection and batch
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+}
+
+/// A single operation request, sent as one line of JSON. A line holding an
+/// array of requests is run as a batch and answered with an array of replies
+/// in the same order.
+///
+/// ```json
+/// {"id": 1, "operation": "fix", "context": "fn main() {}"}
+/// ```
+#[derive(Deserialize, Debug)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    operation: OperationKind,
+    model: Option<String>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    top_p: Option<f32>,
+    prompt: Option<String>,
+    context: Option<String>,
+    fim_marker: Option<String>,
+    #[serde(default)]
+    fallback: Vec<String>,
+    reasoning: Option<ReasoningEffort>,
+    #[serde(default)]
+    max_continuations: u32,
+    /// Only used by `suggest` and `optimize`.
+    #[serde(default)]
+    best_of: u32,
+}
+
+/// The reply to a request, sent as one line of JSON with the request's id.
+#[derive(Serialize, Debug)]
+struct Response {
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let limiter = Arc::new(Semaphore::new(self.concurrency.max(1)));
+
+        if let Some(socket) = &self.socket {
+            return serve_socket(socket, limiter).await;
+        }
+
+        let stdin = BufReader::new(tokio::io::stdin());
+        let stdout = tokio::io::stdout();
+
+        serve(stdin, stdout, limiter).await
+    }
+}
+
+#[cfg(unix)]
+async fn serve_socket(
+    path: &std::path::Path,
+    limiter: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let limiter = Arc::clone(&limiter);
+
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            if let Err(e) = serve(BufReader::new(reader), writer, limiter).await {
+                log::error!("serve connection failed: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_socket(
+    _path: &std::path::Path,
+    _limiter: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    Err("--socket is only supported on Unix".into())
+}
+
+/// Handles newline-delimited requests until the reader is closed.
+async fn serve<R, W>(
+    reader: R,
+    mut writer: W,
+    limiter: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = reader.lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let output = if line.trim_start().starts_with('[') {
+            match serde_json::from_str::<Vec<Request>>(&line) {
+                Ok(requests) => serde_json::to_string(&handle_batch(requests, &limiter).await)?,
+                Err(e) => serde_json::to_string(&invalid_request(&e))?,
+            }
+        } else {
+            match serde_json::from_str::<Request>(&line) {
+                Ok(request) => serde_json::to_string(&handle_one(request, &limiter).await)?,
+                Err(e) => serde_json::to_string(&invalid_request(&e))?,
+            }
+        };
+
+        writer.write_all(format!("{output}\n").as_bytes()).await?;
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+fn invalid_request(e: &serde_json::Error) -> Response {
+    Response::failed(Value::Null, format!("invalid request: {e}"))
+}
+
+/// Runs one request while holding a permit of `limiter`.
+async fn handle_one(request: Request, limiter: &Arc<Semaphore>) -> Response {
+    let id = request.id.clone();
+    let Ok(_permit) = limiter.acquire().await else {
+        return Response::failed(id, "the server is shutting down".to_string());
+    };
+
+    // Run each request on its own task so a panicking client, e.g. a
+    // missing API key, fails the request instead of the server.
+    tokio::spawn(handle(request))
+        .await
+        .unwrap_or_else(|e| Response::failed(id, format!("request failed: {e}")))
+}
+
+/// Runs a batch of operations, sharing `limiter` with every other request.
+async fn handle_batch(requests: Vec<Request>, limiter: &Arc<Semaphore>) -> Vec<Response> {
+    let ids: Vec<Value> = requests.iter().map(|request| request.id.clone()).collect();
+    let items = requests
+        .into_iter()
+        .map(Request::into_operation_request)
+        .collect();
+
+    let mut results = run_batch(items, Arc::clone(limiter)).await.into_iter();
+
+    ids.into_iter()
+        .map(|id| match results.next() {
+            Some(Ok(result)) => Response {
+                id,
+                result,
+                error: None,
+            },
+            Some(Err(e)) => Response::failed(id, e.to_string()),
+            None => Response::failed(id, "request failed".to_string()),
+        })
+        .collect()
+}
+
+impl Request {
+    /// Returns the request as an operation.
+    fn into_operation_request(self) -> OperationRequest {
+        let kind = self.operation;
+
+        OperationRequest {
+            kind,
+            args: OperationArgs {
+                model: self.model,
+                temperature: self.temperature,
+                max_tokens: self.max_tokens.map(MaxTokens::Fixed),
+                top_p: self.top_p,
+                prompt: self.prompt,
+                context: self.context,
+                fim_marker: self.fim_marker,
+                fallback: self.fallback,
+                reasoning: self.reasoning,
+                max_continuations: self.max_continuations,
+                best_of: if kind.supports_best_of() {
+                    self.best_of
+                } else {
+                    0
+                },
+            },
+        }
+    }
+}
+
+impl Response {
+    const fn failed(id: Value, error: String) -> Self {
+        Self {
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+async fn handle(request: Request) -> Response {
+    let id = request.id.clone();
+
+    let item = request.into_operation_request();
+    let result = item.kind.send(item.args).await;
+
+    match result {
+        Ok(result) => Response {
+            id,
+            result,
+            error: None,
+        },
+        Err(e) => Response::failed(id, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn serve_lines(input: &str) -> Vec<Value> {
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output, Arc::new(Semaphore::new(2)))
+            .await
+            .unwrap();
+
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_completions_and_chat_operations() {
+        let operation = |name: &str| serde_json::from_value::<OperationKind>(Value::from(name));
+
+        assert_eq!(operation("complete").ok(), Some(OperationKind::Complete));
+        assert_eq!(operation("fix").ok(), Some(OperationKind::Fix));
+        assert_eq!(operation("suggest").ok(), Some(OperationKind::Suggest));
+        assert!(operation("translate").is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_invalid_requests_with_an_error() {
+        let replies = serve_lines("{\"operation\": \"translate\"}\n\nnot json\n").await;
+
+        assert_eq!(replies.len(), 2);
+        for reply in replies {
+            assert_eq!(reply["id"], Value::Null);
+            assert!(reply["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid request"));
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_a_batch_in_order() {
+        // Requests with neither prompt nor context are answered without a request.
+        let replies = serve_lines(
+            r#"[{"id": 1, "operation": "fix"}, {"id": 2, "operation": "complete"}, {"id": 3, "operation": "document", "context": "  "}]"#,
+        )
+        .await;
+
+        let batch = replies[0].as_array().unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch[0], serde_json::json!({ "id": 1 }));
+        assert_eq!(batch[1], serde_json::json!({ "id": 2 }));
+        assert_eq!(batch[2], serde_json::json!({ "id": 3 }));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keeps_serving_connections_on_one_socket() {
+        let path = std::env::temp_dir().join(format!("acai-serve-{}.sock", std::process::id()));
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move { serve_socket(&path, Arc::new(Semaphore::new(2))).await }
+        });
+
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+
+        for id in 1..=2 {
+            let (reader, mut writer) = stream.split();
+            writer
+                .write_all(format!("{{\"id\": {id}, \"operation\": \"fix\"}}\n").as_bytes())
+                .await
+                .unwrap();
+
+            let reply = BufReader::new(reader).lines().next_line().await.unwrap();
+            assert_eq!(reply.as_deref(), Some(format!("{{\"id\":{id}}}").as_str()));
+        }
+
+        // A second client is served by the same instance.
+        stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.split();
+        writer
+            .write_all(b"{\"id\": 3, \"operation\": \"fix\"}\n")
+            .await
+            .unwrap();
+        let reply = BufReader::new(reader).lines().next_line().await.unwrap();
+        assert_eq!(reply.as_deref(), Some("{\"id\":3}"));
+
+        server.abort();
+        std::fs::remove_file(path).unwrap();
+    }
+}
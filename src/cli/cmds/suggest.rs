@@ -0,0 +1,153 @@
+use std::error::Error;
+
+use anyhow::Result;
+use clap::Args;
+
+use crate::{
+    cli::{file_or_value, status, CmdRunner, ContextArgs, OutputArgs},
+    clients::providers::{MaxTokens, ReasoningEffort, DEFAULT_MAX_TOKENS},
+    config::{Config, Profile},
+    models::tokenizer_for,
+    operations::{format_output, Operation, OperationArgs, Suggest, Suggestions},
+};
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    /// Sets the model to use
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Sets the temperature value
+    #[arg(long)]
+    pub temperature: Option<f32>,
+
+    /// Sets the max tokens value, or `auto` to fit the remaining context
+    #[arg(long)]
+    pub max_tokens: Option<MaxTokens>,
+
+    /// Sets the top-p value
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// Sets the comma-separated models to fall back to when the model's provider fails
+    #[arg(long, value_delimiter = ',')]
+    pub fallback: Vec<String>,
+
+    /// Sets the reasoning effort for models that support it
+    #[arg(long, value_enum)]
+    pub reasoning: Option<ReasoningEffort>,
+
+    /// Asks the model to continue up to N times when its answer is cut off by the token limit
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_continuations: u32,
+
+    /// Sets the prompt, or `@path` to read it from a file
+    #[arg(short, long, value_parser = file_or_value)]
+    prompt: Option<String>,
+
+    /// Prints the suggestions as a list of lines instead of an annotated snippet
+    #[arg(long)]
+    list: bool,
+
+    /// Generates N candidate answers and asks the model to pick the best
+    #[arg(long, value_name = "N", default_value_t = 0, conflicts_with = "list")]
+    pub best_of: u32,
+
+    /// Prints the estimated tokens and cost instead of sending the request
+    #[arg(long)]
+    pub dry_run_tokens: bool,
+
+    #[command(flatten)]
+    pub context: ContextArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+impl Cmd {
+    /// Fills the options not given on the command line from `profile`.
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        self.model = self.model.take().or_else(|| profile.model.clone());
+        self.temperature = self.temperature.or(profile.temperature);
+        self.max_tokens = self
+            .max_tokens
+            .or_else(|| profile.max_tokens.map(MaxTokens::Fixed));
+        self.top_p = self.top_p.or(profile.top_p);
+        self.prompt = self.prompt.take().or_else(|| profile.prompt.clone());
+    }
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let model = Config::get().resolve_model(self.model.as_deref()).model;
+        let budget = model.context_budget(self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS));
+
+        let context = self.context.read(Some((budget, tokenizer_for(&model))))?;
+
+        let op = Suggest(OperationArgs {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            top_p: self.top_p,
+            prompt: self.prompt.clone(),
+            context,
+            fim_marker: None,
+            fallback: self.fallback.clone(),
+            reasoning: self.reasoning,
+            max_continuations: self.max_continuations,
+            best_of: self.best_of,
+        });
+
+        if self.dry_run_tokens {
+            println!("{}", op.estimate()?);
+            return Ok(());
+        }
+
+        if self.list && self.output.out.is_none() && !self.output.json {
+            // Suggestions are printed as they stream in, so there is nothing
+            // left to print unless the answer could not be parsed.
+            let response = op
+                .send_structured_streaming(|suggestion| {
+                    println!("line {}: {}", suggestion.line, suggestion.comment);
+                })
+                .await?;
+
+            match response {
+                Some(Suggestions::Structured(_)) => {}
+                Some(Suggestions::Text(text)) => self.output.sink().write(&text)?,
+                None => status("No suggestions returned"),
+            }
+
+            return Ok(());
+        }
+
+        if self.list {
+            match op.send_structured().await? {
+                Some(Suggestions::Structured(suggestions)) => {
+                    let lines: Vec<String> = suggestions
+                        .iter()
+                        .map(|suggestion| {
+                            format!("line {}: {}", suggestion.line, suggestion.comment)
+                        })
+                        .collect();
+                    self.output.sink().write(&lines.join("\n"))?;
+                }
+                Some(Suggestions::Text(text)) => self.output.sink().write(&text)?,
+                None => status("No suggestions returned"),
+            }
+
+            return Ok(());
+        }
+
+        let response = op.send().await?;
+
+        if let Some(response_msg) = response {
+            let output = format_output(response_msg.content, self.context.extension()).await;
+            self.output.sink().write(&output)?;
+        } else {
+            status(format!("{response:?}"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use clap::Args;
+use notify::{event::ModifyKind, EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{
+    cli::{file_or_value, status, CmdRunner, OutputArgs},
+    clients::providers::MaxTokens,
+    operations::{format_output, OperationArgs, OperationKind},
+};
+
+#[derive(Clone, Args)]
+pub struct Cmd {
+    /// Sets the file to watch; its contents are sent as the context
+    pub path: PathBuf,
+
+    /// Sets the operation to run when the file changes
+    #[arg(long, value_enum, default_value = "instruct")]
+    operation: OperationKind,
+
+    /// Sets the model to use
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Sets the temperature value
+    #[arg(long)]
+    pub temperature: Option<f32>,
+
+    /// Sets the max tokens value, or `auto` to fit the remaining context
+    #[arg(long)]
+    pub max_tokens: Option<MaxTokens>,
+
+    /// Sets the top-p value
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// Sets the prompt, or `@path` to read it from a file
+    #[arg(short, long, value_parser = file_or_value)]
+    prompt: Option<String>,
+
+    /// Sets how long the file must be quiet before the operation runs
+    #[arg(long, default_value_t = 300)]
+    pub debounce_ms: u64,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+impl CmdRunner for Cmd {
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    if changes_contents(&event.kind) {
+                        let _ = tx.send(());
+                    }
+                }
+            })?;
+        watcher.watch(&self.path, RecursiveMode::NonRecursive)?;
+
+        status(format!("Watching {}", self.path.display()));
+
+        let debounce = Duration::from_millis(self.debounce_ms);
+
+        // Runs are awaited inline, so a save during a run is only picked up
+        // after it finishes and never starts a second, overlapping run.
+        while rx.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(debounce, rx.recv()).await {}
+
+            match self.send().await {
+                Ok(Some(result)) => self.output.sink().write(&result)?,
+                Ok(None) => status("No response"),
+                Err(e) => status(format!("Error: {e}")),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether the event may have changed the file's contents. Metadata
+/// changes, such as from `touch`, `chmod` or access time updates, are ignored
+/// so they don't send a request.
+const fn changes_contents(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+    )
+}
+
+impl Cmd {
+    async fn send(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let context = std::fs::read_to_string(&self.path)?;
+
+        let args = OperationArgs {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            top_p: self.top_p,
+            prompt: self.prompt.clone(),
+            context: Some(context),
+            ..Default::default()
+        };
+
+        let result = self.operation.send(args).await?;
+
+        let extension = self.path.extension().and_then(|ext| ext.to_str());
+        match result {
+            Some(result) => Ok(Some(format_output(result, extension).await)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::event::{AccessKind, CreateKind, DataChange, MetadataKind, RenameMode};
+
+    use super::*;
+
+    #[test]
+    fn runs_on_content_changes() {
+        assert!(changes_contents(&EventKind::Create(CreateKind::File)));
+        assert!(changes_contents(&EventKind::Modify(ModifyKind::Any)));
+        assert!(changes_contents(&EventKind::Modify(ModifyKind::Data(
+            DataChange::Content
+        ))));
+    }
+
+    #[test]
+    fn ignores_metadata_and_access_events() {
+        assert!(!changes_contents(&EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::AccessTime
+        ))));
+        assert!(!changes_contents(&EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::Permissions
+        ))));
+        assert!(!changes_contents(&EventKind::Modify(ModifyKind::Name(
+            RenameMode::Any
+        ))));
+        assert!(!changes_contents(&EventKind::Access(AccessKind::Any)));
+    }
+}
@@ -0,0 +1,17 @@
+use std::io::{self, BufRead, Write};
+
+/// Asks a yes/no question on the terminal. Without a terminal the answer is
+/// no, so scripts must opt in with a flag such as `--yes`.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    if !atty::is(atty::Stream::Stdin) {
+        return Ok(false);
+    }
+
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
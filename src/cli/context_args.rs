@@ -0,0 +1,529 @@
+use std::{
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use clap::{Args, ValueEnum};
+use log::warn;
+
+use crate::{
+    models::{truncate_middle, Tokenizer},
+    prompts::redaction_preview,
+};
+
+use super::file_or_value;
+
+/// How context that is not valid UTF-8 is handled.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Encoding {
+    /// Rejects the context with an error
+    #[default]
+    Strict,
+    /// Replaces invalid bytes with U+FFFD and warns
+    Lossy,
+}
+
+impl Encoding {
+    /// Decodes the bytes read from `source` as UTF-8.
+    pub fn decode(self, bytes: Vec<u8>, source: &str) -> io::Result<String> {
+        match String::from_utf8(bytes) {
+            Ok(text) => Ok(text),
+            Err(e) => match self {
+                Self::Strict => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{source} is not valid UTF-8 (invalid byte at offset {}); pass --encoding lossy to replace invalid bytes",
+                        e.utf8_error().valid_up_to()
+                    ),
+                )),
+                Self::Lossy => {
+                    warn!("{source} is not valid UTF-8, replacing invalid bytes");
+                    Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+                }
+            },
+        }
+    }
+}
+
+/// Reads piped stdin, or `None` when stdin is a terminal.
+fn read_stdin() -> io::Result<Option<Vec<u8>>> {
+    #[cfg(test)]
+    if let Some(bytes) = tests::mock_stdin() {
+        return Ok(Some(bytes));
+    }
+
+    if atty::is(atty::Stream::Stdin) {
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::new();
+    io::stdin().read_to_end(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+/// Reads the text on the system clipboard, or `None` with a warning when it
+/// holds no text.
+fn read_clipboard() -> io::Result<Option<String>> {
+    #[cfg(test)]
+    if let Some(text) = tests::mock_clipboard() {
+        return clipboard_context(Ok(text));
+    }
+
+    let mut clipboard = arboard::Clipboard::new().map_err(clipboard_unavailable)?;
+    clipboard_context(clipboard.get_text())
+}
+
+/// Returns the context held by the clipboard, given what reading its text returned.
+fn clipboard_context(text: Result<String, arboard::Error>) -> io::Result<Option<String>> {
+    match text {
+        Ok(text) if !text.trim().is_empty() => Ok(Some(text)),
+        Ok(_) | Err(arboard::Error::ContentNotAvailable) => {
+            warn!("The clipboard holds no text, sending no context");
+            Ok(None)
+        }
+        Err(e) => Err(clipboard_unavailable(e)),
+    }
+}
+
+fn clipboard_unavailable(e: arboard::Error) -> io::Error {
+    io::Error::other(format!("the clipboard is not available: {e}"))
+}
+
+/// Flags controlling where a command's context comes from and how it is wrapped.
+#[derive(Clone, Args)]
+pub struct ContextArgs {
+    /// Reads the context from this file instead of stdin
+    #[arg(long)]
+    pub context_file: Option<PathBuf>,
+
+    /// Sends no context, ignoring piped stdin
+    #[arg(long, conflicts_with = "context_file")]
+    pub no_context: bool,
+
+    /// Reads the context from the system clipboard instead of stdin
+    #[arg(long, conflicts_with_all = ["context_file", "no_context"])]
+    pub clipboard: bool,
+
+    /// Sets text to insert before the context, or `@path` to read it from a file
+    #[arg(long, value_parser = file_or_value)]
+    pub context_prefix: Option<String>,
+
+    /// Sets text to insert after the context, or `@path` to read it from a file
+    #[arg(long, value_parser = file_or_value)]
+    pub context_suffix: Option<String>,
+
+    /// Prints the context that will be sent to stderr before sending it
+    #[arg(long)]
+    pub echo_context: bool,
+
+    /// Sets how context that is not valid UTF-8 is handled
+    #[arg(long, value_enum, default_value_t)]
+    pub encoding: Encoding,
+
+    /// Wraps the context in a code fence tagged with its language, detected
+    /// from the `--context-file` extension
+    #[arg(long)]
+    pub context_lang_fence: bool,
+
+    /// Sets the language tag of the fence, e.g. `rust`, instead of detecting it
+    #[arg(long, requires = "context_lang_fence")]
+    pub context_lang: Option<String>,
+}
+
+impl ContextArgs {
+    /// Returns the extension of `--context-file`, used to pick a formatter.
+    pub fn extension(&self) -> Option<&str> {
+        self.context_file.as_ref()?.extension()?.to_str()
+    }
+
+    /// Reads the context from `--context-file` or piped stdin and wraps it in
+    /// the configured prefix and suffix.
+    ///
+    /// A context file larger than the budget, counted with its tokenizer, keeps
+    /// only its head and tail.
+    /// Secrets are redacted later, when the prompt is built.
+    pub fn read(&self, budget: Option<(u32, Tokenizer)>) -> io::Result<Option<String>> {
+        if self.no_context {
+            return Ok(None);
+        }
+
+        let context = match &self.context_file {
+            None if self.clipboard => read_clipboard()?,
+            Some(path) => {
+                let contents = self
+                    .encoding
+                    .decode(std::fs::read(path)?, &path.display().to_string())?;
+                match budget {
+                    Some((budget, tokenizer)) if tokenizer.count(&contents) > budget => {
+                        warn!(
+                            "{} exceeds the {budget} token budget, keeping only its head and tail",
+                            path.display()
+                        );
+                        Some(truncate_middle(&contents, budget, tokenizer))
+                    }
+                    _ => Some(contents),
+                }
+            }
+            None => match read_stdin()? {
+                Some(bytes) => Some(self.encoding.decode(bytes, "stdin")?),
+                None => None,
+            },
+        };
+
+        let context = context.map(|context| self.wrap(&context));
+
+        if self.echo_context {
+            if let Some(context) = &context {
+                eprintln!("{}", redaction_preview(context));
+            }
+        }
+
+        Ok(context)
+    }
+
+    /// Returns why `context`, as read for `--context-file`, is not exactly the
+    /// file's contents, or `None` when the model sees the file unchanged.
+    ///
+    /// Commands that replace the file with the model's answer must refuse to
+    /// run otherwise, or whatever the model did not see would be lost.
+    pub fn differs_from_file(&self, context: Option<&str>) -> io::Result<Option<&'static str>> {
+        let Some(path) = &self.context_file else {
+            return Ok(Some("the context is not read from --context-file"));
+        };
+
+        if self.context_prefix.is_some() || self.context_suffix.is_some() {
+            return Ok(Some(
+                "it is wrapped with --context-prefix or --context-suffix",
+            ));
+        }
+        if self.context_lang_fence {
+            return Ok(Some(
+                "it is wrapped in a code fence by --context-lang-fence",
+            ));
+        }
+
+        let context = context.unwrap_or_default();
+        if std::fs::read(path)? != context.as_bytes() {
+            return Ok(Some(
+                "it was truncated to fit the token budget or decoded with --encoding lossy",
+            ));
+        }
+        if redaction_preview(context) != context {
+            return Ok(Some(
+                "secrets in it are redacted; pass --no-redact to send them",
+            ));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the language tag of the context fence: the declared language,
+    /// or one detected from the context file's extension.
+    fn fence_language(&self) -> Option<String> {
+        self.context_lang
+            .clone()
+            .or_else(|| self.extension().map(|ext| fence_language(ext).to_string()))
+    }
+
+    fn wrap(&self, context: &str) -> String {
+        let mut wrapped = String::new();
+
+        if let Some(prefix) = &self.context_prefix {
+            wrapped.push_str(prefix);
+            wrapped.push('\n');
+        }
+        if self.context_lang_fence {
+            wrapped.push_str(&fence(context, self.fence_language().as_deref()));
+        } else {
+            wrapped.push_str(context);
+        }
+        if let Some(suffix) = &self.context_suffix {
+            wrapped.push('\n');
+            wrapped.push_str(suffix);
+        }
+
+        wrapped
+    }
+}
+
+/// Wraps `context` in a code fence tagged with `language`. The fence is made
+/// longer than any run of backticks in the context so it cannot be closed early.
+fn fence(context: &str, language: Option<&str>) -> String {
+    let longest_run = context.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+
+    let body = context.strip_suffix('\n').unwrap_or(context);
+    format!("{fence}{}\n{body}\n{fence}", language.unwrap_or_default())
+}
+
+/// Returns the fence language tag for a file extension, or the extension
+/// itself when it is already the usual tag.
+fn fence_language(extension: &str) -> &str {
+    match extension {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "tsx" => "tsx",
+        "jsx" => "jsx",
+        "rb" => "ruby",
+        "kt" | "kts" => "kotlin",
+        "cs" => "csharp",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" => "cpp",
+        "h" => "c",
+        "sh" | "bash" | "zsh" => "bash",
+        "yml" => "yaml",
+        "md" => "markdown",
+        "hs" => "haskell",
+        "ex" | "exs" => "elixir",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, path::Path};
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        context: ContextArgs,
+    }
+
+    thread_local! {
+        static CLIPBOARD: RefCell<Option<String>> = const { RefCell::new(None) };
+        static STDIN: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+    }
+
+    /// Returns the text set by `set_mock_clipboard` on this thread, which
+    /// replaces the system clipboard.
+    pub fn mock_clipboard() -> Option<String> {
+        CLIPBOARD.with(|clipboard| clipboard.borrow().clone())
+    }
+
+    fn set_mock_clipboard(text: &str) {
+        CLIPBOARD.with(|clipboard| *clipboard.borrow_mut() = Some(text.to_string()));
+    }
+
+    /// Returns the bytes set by `set_mock_stdin` on this thread, which stand
+    /// in for piped stdin.
+    pub fn mock_stdin() -> Option<Vec<u8>> {
+        STDIN.with(|stdin| stdin.borrow().clone())
+    }
+
+    fn set_mock_stdin(bytes: &[u8]) {
+        STDIN.with(|stdin| *stdin.borrow_mut() = Some(bytes.to_vec()));
+    }
+
+    fn context_args(args: &[&str]) -> ContextArgs {
+        TestCli::parse_from(std::iter::once("test").chain(args.iter().copied())).context
+    }
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ca-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn path_arg(path: &Path) -> &str {
+        path.to_str().unwrap()
+    }
+
+    #[test]
+    fn accepts_a_file_sent_unchanged() {
+        let path = temp_file("unchanged.rs", "fn main() {}\n");
+        let args = context_args(&["--context-file", path_arg(&path)]);
+
+        let context = args.read(None).unwrap();
+
+        assert_eq!(args.differs_from_file(context.as_deref()).unwrap(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_wrapped_truncated_and_redacted_files() {
+        let path = temp_file("wrapped.rs", "fn main() {}\n");
+        for flags in [
+            ["--context-prefix", "Before"],
+            ["--context-suffix", "After"],
+            ["--context-lang", "rust"],
+        ] {
+            let mut args = vec!["--context-file", path_arg(&path), flags[0], flags[1]];
+            if flags[0] == "--context-lang" {
+                args.push("--context-lang-fence");
+            }
+            let args = context_args(&args);
+            let context = args.read(None).unwrap();
+            assert!(args
+                .differs_from_file(context.as_deref())
+                .unwrap()
+                .is_some());
+        }
+
+        let args = context_args(&["--context-file", path_arg(&path)]);
+        assert!(args.differs_from_file(Some("fn main")).unwrap().is_some());
+        std::fs::remove_file(path).unwrap();
+
+        let path = temp_file("secret.env", "OPENAI_API_KEY=abc123\n");
+        let args = context_args(&["--context-file", path_arg(&path)]);
+        let context = args.read(None).unwrap();
+        assert!(args
+            .differs_from_file(context.as_deref())
+            .unwrap()
+            .is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn brackets_the_context_with_the_prefix_and_suffix() {
+        let path = temp_file("bracketed.rs", "fn main() {}\n");
+        let args = context_args(&[
+            "--context-file",
+            path_arg(&path),
+            "--context-prefix",
+            "Here is our code:",
+            "--context-suffix",
+            "Follow the style guide.",
+        ]);
+
+        let context = args.read(None).unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+        let prompt = crate::prompts::PromptBuilder::new()
+            .build(&std::collections::HashMap::from([(
+                "context".to_string(),
+                context,
+            )]))
+            .unwrap();
+
+        let prefix = prompt.find("Here is our code:\nfn main() {}").unwrap();
+        let suffix = prompt
+            .find("fn main() {}\n\nFollow the style guide.")
+            .unwrap();
+        assert!(prefix < suffix);
+    }
+
+    #[test]
+    fn echoes_the_context_the_model_receives() {
+        let path = temp_file(
+            "echoed.env",
+            "OPENAI_API_KEY=sk-abcdefghijklmnopqrstuvwxyz123456\n",
+        );
+        let args = context_args(&["--context-file", path_arg(&path), "--echo-context"]);
+
+        let context = args.read(None).unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+        // What `read` printed to stderr.
+        let echoed = redaction_preview(&context);
+        let prompt = crate::prompts::PromptBuilder::new()
+            .build(&std::collections::HashMap::from([(
+                "context".to_string(),
+                context,
+            )]))
+            .unwrap();
+
+        assert!(prompt.contains(&echoed), "{echoed} not in {prompt}");
+        assert!(!echoed.contains("sk-abcdefghijklmnopqrstuvwxyz123456"));
+    }
+
+    #[test]
+    fn rejects_or_replaces_invalid_utf8() {
+        let path = std::env::temp_dir().join(format!("ca-{}-latin1.txt", std::process::id()));
+        // "café" in Latin-1.
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+
+        let strict = context_args(&["--context-file", path_arg(&path)])
+            .read(None)
+            .unwrap_err();
+        let lossy = context_args(&["--context-file", path_arg(&path), "--encoding", "lossy"])
+            .read(None)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(strict.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            strict
+                .to_string()
+                .contains("is not valid UTF-8 (invalid byte at offset 3)"),
+            "{strict}"
+        );
+        assert_eq!(lossy.as_deref(), Some("caf\u{fffd}\n"));
+    }
+
+    #[test]
+    fn ignores_piped_stdin_without_context() {
+        set_mock_stdin(b"junk\n");
+
+        assert_eq!(context_args(&["--no-context"]).read(None).unwrap(), None);
+        assert_eq!(
+            context_args(&[]).read(None).unwrap().as_deref(),
+            Some("junk\n")
+        );
+    }
+
+    #[test]
+    fn sends_the_clipboard_text_as_context() {
+        set_mock_clipboard("fn main() {}");
+        let args = context_args(&["--clipboard", "--context-prefix", "Copied:"]);
+
+        let context = args.read(None).unwrap().unwrap();
+        let prompt = crate::prompts::PromptBuilder::new()
+            .build(&std::collections::HashMap::from([(
+                "context".to_string(),
+                context,
+            )]))
+            .unwrap();
+
+        assert!(prompt.contains("Copied:\nfn main() {}"), "{prompt}");
+    }
+
+    #[test]
+    fn sends_no_context_from_an_empty_clipboard() {
+        assert_eq!(clipboard_context(Ok(" \n".to_string())).unwrap(), None);
+        assert_eq!(
+            clipboard_context(Err(arboard::Error::ContentNotAvailable)).unwrap(),
+            None
+        );
+
+        let unsupported =
+            clipboard_context(Err(arboard::Error::ClipboardNotSupported)).unwrap_err();
+        assert!(unsupported
+            .to_string()
+            .starts_with("the clipboard is not available"));
+    }
+
+    #[test]
+    fn fences_the_context_with_its_language() {
+        let path = temp_file("fenced.py", "print(\"```\")\n");
+
+        let detected = context_args(&["--context-file", path_arg(&path), "--context-lang-fence"])
+            .read(None)
+            .unwrap();
+        let declared = context_args(&[
+            "--context-file",
+            path_arg(&path),
+            "--context-lang-fence",
+            "--context-lang",
+            "python3",
+        ])
+        .read(None)
+        .unwrap();
+        let unfenced = context_args(&["--context-file", path_arg(&path)])
+            .read(None)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            detected.as_deref(),
+            Some("````python\nprint(\"```\")\n````")
+        );
+        assert_eq!(
+            declared.as_deref(),
+            Some("````python3\nprint(\"```\")\n````")
+        );
+        assert_eq!(unfenced.as_deref(), Some("print(\"```\")\n"));
+    }
+}
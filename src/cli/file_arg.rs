@@ -0,0 +1,60 @@
+use std::io::Read;
+
+/// Parses a string argument, reading it from a file when prefixed with `@`.
+///
+/// `@path` reads the file at `path`, `@-` reads stdin, and `@@` escapes a
+/// literal leading `@`. Any other value is used as is.
+pub fn file_or_value(value: &str) -> Result<String, String> {
+    read_value(value, std::io::stdin())
+}
+
+fn read_value(value: &str, mut stdin: impl Read) -> Result<String, String> {
+    if let Some(literal) = value.strip_prefix("@@") {
+        return Ok(format!("@{literal}"));
+    }
+
+    match value.strip_prefix('@') {
+        Some("-") => {
+            let mut contents = String::new();
+            stdin
+                .read_to_string(&mut contents)
+                .map_err(|e| format!("failed to read stdin: {e}"))?;
+            Ok(contents)
+        }
+        Some(path) => {
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read {path}: {e}"))
+        }
+        None => Ok(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn reads_files_and_stdin() {
+        let path = std::env::temp_dir().join(format!("acai-file-arg-{}.md", std::process::id()));
+        std::fs::write(&path, "Fix the bug.").unwrap();
+
+        let from_file = read_value(&format!("@{}", path.display()), std::io::empty());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(from_file.unwrap(), "Fix the bug.");
+        assert_eq!(
+            read_value("@-", Cursor::new("from stdin")).unwrap(),
+            "from stdin"
+        );
+    }
+
+    #[test]
+    fn keeps_plain_and_escaped_values() {
+        assert_eq!(read_value("plain", std::io::empty()).unwrap(), "plain");
+        assert_eq!(read_value("@@handle", std::io::empty()).unwrap(), "@handle");
+        assert!(read_value("@/no/such/file", std::io::empty())
+            .unwrap_err()
+            .starts_with("failed to read /no/such/file"));
+    }
+}
@@ -1,5 +1,17 @@
+#[cfg(test)]
+pub mod capture;
 mod cmd_runner;
 mod cmds;
+mod confirm;
+mod context_args;
+mod file_arg;
+mod output;
+mod status;
 
 pub use cmd_runner::*;
 pub use cmds::*;
+pub use confirm::*;
+pub use context_args::*;
+pub use file_arg::*;
+pub use output::*;
+pub use status::*;
@@ -0,0 +1,245 @@
+use std::{io, path::PathBuf};
+
+use clap::Args;
+use serde::Serialize;
+use serde_json::json;
+use termimad::MadSkin;
+
+use crate::config::{Config, Theme};
+
+/// A destination for the result of a command.
+pub trait OutputSink {
+    fn write(&self, result: &str) -> io::Result<()>;
+}
+
+/// Prints `text` to stdout.
+fn print_stdout(text: &str) {
+    #[cfg(test)]
+    if super::capture::stdout(text) {
+        return;
+    }
+    print!("{text}");
+}
+
+/// Renders the result as markdown on stdout.
+pub struct MarkdownSink {
+    skin: MadSkin,
+}
+
+impl MarkdownSink {
+    fn format(&self, result: &str) -> String {
+        self.skin.term_text(result).to_string()
+    }
+}
+
+impl OutputSink for MarkdownSink {
+    fn write(&self, result: &str) -> io::Result<()> {
+        print_stdout(&self.format(result));
+        Ok(())
+    }
+}
+
+/// Prints the result to stdout as is.
+pub struct RawSink;
+
+impl RawSink {
+    fn format(result: &str) -> String {
+        format!("{result}\n")
+    }
+}
+
+impl OutputSink for RawSink {
+    fn write(&self, result: &str) -> io::Result<()> {
+        print_stdout(&Self::format(result));
+        Ok(())
+    }
+}
+
+/// Writes the result to a file, replacing its contents.
+pub struct FileSink {
+    path: PathBuf,
+}
+
+impl OutputSink for FileSink {
+    fn write(&self, result: &str) -> io::Result<()> {
+        std::fs::write(&self.path, result)
+    }
+}
+
+/// Prints the result to stdout as a JSON object with a `result` field.
+pub struct JsonSink {
+    pretty: bool,
+}
+
+impl JsonSink {
+    fn format(&self, result: &str) -> serde_json::Result<String> {
+        Ok(format!(
+            "{}\n",
+            to_json(&json!({ "result": result }), self.pretty)?
+        ))
+    }
+}
+
+impl OutputSink for JsonSink {
+    fn write(&self, result: &str) -> io::Result<()> {
+        print_stdout(&self.format(result)?);
+        Ok(())
+    }
+}
+
+/// Serializes the value as indented JSON, or on a single line when `pretty` is false.
+pub fn to_json<T: Serialize>(value: &T, pretty: bool) -> serde_json::Result<String> {
+    if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+/// Flags choosing how JSON output is laid out.
+#[derive(Clone, Args)]
+pub struct JsonFormatArgs {
+    /// Prints JSON indented over multiple lines
+    #[arg(long, conflicts_with = "compact")]
+    pub pretty: bool,
+
+    /// Prints JSON on a single line
+    #[arg(long)]
+    pub compact: bool,
+}
+
+impl JsonFormatArgs {
+    /// Returns whether JSON should be indented. Without flags, JSON is
+    /// indented when stdout is a terminal and compact when it is piped.
+    pub fn is_pretty(&self) -> bool {
+        if self.pretty || self.compact {
+            return self.pretty;
+        }
+        atty::is(atty::Stream::Stdout)
+    }
+}
+
+/// Flags choosing where a command writes its result.
+#[derive(Clone, Args)]
+pub struct OutputArgs {
+    /// Writes the result to this file instead of stdout
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+
+    /// Prints the result as JSON
+    #[arg(long, conflicts_with = "raw")]
+    pub json: bool,
+
+    /// Prints the result without markdown rendering
+    #[arg(long)]
+    pub raw: bool,
+
+    #[command(flatten)]
+    pub json_format: JsonFormatArgs,
+}
+
+impl OutputArgs {
+    /// Returns the sink selected by the flags.
+    ///
+    /// Without flags, results are rendered as markdown when stdout is a
+    /// terminal and printed as is otherwise.
+    pub fn sink(&self) -> Box<dyn OutputSink> {
+        if let Some(path) = &self.out {
+            return Box::new(FileSink { path: path.clone() });
+        }
+        if self.json {
+            return Box::new(JsonSink {
+                pretty: self.json_format.is_pretty(),
+            });
+        }
+        if self.raw || !atty::is(atty::Stream::Stdout) {
+            return Box::new(RawSink);
+        }
+
+        match Config::get()
+            .theme
+            .map_or_else(|| Some(MadSkin::default()), Theme::skin)
+        {
+            Some(skin) => Box::new(MarkdownSink { skin }),
+            None => Box::new(RawSink),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        format: JsonFormatArgs,
+    }
+
+    fn json_format(args: &[&str]) -> JsonFormatArgs {
+        TestCli::parse_from(std::iter::once("test").chain(args.iter().copied())).format
+    }
+
+    const RESULT: &str = "Use **`cargo fmt`**.";
+
+    #[test]
+    fn renders_markdown_with_the_skin() {
+        let sink = MarkdownSink {
+            skin: MadSkin::default(),
+        };
+
+        let rendered = sink.format(RESULT);
+
+        assert!(rendered.contains("cargo fmt"));
+        assert!(rendered.contains('\x1b'));
+        assert!(!rendered.contains("**"));
+    }
+
+    #[test]
+    fn prints_raw_results_as_is() {
+        assert_eq!(RawSink::format(RESULT), "Use **`cargo fmt`**.\n");
+    }
+
+    #[test]
+    fn wraps_results_in_json() {
+        let compact = JsonSink { pretty: false };
+        let pretty = JsonSink { pretty: true };
+
+        assert_eq!(
+            compact.format(RESULT).unwrap(),
+            "{\"result\":\"Use **`cargo fmt`**.\"}\n"
+        );
+        assert_eq!(
+            pretty.format(RESULT).unwrap(),
+            "{\n  \"result\": \"Use **`cargo fmt`**.\"\n}\n"
+        );
+    }
+
+    #[test]
+    fn writes_results_to_the_file() {
+        let path = std::env::temp_dir().join(format!("acai-output-{}.md", std::process::id()));
+        let sink = FileSink { path: path.clone() };
+
+        sink.write(RESULT).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), RESULT);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn lays_out_json_as_the_flags_choose() {
+        let payload = json!({ "model": "gpt-4o", "tokens": [1, 2, 3] });
+
+        let compact = to_json(&payload, json_format(&["--compact"]).is_pretty()).unwrap();
+        let pretty = to_json(&payload, json_format(&["--pretty"]).is_pretty()).unwrap();
+
+        assert_eq!(compact.lines().count(), 1);
+        assert!(pretty.lines().count() > 1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&compact).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&pretty).unwrap()
+        );
+    }
+}
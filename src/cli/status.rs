@@ -0,0 +1,24 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Turns status output off or on for the rest of the process.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Prints a progress or status line to stderr unless `--quiet` is set.
+///
+/// Command results go to stdout and are never silenced.
+pub fn status(message: impl Display) {
+    if !QUIET.load(Ordering::Relaxed) {
+        #[cfg(test)]
+        if super::capture::stderr(&format!("{message}\n")) {
+            return;
+        }
+        eprintln!("{message}");
+    }
+}
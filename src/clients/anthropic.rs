@@ -1,27 +1,228 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::models::{IntoMessage, Message, Role};
+use crate::models::{IntoMessage, IntoUsage, Message, Role, Usage};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
     pub role: Role,
+    #[serde(default)]
     pub content: Vec<Content>,
+    pub usage: Option<Usage>,
+    #[serde(default)]
+    pub stop_reason: Option<String>,
 }
 
 impl IntoMessage for Response {
     fn into_message(self) -> Option<Message> {
-        if let Some(content) = self.content.first() {
-            let msg = Message {
-                role: self.role,
-                content: content.text.to_string(),
-            };
-            return Some(msg);
+        let text: Vec<&str> = self
+            .content
+            .iter()
+            .filter_map(|content| match content {
+                Content::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        if text.is_empty() {
+            return None;
         }
-        None
+
+        let thinking: Vec<&str> = self
+            .content
+            .iter()
+            .filter_map(|content| match content {
+                Content::Thinking { thinking } => Some(thinking.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        Some(Message {
+            role: self.role,
+            content: text.concat(),
+            reasoning: (!thinking.is_empty()).then(|| thinking.join("\n\n")),
+            tool_call_id: None,
+            finish_reason: self.stop_reason,
+            metadata: None,
+        })
     }
 }
 
+/// A block of a response's content.
 #[derive(Serialize, Deserialize, Debug)]
-pub struct Content {
-    text: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Content {
+    Text {
+        text: String,
+    },
+    /// The model's extended thinking, returned when thinking is enabled.
+    Thinking {
+        thinking: String,
+    },
+    /// A request from the model to call one of the tools it was given.
+    ToolUse(ToolUse),
+    #[serde(other)]
+    Other,
+}
+
+/// A tool call requested by the model.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolUse {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+}
+
+impl Response {
+    /// Returns the tool calls in the response, in the order they were made.
+    #[allow(dead_code)]
+    pub fn tool_uses(&self) -> Vec<&ToolUse> {
+        self.content
+            .iter()
+            .filter_map(|content| match content {
+                Content::ToolUse(tool_use) => Some(tool_use),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A message as sent to the Messages API.
+///
+/// Tool results have no role of their own: they are sent as a user message
+/// holding a `tool_result` block.
+#[derive(Serialize, Debug)]
+pub struct RequestMessage {
+    pub role: Role,
+    pub content: RequestContent,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum RequestContent {
+    Text(String),
+    Blocks(Vec<RequestBlock>),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBlock {
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+}
+
+impl From<&Message> for RequestMessage {
+    fn from(value: &Message) -> Self {
+        match value.role {
+            Role::Tool => Self {
+                role: Role::User,
+                content: RequestContent::Blocks(vec![RequestBlock::ToolResult {
+                    tool_use_id: value.tool_call_id.clone().unwrap_or_default(),
+                    content: value.content.clone(),
+                }]),
+            },
+            role => Self {
+                role,
+                content: RequestContent::Text(value.content.clone()),
+            },
+        }
+    }
+}
+
+impl IntoUsage for Response {
+    fn usage(&self) -> Option<Usage> {
+        self.usage
+    }
+}
+
+/// An event of a streamed message.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockDelta {
+        delta: TextDelta,
+    },
+    MessageDelta {
+        #[serde(default)]
+        delta: StopDelta,
+        usage: OutputUsage,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamMessage {
+    pub usage: Usage,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TextDelta {
+    #[serde(default)]
+    pub text: String,
+    /// Extended thinking, sent in its own content block before the answer.
+    pub thinking: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StopDelta {
+    pub stop_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutputUsage {
+    pub output_tokens: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_text_blocks_and_keeps_tool_uses_apart() {
+        let response: Response = serde_json::from_str(
+            r#"{
+                "role": "assistant",
+                "content": [
+                    {"type": "text", "text": "Here is the fix. "},
+                    {"type": "tool_use", "id": "toolu_1", "name": "apply_edit", "input": {"line": 3}},
+                    {"type": "text", "text": "It handles empty input."}
+                ],
+                "usage": {"input_tokens": 10, "output_tokens": 20}
+            }"#,
+        )
+        .unwrap();
+
+        let tool_uses = response.tool_uses();
+        assert_eq!(tool_uses.len(), 1);
+        assert_eq!(tool_uses[0].name, "apply_edit");
+        assert_eq!(tool_uses[0].input["line"], 3);
+
+        assert_eq!(
+            response.into_message().unwrap().content,
+            "Here is the fix. It handles empty input."
+        );
+    }
+
+    #[test]
+    fn keeps_the_stop_reason() {
+        let response: Response = serde_json::from_str(
+            r#"{
+                "role": "assistant",
+                "content": [{"type": "text", "text": "fn main() {"}],
+                "stop_reason": "max_tokens",
+                "usage": {"input_tokens": 10, "output_tokens": 20}
+            }"#,
+        )
+        .unwrap();
+
+        let message = response.into_message().unwrap();
+        assert_eq!(message.finish_reason.as_deref(), Some("max_tokens"));
+        assert!(message.is_truncated());
+    }
 }
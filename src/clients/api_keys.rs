@@ -0,0 +1,119 @@
+use std::env;
+
+use keyring::Entry;
+
+use super::providers::Provider;
+
+/// The keyring service that provider keys are stored under.
+const KEYRING_SERVICE: &str = "coding-assistant";
+
+/// Returns the API key for the provider from its environment variable, or
+/// from the OS keyring when the variable is unset.
+pub fn api_key(provider: Provider) -> Option<String> {
+    env::var(provider.key_var())
+        .ok()
+        .or_else(|| stored_api_key(provider))
+}
+
+/// Returns the API key stored for the provider in the OS keyring.
+fn stored_api_key(provider: Provider) -> Option<String> {
+    keyring_entry(provider)
+        .and_then(|entry| entry.get_password())
+        .ok()
+}
+
+/// Stores the API key for the provider in the OS keyring.
+pub fn store_api_key(provider: Provider, key: &str) -> keyring::Result<()> {
+    keyring_entry(provider)?.set_password(key)
+}
+
+fn keyring_entry(provider: Provider) -> keyring::Result<Entry> {
+    Entry::new(KEYRING_SERVICE, provider.key_var())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        any::Any,
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+
+    use super::*;
+
+    type Store = Arc<Mutex<HashMap<String, String>>>;
+
+    /// A keyring kept in memory, shared by every entry built from it.
+    #[derive(Debug, Default)]
+    struct MemoryKeyring(Store);
+
+    #[derive(Debug)]
+    struct MemoryCredential {
+        store: Store,
+        key: String,
+    }
+
+    impl CredentialBuilderApi for MemoryKeyring {
+        fn build(
+            &self,
+            _target: Option<&str>,
+            service: &str,
+            user: &str,
+        ) -> keyring::Result<Box<Credential>> {
+            Ok(Box::new(MemoryCredential {
+                store: Arc::clone(&self.0),
+                key: format!("{service}/{user}"),
+            }))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    impl CredentialApi for MemoryCredential {
+        fn set_password(&self, password: &str) -> keyring::Result<()> {
+            self.store
+                .lock()
+                .unwrap()
+                .insert(self.key.clone(), password.to_string());
+            Ok(())
+        }
+
+        fn get_password(&self) -> keyring::Result<String> {
+            self.store
+                .lock()
+                .unwrap()
+                .get(&self.key)
+                .cloned()
+                .ok_or(keyring::Error::NoEntry)
+        }
+
+        fn delete_password(&self) -> keyring::Result<()> {
+            self.store.lock().unwrap().remove(&self.key);
+            Ok(())
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn reads_stored_keys_unless_the_variable_is_set() {
+        keyring::set_default_credential_builder(Box::<MemoryKeyring>::default());
+
+        assert_eq!(stored_api_key(Provider::Fireworks), None);
+        store_api_key(Provider::Fireworks, "stored-key").unwrap();
+        assert_eq!(
+            stored_api_key(Provider::Fireworks).as_deref(),
+            Some("stored-key")
+        );
+
+        // Other tests set every provider's variable to this value.
+        env::set_var(Provider::Fireworks.key_var(), "test-key");
+        assert_eq!(api_key(Provider::Fireworks).as_deref(), Some("test-key"));
+    }
+}
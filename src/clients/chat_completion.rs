@@ -1,18 +1,99 @@
-use std::{env, error::Error};
+use std::{error::Error, future::Future};
 
-use reqwest::Client;
+use log::{debug, info, warn};
+use reqwest::{RequestBuilder, StatusCode};
 use serde_json::{json, Value};
+use thiserror::Error;
+use uuid::Uuid;
 
-use crate::models::{IntoMessage, Message, Role};
+use crate::models::{tokenizer_for, IntoMessage, IntoUsage, Message, Role, TurnMetadata, Usage};
 
 use super::{
-    anthropic::Response as AnthropicResponse,
-    google::{Instruction, Part, Request, Response as GoogleResponse, SystemInstruction},
+    anthropic::{
+        RequestMessage, Response as AnthropicResponse, StreamEvent as AnthropicStreamEvent,
+    },
+    api_key, extract_text,
+    google::{
+        Instruction, Part, Request, Response as GoogleResponse, SafetySetting, SystemInstruction,
+    },
     mistral::Response as MistralResponse,
-    open_ai::Response as OpenAIResponse,
-    providers::{Model, Provider},
+    open_ai::{
+        RequestMessage as OpenAIRequestMessage, Response as OpenAIResponse,
+        StreamChunk as OpenAIStreamChunk,
+    },
+    providers::{MaxTokens, Model, Provider, ReasoningEffort, DEFAULT_MAX_TOKENS},
+    rate_limit::RateLimit,
 };
 
+/// Returned when a request fails before a usable response is received.
+#[derive(Error, Debug)]
+#[error("{model} (request {request_id})\n\n{details}")]
+pub struct RequestError {
+    pub model: String,
+    pub request_id: Uuid,
+    pub kind: RequestErrorKind,
+    pub details: String,
+}
+
+/// Why a request failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestErrorKind {
+    /// The provider could not be reached, e.g. its host did not resolve, the
+    /// connection was refused or the request timed out.
+    Network,
+    /// The provider answered with this status but no usable response.
+    Status(StatusCode),
+}
+
+impl RequestError {
+    /// Returns the error for a request that could not be sent.
+    pub fn network(model: String, request_id: Uuid, error: &reqwest::Error) -> Self {
+        Self {
+            model,
+            request_id,
+            kind: RequestErrorKind::Network,
+            details: super::describe_send_error(error),
+        }
+    }
+
+    /// Returns the HTTP status, or `None` if the request could not be sent.
+    pub const fn status(&self) -> Option<StatusCode> {
+        match self.kind {
+            RequestErrorKind::Network => None,
+            RequestErrorKind::Status(status) => Some(status),
+        }
+    }
+
+    /// Returns whether another provider might succeed where this one failed.
+    ///
+    /// Connection failures, auth and rate-limit errors, and server errors are
+    /// retryable; other client errors mean the request itself is at fault.
+    pub fn is_retryable(&self) -> bool {
+        self.status().is_none_or(|status| {
+            matches!(
+                status,
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+            ) || status.is_server_error()
+        })
+    }
+
+    /// Returns whether the provider rejected the prompt for exceeding the
+    /// model's context window.
+    pub fn is_context_length_exceeded(&self) -> bool {
+        const MARKERS: [&str; 5] = [
+            "context_length_exceeded",
+            "prompt is too long",
+            "exceeds the maximum number of tokens",
+            "too large for model",
+            "maximum context length",
+        ];
+
+        let details = self.details.to_lowercase();
+        self.status().is_some_and(|status| status.is_client_error())
+            && MARKERS.iter().any(|marker| details.contains(marker))
+    }
+}
+
 #[allow(clippy::module_name_repetitions)]
 pub struct ChatCompletionClient {
     provider: Provider,
@@ -20,7 +101,7 @@ pub struct ChatCompletionClient {
     token: String,
     temperature: Option<f32>,
     top_p: Option<f32>,
-    max_tokens: Option<u32>,
+    max_tokens: MaxTokens,
     system: String,
     messages: Vec<Message>,
     stop: Option<Vec<String>>,
@@ -30,23 +111,32 @@ pub struct ChatCompletionClient {
     user: Option<String>,
     top_k: Option<u32>,
     stream: bool,
+    safety_settings: Option<Vec<SafetySetting>>,
+    reasoning_effort: Option<ReasoningEffort>,
+    anthropic_beta: Vec<String>,
+    usage: Usage,
 }
 
 impl ChatCompletionClient {
     pub fn new(provider: Provider, model: Model, system_prompt: &str) -> Self {
-        let token = match provider {
-            Provider::Anthropic => env::var("CLAUDE_API_KEY"),
-            Provider::OpenAI => env::var("OPENAI_API_KEY"),
-            Provider::Mistral => env::var("MISTRAL_API_KEY"),
-            Provider::Google => env::var("GOOGLE_API_KEY"),
-        }
-        .unwrap_or_else(|_error| panic!("Error: Environment variable not set."));
+        let token = api_key(provider).unwrap_or_else(|| {
+            panic!(
+                "Error: {} not set and no key stored with `config set-key`.",
+                provider.key_var()
+            )
+        });
 
         let msgs: Vec<Message> = match provider {
-            Provider::OpenAI | Provider::Mistral => vec![Message {
-                role: Role::System,
-                content: system_prompt.to_string(),
-            }],
+            Provider::OpenAI | Provider::Mistral | Provider::Fireworks | Provider::Together => {
+                vec![Message {
+                    role: Role::System,
+                    content: system_prompt.to_string(),
+                    reasoning: None,
+                    tool_call_id: None,
+                    finish_reason: None,
+                    metadata: None,
+                }]
+            }
             Provider::Google | Provider::Anthropic => vec![],
         };
 
@@ -55,7 +145,7 @@ impl ChatCompletionClient {
             model,
             token,
             temperature: Some(0.0),
-            max_tokens: Some(1028),
+            max_tokens: DEFAULT_MAX_TOKENS,
             top_p: None,
             system: system_prompt.to_string(),
             messages: msgs,
@@ -66,6 +156,10 @@ impl ChatCompletionClient {
             user: None,
             top_k: None,
             stream: false,
+            safety_settings: None,
+            reasoning_effort: None,
+            anthropic_beta: Vec::new(),
+            usage: Usage::default(),
         }
     }
 
@@ -83,13 +177,26 @@ impl ChatCompletionClient {
         self
     }
 
-    pub const fn max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+    pub const fn max_tokens(mut self, max_tokens: Option<MaxTokens>) -> Self {
         if let Some(max_tokens) = max_tokens {
-            self.max_tokens = Some(max_tokens);
+            self.max_tokens = max_tokens;
         }
         self
     }
 
+    /// Seeds the conversation with previously saved messages.
+    ///
+    /// System messages are skipped since the client already carries its own
+    /// system prompt.
+    pub fn history(mut self, messages: Vec<Message>) -> Self {
+        self.messages.extend(
+            messages
+                .into_iter()
+                .filter(|msg| !matches!(msg.role, Role::System)),
+        );
+        self
+    }
+
     #[allow(dead_code)]
     pub fn stop(mut self, stop: Vec<String>) -> Self {
         self.stop = Some(stop);
@@ -132,30 +239,153 @@ impl ChatCompletionClient {
         self
     }
 
+    /// Sets the reasoning effort, sent as OpenAI's `reasoning_effort` or as an
+    /// Anthropic extended thinking budget. Ignored by other providers.
+    pub const fn reasoning_effort(mut self, reasoning_effort: Option<ReasoningEffort>) -> Self {
+        if let Some(reasoning_effort) = reasoning_effort {
+            self.reasoning_effort = Some(reasoning_effort);
+        }
+        self
+    }
+
+    /// Adds Anthropic beta feature flags, sent comma-joined in the
+    /// `anthropic-beta` header. Ignored by other providers.
+    pub fn anthropic_beta(mut self, flags: impl IntoIterator<Item = String>) -> Self {
+        self.anthropic_beta.extend(flags);
+        self
+    }
+
+    /// Sets the Google `safetySettings` thresholds. Ignored by other providers.
+    #[allow(dead_code)]
+    pub fn safety_settings(mut self, safety_settings: Vec<SafetySetting>) -> Self {
+        self.safety_settings = Some(safety_settings);
+        self
+    }
+
+    /// Adds a system instruction partway through the conversation.
+    ///
+    /// Providers that accept system messages anywhere get a new system-role
+    /// message; Anthropic and Google only take a single system instruction, so
+    /// the text is appended to it instead.
+    pub fn add_system_message(&mut self, content: &str) {
+        match self.provider {
+            Provider::OpenAI | Provider::Mistral | Provider::Fireworks | Provider::Together => {
+                self.messages.push(Message {
+                    role: Role::System,
+                    content: content.to_string(),
+                    reasoning: None,
+                    tool_call_id: None,
+                    finish_reason: None,
+                    metadata: None,
+                });
+            }
+            Provider::Anthropic | Provider::Google => {
+                self.system.push_str("\n\n");
+                self.system.push_str(content);
+            }
+        }
+    }
+
     pub async fn send_message(
         &mut self,
         message: Message,
     ) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
         self.messages.push(message);
 
-        let prompt = match &self.provider {
+        if self.stream && self.supports_streaming() {
+            self.receive_stream(|_| {}, std::future::pending()).await
+        } else {
+            self.receive().await
+        }
+    }
+
+    /// Sends a message and streams the response, calling `on_delta` with each
+    /// piece of text as it arrives.
+    ///
+    /// If `cancel` completes before the response does, the text received so
+    /// far is kept as the assistant's turn. Providers without streaming support
+    /// are sent a regular request and `on_delta` is called once with the whole
+    /// response.
+    pub async fn send_message_streaming<F, C>(
+        &mut self,
+        message: Message,
+        mut on_delta: F,
+        cancel: C,
+    ) -> Result<Option<Message>, Box<dyn Error + Send + Sync>>
+    where
+        F: FnMut(&str),
+        C: Future<Output = ()>,
+    {
+        self.messages.push(message);
+
+        if self.supports_streaming() {
+            return self.receive_stream(on_delta, cancel).await;
+        }
+
+        tokio::select! {
+            response = self.receive() => {
+                let response = response?;
+                if let Some(msg) = &response {
+                    on_delta(&msg.content);
+                }
+                Ok(response)
+            }
+            () = cancel => {
+                // Nothing was received, so drop the unanswered message.
+                self.messages.pop();
+                Ok(None)
+            }
+        }
+    }
+
+    const fn supports_streaming(&self) -> bool {
+        matches!(
+            self.provider,
+            Provider::Anthropic | Provider::OpenAI | Provider::Fireworks | Provider::Together
+        )
+    }
+
+    fn prompt_tokens(&self) -> u32 {
+        let tokenizer = tokenizer_for(&self.model);
+        tokenizer.count(&self.system)
+            + self
+                .messages
+                .iter()
+                .map(|msg| msg.tokens(tokenizer))
+                .sum::<u32>()
+    }
+
+    /// Counts the tokens in `text` with the model's tokenizer.
+    fn tokens(&self, text: &str) -> u32 {
+        tokenizer_for(&self.model).count(text)
+    }
+
+    /// Builds the request for the conversation, tagged with a new request id.
+    fn request(
+        &self,
+        stream: bool,
+    ) -> Result<(Uuid, RequestBuilder), Box<dyn Error + Send + Sync>> {
+        self.max_tokens.validate(&self.model)?;
+        let max_tokens = self.max_tokens.resolve(&self.model, self.prompt_tokens());
+
+        let mut prompt = match &self.provider {
             Provider::Anthropic => json!({
                 "model": self.model,
                 "temperature": self.temperature,
-                "max_tokens": self.max_tokens,
+                "max_tokens": max_tokens,
                 "top_p": self.top_p,
                 "top_k": self.top_k,
-                "stream": self.stream,
+                "stream": stream,
                 "system": self.system,
-                "messages": self.messages
+                "messages": self.messages.iter().map(RequestMessage::from).collect::<Vec<_>>()
             }),
-            Provider::OpenAI => json!({
+            Provider::OpenAI | Provider::Fireworks | Provider::Together => json!({
                 "model": self.model,
                 "temperature": self.temperature,
                 "top_p": self.top_p,
-                "max_tokens": self.max_tokens,
-                "stream": self.stream,
-                "messages": self.messages,
+                "max_tokens": max_tokens,
+                "stream": stream,
+                "messages": self.messages.iter().map(OpenAIRequestMessage::from).collect::<Vec<_>>(),
                 "presence_penalty": self.presence_penalty,
                 "frequency_penalty": self.frequency_penalty,
                 "stop": self.stop,
@@ -169,71 +399,380 @@ impl ChatCompletionClient {
                     },
                 },
                 contents: self.messages.iter().map(Instruction::from).collect(),
+                safety_settings: self.safety_settings.clone(),
             })?,
             Provider::Mistral => json!({}),
         };
 
-        let request_url = match &self.provider {
-            Provider::Anthropic => "https://api.anthropic.com/v1/messages".to_string(),
-            Provider::OpenAI => "https://api.openai.com/v1/chat/completions".to_string(),
-            Provider::Mistral => "https://api.mistral.ai/v1/chat/completions".to_string(),
-            Provider::Google => format!(
-                "https://generativelanguage.googleapis.com/v1beta/models/{}/generateContent?key={}",
-                self.model, self.token
-            ),
-        };
+        if let Some(effort) = self.reasoning_effort {
+            match self.provider {
+                Provider::OpenAI => {
+                    prompt["reasoning_effort"] = json!(effort.as_str());
+                }
+                Provider::Anthropic => {
+                    // Thinking counts towards max_tokens and does not allow
+                    // changing the sampling parameters.
+                    prompt["thinking"] = json!({
+                        "type": "enabled",
+                        "budget_tokens": effort.budget_tokens(),
+                    });
+                    prompt["max_tokens"] = json!(max_tokens + effort.budget_tokens());
+                    if let Some(payload) = prompt.as_object_mut() {
+                        payload.remove("temperature");
+                        payload.remove("top_p");
+                        payload.remove("top_k");
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        let req_base = Client::new()
-            .post(request_url)
-            .json(&prompt)
-            .header("content-type", "application/json");
+        // OpenAI only reports usage for streamed responses when asked to.
+        if stream && matches!(self.provider, Provider::OpenAI) {
+            prompt["stream_options"] = json!({ "include_usage": true });
+        }
 
-        let req = match &self.provider {
-            Provider::Anthropic => req_base
-                .header("anthropic-version", "2023-06-01")
-                .header("x-api-key", self.token.to_string()),
-            Provider::OpenAI | Provider::Mistral => req_base.bearer_auth(self.token.to_string()),
-            Provider::Google => req_base,
-        };
+        let request_id = Uuid::new_v4();
+
+        let req = authorize(
+            super::http_client()
+                .post(endpoint(
+                    self.provider,
+                    &self.model.to_string(),
+                    &self.token,
+                ))
+                .json(&prompt)
+                .header("content-type", "application/json")
+                .header("x-client-request-id", request_id.to_string()),
+            self.provider,
+            &self.token,
+            &self.anthropic_beta,
+        );
+
+        Ok((request_id, req))
+    }
+
+    /// Sends the conversation to the provider, returning the request id and the response.
+    async fn send_request(
+        &mut self,
+        stream: bool,
+    ) -> Result<(Uuid, reqwest::Response), Box<dyn Error + Send + Sync>> {
+        let (request_id, req) = self.request(stream)?;
+
+        // Wait out an exhausted budget instead of sending a request bound to be rejected.
+        if let Some(wait) =
+            RateLimit::latest(self.provider).and_then(|rate_limit| rate_limit.wait())
+        {
+            info!("Rate limit reached for {}, waiting {wait:?}", self.model);
+            tokio::time::sleep(wait).await;
+        }
 
-        let response = req.send().await?;
+        debug!("[{request_id}] sending request to {}", self.model);
+
+        let response = req
+            .send()
+            .await
+            .map_err(|e| RequestError::network(self.model.to_string(), request_id, &e))?;
+
+        debug!(
+            "[{request_id}] {} responded with {}",
+            self.model,
+            response.status()
+        );
+
+        if let Some(rate_limit) = RateLimit::from_headers(response.headers()) {
+            debug!("[{request_id}] rate limit {rate_limit:?}");
+            rate_limit.record(self.provider);
+        }
 
         if response.status().is_success() {
-            let message = match &self.provider {
-                Provider::Anthropic => {
-                    let anth_response = response.json::<AnthropicResponse>().await?;
-                    anth_response.into_message()
-                }
-                Provider::OpenAI => {
-                    let ai_response = response.json::<OpenAIResponse>().await?;
-                    ai_response.into_message()
-                }
-                Provider::Mistral => {
-                    let mistral_response = response.json::<MistralResponse>().await?;
-                    mistral_response.into_message()
+            Ok((request_id, response))
+        } else {
+            Err(self.error_response(request_id, response).await)
+        }
+    }
+
+    async fn error_response(
+        &self,
+        request_id: Uuid,
+        response: reqwest::Response,
+    ) -> Box<dyn Error + Send + Sync> {
+        let status = response.status();
+
+        let details = match response.json::<Value>().await {
+            Ok(resp_json) => {
+                serde_json::to_string_pretty(&resp_json).unwrap_or_else(|_| resp_json.to_string())
+            }
+            Err(e) => format!("Failed to parse response JSON: {e}"),
+        };
+
+        RequestError {
+            model: self.model.to_string(),
+            request_id,
+            kind: RequestErrorKind::Status(status),
+            details,
+        }
+        .into()
+    }
+
+    async fn receive(&mut self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        let (request_id, response) = self.send_request(false).await?;
+
+        let value = response.json::<Value>().await?;
+
+        let decoded = match &self.provider {
+            Provider::Anthropic => serde_json::from_value::<AnthropicResponse>(value.clone())
+                .map(|resp| (resp.usage(), resp.into_message())),
+            Provider::OpenAI | Provider::Fireworks | Provider::Together => {
+                serde_json::from_value::<OpenAIResponse>(value.clone())
+                    .map(|resp| (resp.usage(), resp.into_message()))
+            }
+            Provider::Mistral => serde_json::from_value::<MistralResponse>(value.clone())
+                .map(|resp| (resp.usage(), resp.into_message())),
+            Provider::Google => match serde_json::from_value::<GoogleResponse>(value.clone()) {
+                Ok(resp) => {
+                    if let Some(blocked) = resp.blocked() {
+                        self.record_usage(resp.usage());
+                        self.messages.pop();
+                        return Err(blocked.into());
+                    }
+                    Ok((resp.usage(), resp.into_message()))
                 }
-                Provider::Google => {
-                    let google_response = response.json::<GoogleResponse>().await?;
-                    google_response.into_message()
+                Err(e) => Err(e),
+            },
+        };
+
+        let (usage, message) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                let content = extract_text(&value).ok_or_else(|| {
+                    format!(
+                        "Failed to decode {} response (request {request_id}): {e}",
+                        self.model
+                    )
+                })?;
+
+                warn!(
+                    "[{request_id}] {} response did not match the expected schema ({e}), using best-effort text",
+                    self.model
+                );
+
+                let usage = Usage {
+                    input_tokens: self.prompt_tokens(),
+                    output_tokens: self.tokens(&content),
+                };
+
+                (
+                    Some(usage),
+                    Some(Message {
+                        role: Role::Assistant,
+                        content,
+                        reasoning: None,
+                        tool_call_id: None,
+                        finish_reason: None,
+                        metadata: None,
+                    }),
+                )
+            }
+        };
+
+        self.record_usage(usage);
+
+        // A successful response can still hold no answer, e.g. when every
+        // candidate was filtered, so drop the unanswered message.
+        let Some(message) = message else {
+            self.messages.pop();
+            return Err(RequestError {
+                model: self.model.to_string(),
+                request_id,
+                kind: RequestErrorKind::Status(StatusCode::OK),
+                details: "no candidates returned; the provider may have filtered every answer"
+                    .to_string(),
+            }
+            .into());
+        };
+
+        let message = Message {
+            metadata: Some(self.turn_metadata()),
+            ..message
+        };
+        self.messages.push(message.clone());
+
+        Ok(Some(message))
+    }
+
+    /// Reads a server-sent events response, passing each text delta to `on_delta`.
+    ///
+    /// Stops reading when `cancel` completes, keeping the text received so far.
+    async fn receive_stream<F, C>(
+        &mut self,
+        mut on_delta: F,
+        cancel: C,
+    ) -> Result<Option<Message>, Box<dyn Error + Send + Sync>>
+    where
+        F: FnMut(&str),
+        C: Future<Output = ()>,
+    {
+        tokio::pin!(cancel);
+
+        let (request_id, mut response) = tokio::select! {
+            response = self.send_request(true) => response?,
+            () = &mut cancel => {
+                self.messages.pop();
+                return Ok(None);
+            }
+        };
+
+        let mut content = String::new();
+        let mut reasoning = String::new();
+        let mut usage: Option<Usage> = None;
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut finish_reason: Option<String> = None;
+        let mut aborted = false;
+
+        loop {
+            let chunk = tokio::select! {
+                chunk = response.chunk() => chunk?,
+                () = &mut cancel => {
+                    debug!("[{request_id}] response aborted");
+                    aborted = true;
+                    break;
                 }
             };
+            let Some(chunk) = chunk else {
+                break;
+            };
 
-            if let Some(msg) = message.clone() {
-                self.messages.push(msg);
-            }
+            buffer.extend_from_slice(&chunk);
 
-            Ok(message)
-        } else {
-            match response.json::<Value>().await {
-                Ok(resp_json) => match serde_json::to_string_pretty(&resp_json) {
-                    Ok(resp_formatted) => {
-                        Err(format!("{}\n\n{}", self.model, resp_formatted).into())
-                    }
-                    Err(e) => Err(format!("Failed to format response JSON: {e}").into()),
-                },
-                Err(e) => Err(format!("Failed to parse response JSON: {e}").into()),
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    continue;
+                }
+
+                let delta = match &self.provider {
+                    Provider::Anthropic => match serde_json::from_str::<AnthropicStreamEvent>(data)
+                    {
+                        Ok(AnthropicStreamEvent::MessageStart { message }) => {
+                            usage = Some(message.usage);
+                            None
+                        }
+                        Ok(AnthropicStreamEvent::ContentBlockDelta { delta }) => {
+                            if let Some(thinking) = delta.thinking {
+                                reasoning.push_str(&thinking);
+                            }
+                            Some(delta.text)
+                        }
+                        Ok(AnthropicStreamEvent::MessageDelta {
+                            delta,
+                            usage: delta_usage,
+                        }) => {
+                            if let Some(usage) = usage.as_mut() {
+                                usage.output_tokens = delta_usage.output_tokens;
+                            }
+                            finish_reason = delta.stop_reason.or(finish_reason.take());
+                            None
+                        }
+                        Ok(AnthropicStreamEvent::Other) => None,
+                        Err(e) => {
+                            debug!("[{request_id}] skipping unparsable event: {e}");
+                            None
+                        }
+                    },
+                    _ => match serde_json::from_str::<OpenAIStreamChunk>(data) {
+                        Ok(stream_chunk) => {
+                            if let Some(chunk_usage) = stream_chunk.usage() {
+                                usage = Some(chunk_usage);
+                            }
+                            if let Some(reason) = stream_chunk.finish_reason() {
+                                finish_reason = Some(reason);
+                            }
+                            if let Some(thinking) = stream_chunk.reasoning() {
+                                reasoning.push_str(thinking);
+                            }
+                            stream_chunk.into_delta()
+                        }
+                        Err(e) => {
+                            debug!("[{request_id}] skipping unparsable chunk: {e}");
+                            None
+                        }
+                    },
+                };
+
+                if let Some(delta) = delta.filter(|delta| !delta.is_empty()) {
+                    on_delta(&delta);
+                    content.push_str(&delta);
+                }
             }
         }
+
+        // An empty assistant turn would be rejected by the next request.
+        if aborted && content.is_empty() && reasoning.is_empty() {
+            self.messages.pop();
+            return Ok(None);
+        }
+
+        // Fall back to an estimate when the provider did not report usage.
+        let usage = usage.unwrap_or_else(|| Usage {
+            input_tokens: self.prompt_tokens(),
+            output_tokens: self.tokens(&content),
+        });
+        self.record_usage(Some(usage));
+
+        let msg = Message {
+            role: Role::Assistant,
+            content,
+            reasoning: (!reasoning.is_empty()).then_some(reasoning),
+            tool_call_id: None,
+            finish_reason,
+            metadata: Some(self.turn_metadata()),
+        };
+        self.messages.push(msg.clone());
+
+        Ok(Some(msg))
+    }
+
+    /// Returns the settings of the request being answered. Called before the
+    /// answer is added to the history, so the prompt size matches the request.
+    fn turn_metadata(&self) -> TurnMetadata {
+        // Anthropic drops the sampling parameters when thinking is enabled.
+        let sampled = !(self.provider == Provider::Anthropic && self.reasoning_effort.is_some());
+
+        TurnMetadata {
+            model: self.model.id().to_string(),
+            temperature: self.temperature.filter(|_| sampled),
+            top_p: self.top_p.filter(|_| sampled),
+            max_tokens: self.max_tokens.resolve(&self.model, self.prompt_tokens()),
+        }
+    }
+
+    fn record_usage(&mut self, usage: Option<Usage>) {
+        if let Some(usage) = usage {
+            self.usage += usage;
+        }
+    }
+
+    /// Returns the token usage accumulated over every request sent by this client.
+    pub const fn get_usage(&self) -> Usage {
+        self.usage
+    }
+
+    /// Returns the model this client sends requests to.
+    pub const fn model(&self) -> &Model {
+        &self.model
+    }
+
+    /// Returns the rate limit the provider reported with its latest response
+    /// to any client in the process, if any.
+    pub fn get_rate_limit(&self) -> Option<RateLimit> {
+        RateLimit::latest(self.provider)
     }
 
     pub fn get_message_history(&self) -> Vec<Message> {
@@ -243,11 +782,605 @@ impl ChatCompletionClient {
                 let mut result = vec![Message {
                     role: Role::System,
                     content: self.system.to_string(),
+                    reasoning: None,
+                    tool_call_id: None,
+                    finish_reason: None,
+                    metadata: None,
                 }];
                 result.append(&mut msgs);
                 result
             }
-            Provider::OpenAI | Provider::Mistral => msgs,
+            Provider::OpenAI | Provider::Mistral | Provider::Fireworks | Provider::Together => msgs,
+        }
+    }
+}
+
+/// Returns the chat endpoint of `provider`. Only Google's includes the model
+/// and the key.
+fn endpoint(provider: Provider, model: &str, token: &str) -> String {
+    #[cfg(test)]
+    if let Some(url) = super::mock_server::endpoint() {
+        return url;
+    }
+
+    match provider {
+        Provider::Anthropic => "https://api.anthropic.com/v1/messages".to_string(),
+        Provider::OpenAI => "https://api.openai.com/v1/chat/completions".to_string(),
+        Provider::Mistral => "https://api.mistral.ai/v1/chat/completions".to_string(),
+        Provider::Fireworks => "https://api.fireworks.ai/inference/v1/chat/completions".to_string(),
+        Provider::Together => "https://api.together.xyz/v1/chat/completions".to_string(),
+        Provider::Google => format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{model}/generateContent?key={token}"
+        ),
+    }
+}
+
+/// Adds the auth and version headers `provider` expects to `req`.
+fn authorize(
+    req: RequestBuilder,
+    provider: Provider,
+    token: &str,
+    anthropic_beta: &[String],
+) -> RequestBuilder {
+    match provider {
+        Provider::Anthropic => {
+            let req = req
+                .header("anthropic-version", "2023-06-01")
+                .header("x-api-key", token);
+
+            if anthropic_beta.is_empty() {
+                req
+            } else {
+                req.header("anthropic-beta", anthropic_beta.join(","))
+            }
+        }
+        Provider::OpenAI | Provider::Mistral | Provider::Fireworks | Provider::Together => {
+            req.bearer_auth(token)
+        }
+        Provider::Google => req,
+    }
+}
+
+/// Posts `body` to the chat endpoint of `provider` byte for byte, with the
+/// provider's auth headers, and returns the response status and raw body.
+///
+/// `model` is only used by providers that put it in the URL.
+pub async fn send_raw(
+    provider: Provider,
+    model: &str,
+    body: String,
+    anthropic_beta: &[String],
+) -> Result<(StatusCode, String), Box<dyn Error + Send + Sync>> {
+    let token = api_key(provider).ok_or_else(|| {
+        format!(
+            "{} not set and no key stored with `config set-key`",
+            provider.key_var()
+        )
+    })?;
+
+    let request_id = Uuid::new_v4();
+    let req = authorize(
+        super::http_client()
+            .post(endpoint(provider, model, &token))
+            .body(body)
+            .header("content-type", "application/json")
+            .header("x-client-request-id", request_id.to_string()),
+        provider,
+        &token,
+        anthropic_beta,
+    );
+
+    debug!("[{request_id}] sending raw request to {provider:?}");
+
+    let response = req
+        .send()
+        .await
+        .map_err(|e| RequestError::network(model.to_string(), request_id, &e))?;
+
+    let status = response.status();
+    Ok((status, response.text().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::Notify;
+
+    use super::{
+        super::mock_server::{self, MockResponse, MockServer},
+        *,
+    };
+
+    fn user(content: &str) -> Message {
+        Message {
+            role: Role::User,
+            content: content.to_string(),
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        }
+    }
+
+    fn client(provider: Provider, model: Model) -> ChatCompletionClient {
+        std::env::set_var(provider.key_var(), "test-key");
+        let mut client = ChatCompletionClient::new(provider, model, "Be brief.");
+        client.messages.push(user("Hello"));
+        client
+    }
+
+    fn openai_delta(text: &str) -> Value {
+        json!({ "choices": [{ "delta": { "content": text }, "finish_reason": null }] })
+    }
+
+    fn build(client: &ChatCompletionClient, stream: bool) -> (Uuid, reqwest::Request) {
+        let (request_id, req) = client.request(stream).unwrap();
+        (request_id, req.build().unwrap())
+    }
+
+    fn body(request: &reqwest::Request) -> Value {
+        serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap()
+    }
+
+    fn header<'a>(request: &'a reqwest::Request, name: &str) -> Option<&'a str> {
+        request
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn tags_each_request_with_a_new_id() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                &json!({
+                    "choices": [{ "message": { "role": "assistant", "content": "Hi." }, "finish_reason": "stop" }],
+                    "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+                }),
+            ),
+            MockResponse::json(400, &json!({ "error": { "message": "bad request" } })),
+        ])
+        .await;
+        let mut client = client(Provider::OpenAI, Model::GPT4o);
+
+        client.send_message(user("First")).await.unwrap();
+        let error = client.send_message(user("Second")).await.unwrap_err();
+
+        let requests = server.requests();
+        let ids: Vec<&str> = requests
+            .iter()
+            .map(|request| request.headers["x-client-request-id"].as_str())
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+
+        let error = error.downcast::<RequestError>().unwrap();
+        assert_eq!(error.request_id.to_string(), ids[1]);
+        assert_eq!(
+            error.kind,
+            RequestErrorKind::Status(StatusCode::BAD_REQUEST)
+        );
+        assert!(error.to_string().contains(ids[1]), "{error}");
+    }
+
+    #[test]
+    fn sends_hosted_models_to_their_provider() {
+        for (provider, url, model_id) in [
+            (
+                Provider::Fireworks,
+                "https://api.fireworks.ai/inference/v1/chat/completions",
+                "accounts/fireworks/models/llama-v3-70b-instruct",
+            ),
+            (
+                Provider::Together,
+                "https://api.together.xyz/v1/chat/completions",
+                "meta-llama/Llama-3-70b-chat-hf",
+            ),
+        ] {
+            let client = client(provider, Model::Custom(model_id.to_string()));
+
+            let (_, request) = build(&client, false);
+
+            assert_eq!(request.url().as_str(), url);
+            assert_eq!(header(&request, "authorization"), Some("Bearer test-key"));
+            assert_eq!(body(&request)["model"], model_id);
+            assert_eq!(body(&request)["messages"][0]["role"], "system");
         }
     }
+
+    #[test]
+    fn asks_openai_for_usage_only_when_streaming() {
+        let openai = client(Provider::OpenAI, Model::GPT4o);
+        assert_eq!(
+            body(&build(&openai, true).1)["stream_options"],
+            json!({ "include_usage": true })
+        );
+        assert_eq!(body(&build(&openai, false).1).get("stream_options"), None);
+
+        let together = client(Provider::Together, Model::Custom("llama".to_string()));
+        assert_eq!(body(&build(&together, true).1).get("stream_options"), None);
+    }
+
+    #[test]
+    fn sends_tool_results_in_each_provider_shape() {
+        let tool_result = Message {
+            role: Role::Tool,
+            content: "42".to_string(),
+            reasoning: None,
+            tool_call_id: Some("call_1".to_string()),
+            finish_reason: None,
+            metadata: None,
+        };
+
+        let mut openai = client(Provider::OpenAI, Model::GPT4o);
+        openai.messages.push(tool_result.clone());
+        let messages = body(&build(&openai, false).1)["messages"].clone();
+        assert_eq!(
+            messages.as_array().unwrap().last().unwrap(),
+            &json!({ "role": "tool", "content": "42", "tool_call_id": "call_1" })
+        );
+
+        let mut anthropic = client(Provider::Anthropic, Model::Claude3_5Sonnet);
+        anthropic.messages.push(tool_result);
+        let messages = body(&build(&anthropic, false).1)["messages"].clone();
+        assert_eq!(
+            messages.as_array().unwrap().last().unwrap(),
+            &json!({
+                "role": "user",
+                "content": [{ "type": "tool_result", "tool_use_id": "call_1", "content": "42" }]
+            })
+        );
+    }
+
+    #[test]
+    fn maps_reasoning_effort_to_each_provider_field() {
+        let openai =
+            client(Provider::OpenAI, Model::GPT4o).reasoning_effort(Some(ReasoningEffort::High));
+        let request = body(&build(&openai, false).1);
+        assert_eq!(request["reasoning_effort"], "high");
+        assert_eq!(request.get("thinking"), None);
+
+        let anthropic = client(Provider::Anthropic, Model::Claude3_5Sonnet)
+            .temperature(Some(0.2))
+            .max_tokens(Some(MaxTokens::Fixed(1000)))
+            .reasoning_effort(Some(ReasoningEffort::Low));
+        let request = body(&build(&anthropic, false).1);
+        assert_eq!(
+            request["thinking"],
+            json!({ "type": "enabled", "budget_tokens": 1024 })
+        );
+        assert_eq!(request["max_tokens"], 2024);
+        assert_eq!(request.get("temperature"), None);
+        assert_eq!(request.get("reasoning_effort"), None);
+
+        let google = client(Provider::Google, Model::GeminiPro)
+            .reasoning_effort(Some(ReasoningEffort::Medium));
+        let request = body(&build(&google, false).1);
+        assert_eq!(request.get("reasoning_effort"), None);
+        assert_eq!(request.get("thinking"), None);
+    }
+
+    #[test]
+    fn adds_mid_conversation_system_messages_per_provider() {
+        let mut openai = client(Provider::OpenAI, Model::GPT4o);
+        openai.add_system_message("Be concise.");
+        let messages = body(&build(&openai, false).1)["messages"].clone();
+        assert_eq!(
+            messages.as_array().unwrap().last().unwrap(),
+            &json!({ "role": "system", "content": "Be concise." })
+        );
+
+        let mut anthropic = client(Provider::Anthropic, Model::Claude3_5Sonnet);
+        anthropic.add_system_message("Be concise.");
+        let request = body(&build(&anthropic, false).1);
+        assert_eq!(request["system"], "Be brief.\n\nBe concise.");
+        assert!(request["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|msg| msg["role"] != "system"));
+
+        let mut google = client(Provider::Google, Model::GeminiPro);
+        google.add_system_message("Be concise.");
+        assert_eq!(
+            body(&build(&google, false).1)["system_instruction"]["parts"]["text"],
+            "Be brief.\n\nBe concise."
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_partial_answer_of_an_aborted_stream() {
+        let server = MockServer::start(vec![
+            MockResponse::unfinished_stream(&[openai_delta("Once upon")]),
+            MockResponse::stream(&[openai_delta("Sure.")]),
+        ])
+        .await;
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let mut client = ChatCompletionClient::new(Provider::OpenAI, Model::GPT4o, "Be brief.");
+
+        let first_delta = Notify::new();
+        let partial = client
+            .send_message_streaming(
+                user("Tell me a story"),
+                |_| first_delta.notify_one(),
+                first_delta.notified(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(partial.content, "Once upon");
+        let history = client.get_message_history();
+        let last = history.last().unwrap();
+        assert!(matches!(last.role, Role::Assistant));
+        assert_eq!(last.content, "Once upon");
+
+        let next = client
+            .send_message_streaming(user("Go on"), |_| {}, std::future::pending())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(next.content, "Sure.");
+        assert_eq!(client.get_message_history().len(), history.len() + 2);
+        assert_eq!(
+            server.requests()[1].body["messages"][2]["content"],
+            "Once upon"
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_the_turn_aborted_before_the_first_delta() {
+        let server = MockServer::start(vec![
+            MockResponse::unfinished_stream(&[json!({
+                "choices": [{ "delta": { "role": "assistant" }, "finish_reason": null }]
+            })]),
+            MockResponse::stream(&[openai_delta("Sure.")]),
+        ])
+        .await;
+        let mut client = client(Provider::OpenAI, Model::GPT4o);
+        let history = client.get_message_history().len();
+
+        let aborted = client
+            .send_message_streaming(
+                user("Tell me a story"),
+                |_| panic!("no text was sent"),
+                async {
+                    // Wait for the request to be answered before aborting.
+                    while server.requests().is_empty() {
+                        tokio::task::yield_now().await;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(aborted.is_none());
+        assert_eq!(client.get_message_history().len(), history);
+
+        client
+            .send_message_streaming(user("Go on"), |_| {}, std::future::pending())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = server.requests()[1].body["messages"].clone();
+        let contents: Vec<&str> = sent
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|msg| msg["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(contents, ["Be brief.", "Hello", "Go on"]);
+    }
+
+    #[tokio::test]
+    async fn reports_an_unresolvable_host_as_a_network_error() {
+        mock_server::redirect("http://api.acai.invalid/v1/chat/completions");
+        let mut client = client(Provider::OpenAI, Model::GPT4o).stream(false);
+
+        let error = client.send_message(user("Hi")).await.unwrap_err();
+
+        let error = error.downcast::<RequestError>().unwrap();
+        assert_eq!(error.kind, RequestErrorKind::Network);
+        assert!(error.is_retryable());
+        assert!(
+            error.details.starts_with("cannot reach api.acai.invalid"),
+            "{}",
+            error.details
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_the_text_of_a_drifted_response() {
+        for (provider, model, drifted) in [
+            (
+                Provider::OpenAI,
+                Model::GPT4o,
+                json!({ "choices": [{ "message": { "content": "Hi there" } }], "usage": "n/a" }),
+            ),
+            (
+                Provider::Anthropic,
+                Model::Claude3_5Sonnet,
+                json!({ "content": [{ "type": "text", "text": "Hi there" }], "usage": "n/a" }),
+            ),
+            (
+                Provider::Google,
+                Model::GeminiPro,
+                json!({
+                    "candidates": [{ "content": { "parts": [{ "text": "Hi there" }] } }],
+                    "usageMetadata": "n/a"
+                }),
+            ),
+        ] {
+            let _server = MockServer::start(vec![MockResponse::json(200, &drifted)]).await;
+            let mut client = client(provider, model).stream(false);
+
+            let answer = client.send_message(user("Hi")).await.unwrap().unwrap();
+
+            assert_eq!(answer.content, "Hi there", "{provider:?}");
+            assert!(client.get_usage().output_tokens > 0, "{provider:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_responses_without_candidates() {
+        for (provider, model, empty) in [
+            (
+                Provider::OpenAI,
+                Model::GPT4o,
+                json!({ "choices": [], "usage": { "prompt_tokens": 5, "completion_tokens": 0 } }),
+            ),
+            (
+                Provider::Mistral,
+                Model::Codestral,
+                json!({ "choices": [], "usage": { "prompt_tokens": 5, "completion_tokens": 0 } }),
+            ),
+            (
+                Provider::Anthropic,
+                Model::Claude3_5Sonnet,
+                json!({
+                    "role": "assistant",
+                    "content": [],
+                    "usage": { "input_tokens": 5, "output_tokens": 0 },
+                    "stop_reason": "end_turn"
+                }),
+            ),
+            (
+                Provider::Google,
+                Model::GeminiPro,
+                json!({ "candidates": [] }),
+            ),
+        ] {
+            let _server = MockServer::start(vec![MockResponse::json(200, &empty)]).await;
+            let mut client = client(provider, model).stream(false);
+            let history = client.get_message_history().len();
+
+            let error = client.send_message(user("Hi")).await.unwrap_err();
+
+            assert!(
+                error.to_string().contains("no candidates returned"),
+                "{provider:?}: {error}"
+            );
+            assert_eq!(client.get_message_history().len(), history, "{provider:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_returned_thinking_as_reasoning() {
+        let _server = MockServer::start(vec![MockResponse::json(
+            200,
+            &json!({
+                "role": "assistant",
+                "content": [
+                    { "type": "thinking", "thinking": "Check the edge cases." },
+                    { "type": "text", "text": "Done." }
+                ],
+                "usage": { "input_tokens": 10, "output_tokens": 5 }
+            }),
+        )])
+        .await;
+        let mut client = client(Provider::Anthropic, Model::Claude3_5Sonnet)
+            .reasoning_effort(Some(ReasoningEffort::Low));
+
+        let answer = client.send_message(user("Hi")).await.unwrap().unwrap();
+
+        assert_eq!(answer.content, "Done.");
+        assert_eq!(answer.reasoning.as_deref(), Some("Check the edge cases."));
+    }
+
+    #[test]
+    fn joins_anthropic_beta_flags_into_one_header() {
+        let anthropic = client(Provider::Anthropic, Model::Claude3_5Sonnet).anthropic_beta([
+            "context-1m-2025-08-07".to_string(),
+            "token-efficient-tools-2025-02-19".to_string(),
+        ]);
+        let (_, request) = build(&anthropic, false);
+        assert_eq!(
+            header(&request, "anthropic-beta"),
+            Some("context-1m-2025-08-07,token-efficient-tools-2025-02-19")
+        );
+
+        let (_, request) = build(&client(Provider::Anthropic, Model::Claude3_5Sonnet), false);
+        assert_eq!(header(&request, "anthropic-beta"), None);
+    }
+
+    #[tokio::test]
+    async fn saves_the_settings_of_each_assistant_turn() {
+        let answer = |content: &str| {
+            MockResponse::json(
+                200,
+                &json!({
+                    "choices": [{ "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }],
+                    "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+                }),
+            )
+        };
+        let server = MockServer::start(vec![answer("One."), answer("Two.")]).await;
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let mut client = ChatCompletionClient::new(Provider::OpenAI, Model::GPT4o, "Be brief.")
+            .temperature(Some(0.25))
+            .top_p(Some(0.5))
+            .max_tokens(Some(MaxTokens::Fixed(500)));
+
+        client.send_message(user("Count.")).await.unwrap();
+        client.send_message(user("Again.")).await.unwrap();
+
+        let saved = serde_json::to_value(client.get_message_history()).unwrap();
+        let turns: Vec<&Value> = saved
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|msg| msg["role"] == "assistant")
+            .collect();
+        assert_eq!(turns.len(), 2);
+        for turn in turns {
+            assert_eq!(
+                turn["metadata"],
+                json!({ "model": "gpt-4o", "temperature": 0.25, "top_p": 0.5, "max_tokens": 500 })
+            );
+        }
+        assert!(server.requests()[1].body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|msg| msg.get("metadata").is_none()));
+    }
+
+    #[tokio::test]
+    async fn posts_the_raw_body_with_the_provider_auth() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, &json!({ "id": "msg_1" })),
+            MockResponse::json(400, &json!({ "error": "bad model" })),
+        ])
+        .await;
+        std::env::set_var(Provider::Anthropic.key_var(), "test-key");
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let body = r#"{ "model":"claude-3-5-sonnet-20240620",  "max_tokens": 8, "messages": [] }"#;
+
+        let (status, response) = send_raw(
+            Provider::Anthropic,
+            "",
+            body.to_string(),
+            &["beta-1".to_string()],
+        )
+        .await
+        .unwrap();
+        let (error_status, _) = send_raw(Provider::OpenAI, "", "{}".to_string(), &[])
+            .await
+            .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response, r#"{"id":"msg_1"}"#);
+        assert_eq!(error_status, StatusCode::BAD_REQUEST);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].raw_body, body);
+        assert_eq!(requests[0].headers["x-api-key"], "test-key");
+        assert_eq!(requests[0].headers["anthropic-version"], "2023-06-01");
+        assert_eq!(requests[0].headers["anthropic-beta"], "beta-1");
+        assert_eq!(requests[1].headers["authorization"], "Bearer test-key");
+        assert!(!requests[1].headers.contains_key("x-api-key"));
+    }
 }
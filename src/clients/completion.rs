@@ -1,38 +1,59 @@
-use crate::{clients::mistral::Response as MistralResponse, models::IntoMessage};
-use core::panic;
-use std::{env, error::Error};
+use crate::{
+    clients::mistral::Response as MistralResponse,
+    models::{tokenizer_for, IntoMessage, IntoUsage, Usage},
+};
+use std::error::Error;
 
-use reqwest::Client;
+use log::{debug, warn};
 use serde_json::{json, Value};
+use uuid::Uuid;
 
 use crate::models::{Message, Role};
 
-use super::providers::{Model, Provider};
+use super::{
+    api_key, extract_text,
+    open_ai::StreamChunk as OpenAIStreamChunk,
+    providers::{MaxTokens, Model, Provider, DEFAULT_MAX_TOKENS},
+    ChatCompletionClient, RequestError,
+};
 
+/// The system prompt used to emulate fill-in-the-middle over a chat endpoint.
+const CHAT_FIM_PROMPT: &str = "You fill in missing code. The user sends the code before and after a gap. Respond with only the code that belongs in the gap, without explanations or Markdown code fences.";
+
+/// The shortest suffix line that stops a stream as soon as it is repeated.
+/// Shorter lines, such as a lone `}`, also turn up in code the model has yet
+/// to close, so they are only trimmed once the answer is complete.
+const MIN_REPEATED_LINE_CHARS: usize = 8;
+
+/// Sends fill-in-the-middle requests.
+///
+/// Mistral models use the dedicated FIM endpoint, which takes the prefix and
+/// suffix as separate fields. Other providers are asked over their chat endpoint.
 #[allow(clippy::module_name_repetitions)]
 pub struct CompletionClient {
     provider: Provider,
     model: Model,
     token: String,
     temperature: Option<f32>,
-    max_tokens: Option<u32>,
+    max_tokens: MaxTokens,
     prompt: String,
     suffix: String,
     messages: Vec<Message>,
+    usage: Usage,
+    first_line: bool,
 }
 
 impl CompletionClient {
     pub fn new(provider: Provider, model: Model) -> Self {
+        // Other providers authenticate through the chat client.
         let token = match provider {
-            Provider::Mistral => env::var("MISTRAL_API_KEY"),
-            _ => todo!(),
-        }
-        .unwrap_or_else(|_error| panic!("Error: Environment variable not set."));
-
-        let msgs: Vec<Message> = if matches!(provider, Provider::Mistral) {
-            vec![]
-        } else {
-            panic!()
+            Provider::Mistral => api_key(provider).unwrap_or_else(|| {
+                panic!(
+                    "Error: {} not set and no key stored with `config set-key`.",
+                    provider.key_var()
+                )
+            }),
+            _ => String::new(),
         };
 
         Self {
@@ -40,10 +61,12 @@ impl CompletionClient {
             model,
             token,
             temperature: Some(0.0),
-            max_tokens: Some(1028),
+            max_tokens: DEFAULT_MAX_TOKENS,
             prompt: String::new(),
             suffix: String::new(),
-            messages: msgs,
+            messages: vec![],
+            usage: Usage::default(),
+            first_line: false,
         }
     }
 
@@ -54,13 +77,25 @@ impl CompletionClient {
         self
     }
 
-    pub const fn max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+    pub const fn max_tokens(mut self, max_tokens: Option<MaxTokens>) -> Self {
         if let Some(max_tokens) = max_tokens {
-            self.max_tokens = Some(max_tokens);
+            self.max_tokens = max_tokens;
         }
         self
     }
 
+    /// Keeps only the first non-empty line of the infill, streaming it where
+    /// the provider allows so the rest of the answer is never waited for.
+    pub const fn first_line(mut self, first_line: bool) -> Self {
+        self.first_line = first_line;
+        self
+    }
+
+    /// Counts the tokens in `text` with the model's tokenizer.
+    fn tokens(&self, text: &str) -> u32 {
+        tokenizer_for(&self.model).count(text)
+    }
+
     pub async fn send_message(
         &mut self,
         message: &str,
@@ -69,6 +104,10 @@ impl CompletionClient {
         self.messages.push(Message {
             role: Role::User,
             content: message.to_string(),
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
         });
 
         message.clone_into(&mut self.prompt);
@@ -76,69 +115,418 @@ impl CompletionClient {
             self.suffix.clone_from(sfx);
         }
 
-        let prompt = if matches!(&self.provider, Provider::Mistral) {
-            let mut json_map = serde_json::Map::new();
-            json_map.insert("model".to_string(), json!(self.model));
-            json_map.insert("temperature".to_string(), json!(self.temperature));
-            json_map.insert("max_tokens".to_string(), json!(self.max_tokens));
-            json_map.insert("prompt".to_string(), json!(self.prompt));
-            json_map.insert("suffix".to_string(), json!(self.suffix));
-            json!(json_map)
-        } else {
-            panic!()
+        self.max_tokens.validate(&self.model)?;
+
+        let message = match (self.provider, self.first_line) {
+            (Provider::Mistral, true) => self.send_fim_first_line().await?,
+            (Provider::Mistral, false) => self.send_fim().await?,
+            (_, first_line) => self.send_chat().await?.map(|mut msg| {
+                if let Some(end) = first_line_end(&msg.content).filter(|_| first_line) {
+                    msg.content.truncate(end);
+                }
+                msg
+            }),
         };
 
-        let request_url = if matches!(&self.provider, Provider::Mistral) {
-            "https://codestral.mistral.ai/v1/fim/completions"
-        } else {
-            panic!()
+        let message = message.and_then(|mut msg| {
+            if let Some(end) = suffix_overlap(&msg.content, &self.suffix) {
+                debug!("infill repeats the suffix, truncating at byte {end}");
+                msg.content.truncate(end);
+            }
+            (!msg.content.trim().is_empty()).then_some(msg)
+        });
+
+        if let Some(msg) = message.clone() {
+            self.messages.push(msg);
+        }
+
+        Ok(message)
+    }
+
+    /// Sends the prefix and suffix to Mistral's FIM endpoint.
+    async fn send_fim(&mut self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        let (request_id, response) = self.fim_request(false).await?;
+
+        let value = response.json::<Value>().await?;
+        let message = match serde_json::from_value::<MistralResponse>(value.clone()) {
+            Ok(mistral_response) => {
+                if let Some(usage) = mistral_response.usage() {
+                    self.usage += usage;
+                }
+                mistral_response.into_message()
+            }
+            Err(e) => {
+                let content = extract_text(&value).ok_or_else(|| {
+                    format!(
+                        "Failed to decode {} response (request {request_id}): {e}",
+                        self.model
+                    )
+                })?;
+
+                warn!(
+                    "[{request_id}] {} response did not match the expected schema ({e}), using best-effort text",
+                    self.model
+                );
+
+                self.usage += Usage {
+                    input_tokens: self.tokens(&self.prompt),
+                    output_tokens: self.tokens(&content),
+                };
+
+                Some(Message {
+                    role: Role::Assistant,
+                    content,
+                    reasoning: None,
+                    tool_call_id: None,
+                    finish_reason: None,
+                    metadata: None,
+                })
+            }
         };
 
-        let req_base = Client::new()
-            .post(request_url)
-            .json(&prompt)
-            .header("content-type", "application/json");
+        Ok(message)
+    }
 
-        let req = if matches!(&self.provider, Provider::Mistral) {
-            req_base.bearer_auth(self.token.to_string())
-        } else {
-            panic!()
+    /// Streams the infill from Mistral's FIM endpoint, dropping the response as
+    /// soon as its first line is complete.
+    async fn send_fim_first_line(
+        &mut self,
+    ) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        let (request_id, mut response) = self.fim_request(true).await?;
+
+        let mut content = String::new();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        'stream: while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+
+                match serde_json::from_str::<OpenAIStreamChunk>(data) {
+                    Ok(stream_chunk) => {
+                        if let Some(delta) = stream_chunk.into_delta() {
+                            content.push_str(&delta);
+                        }
+                    }
+                    Err(e) => debug!("[{request_id}] skipping unparsable chunk: {e}"),
+                }
+
+                if let Some(end) = repeated_suffix_line(&content, &self.suffix) {
+                    debug!("[{request_id}] infill repeats the suffix, dropping the rest");
+                    content.truncate(end);
+                    break 'stream;
+                }
+
+                if let Some(end) = first_line_end(&content) {
+                    debug!("[{request_id}] first line received, dropping the rest");
+                    content.truncate(end);
+                    break 'stream;
+                }
+            }
+        }
+
+        self.usage += Usage {
+            input_tokens: self.tokens(&self.prompt) + self.tokens(&self.suffix),
+            output_tokens: self.tokens(&content),
         };
 
-        let response = req.send().await?;
+        if content.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Message {
+            role: Role::Assistant,
+            content,
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        }))
+    }
+
+    /// Sends a FIM request, returning the request id and the successful response.
+    async fn fim_request(
+        &self,
+        stream: bool,
+    ) -> Result<(Uuid, reqwest::Response), Box<dyn Error + Send + Sync>> {
+        let max_tokens = self.max_tokens.resolve(
+            &self.model,
+            self.tokens(&self.prompt) + self.tokens(&self.suffix),
+        );
+
+        let mut json_map = serde_json::Map::new();
+        json_map.insert("model".to_string(), json!(self.model));
+        json_map.insert("temperature".to_string(), json!(self.temperature));
+        json_map.insert("max_tokens".to_string(), json!(max_tokens));
+        json_map.insert("prompt".to_string(), json!(self.prompt));
+        json_map.insert("suffix".to_string(), json!(self.suffix));
+        json_map.insert("stream".to_string(), json!(stream));
+        let prompt = json!(json_map);
+
+        let request_id = Uuid::new_v4();
+
+        let req = super::http_client()
+            .post(fim_endpoint())
+            .json(&prompt)
+            .header("content-type", "application/json")
+            .header("x-client-request-id", request_id.to_string())
+            .bearer_auth(self.token.to_string());
+
+        debug!("[{request_id}] sending request to {}", self.model);
+
+        let response = req
+            .send()
+            .await
+            .map_err(|e| RequestError::network(self.model.to_string(), request_id, &e))?;
+
+        debug!(
+            "[{request_id}] {} responded with {}",
+            self.model,
+            response.status()
+        );
 
         if response.status().is_success() {
-            let message = if matches!(&self.provider, Provider::Mistral) {
-                let anth_response = response.json::<MistralResponse>().await?;
-                anth_response.into_message()
-            } else {
-                panic!()
-            };
-
-            if let Some(msg) = message.clone() {
-                self.messages.push(msg);
-            }
+            return Ok((request_id, response));
+        }
 
-            Ok(message)
-        } else {
-            match response.json::<Value>().await {
-                Ok(resp_json) => match serde_json::to_string_pretty(&resp_json) {
-                    Ok(resp_formatted) => {
-                        Err(format!("{}\n\n{}", self.model, resp_formatted).into())
-                    }
-                    Err(e) => Err(format!("Failed to format response JSON: {e}").into()),
-                },
-                Err(e) => Err(format!("Failed to parse response JSON: {e}").into()),
+        match response.json::<Value>().await {
+            Ok(resp_json) => match serde_json::to_string_pretty(&resp_json) {
+                Ok(resp_formatted) => {
+                    Err(format!("{} (request {request_id})\n\n{resp_formatted}", self.model).into())
+                }
+                Err(e) => Err(format!("Failed to format response JSON: {e}").into()),
+            },
+            Err(e) => {
+                Err(format!("Failed to parse response JSON (request {request_id}): {e}").into())
             }
         }
     }
 
+    /// Asks a chat model for the code between the prefix and the suffix.
+    async fn send_chat(&mut self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        let mut client =
+            ChatCompletionClient::new(self.provider, self.model.clone(), CHAT_FIM_PROMPT)
+                .temperature(self.temperature)
+                .max_tokens(Some(self.max_tokens));
+
+        let message = client
+            .send_message(Message {
+                role: Role::User,
+                content: format!(
+                    "<prefix>\n{}\n</prefix>\n<suffix>\n{}\n</suffix>",
+                    self.prompt, self.suffix
+                ),
+                reasoning: None,
+                tool_call_id: None,
+                finish_reason: None,
+                metadata: None,
+            })
+            .await?;
+
+        self.usage += client.get_usage();
+
+        Ok(message)
+    }
+
+    /// Returns the token usage accumulated over every request sent by this client.
+    pub const fn get_usage(&self) -> Usage {
+        self.usage
+    }
+
     pub fn get_message_history(&self) -> Vec<Message> {
-        let msgs = self.messages.clone();
-        if matches!(self.provider, Provider::Mistral) {
-            msgs
-        } else {
-            panic!()
+        self.messages.clone()
+    }
+}
+
+/// Returns Mistral's FIM endpoint.
+fn fim_endpoint() -> String {
+    #[cfg(test)]
+    if let Some(url) = super::mock_server::endpoint() {
+        return url;
+    }
+
+    "https://codestral.mistral.ai/v1/fim/completions".to_string()
+}
+
+/// Returns where the first line with any non-whitespace text ends, if it has ended.
+fn first_line_end(text: &str) -> Option<usize> {
+    let mut start = 0;
+    while let Some(offset) = text[start..].find('\n') {
+        let end = start + offset;
+        if !text[..end].trim().is_empty() {
+            return Some(end);
+        }
+        start = end + 1;
+    }
+    None
+}
+
+/// Returns where a line of the text repeats the first line of the suffix, if
+/// that line is long enough to be a reliable sign of duplication.
+fn repeated_suffix_line(text: &str, suffix: &str) -> Option<usize> {
+    let anchor = suffix
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?;
+    if anchor.chars().count() < MIN_REPEATED_LINE_CHARS {
+        return None;
+    }
+
+    let mut start = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim() == anchor {
+            return Some(start);
         }
+        start += line.len();
+    }
+    None
+}
+
+/// Returns where the text starts reproducing the suffix, if it does.
+///
+/// The text overlaps when one of its lines repeats the first line of the
+/// suffix, or when everything from the start of a line to the end of the text
+/// is the beginning of the suffix.
+fn suffix_overlap(text: &str, suffix: &str) -> Option<usize> {
+    let suffix = suffix.trim_start();
+    if suffix.is_empty() {
+        return None;
+    }
+
+    let mut start = 0;
+    let tail = text.split_inclusive('\n').find_map(|line| {
+        let rest = text[start..].trim();
+        let overlaps = !rest.is_empty() && suffix.starts_with(rest);
+        let at = start;
+        start += line.len();
+        overlaps.then_some(at)
+    });
+
+    match (repeated_suffix_line(text, suffix), tail) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            mock_server::{self, MockResponse, MockServer},
+            RequestErrorKind,
+        },
+        *,
+    };
+
+    fn mistral_client() -> CompletionClient {
+        std::env::set_var(Provider::Mistral.key_var(), "test-key");
+        CompletionClient::new(Provider::Mistral, Model::Codestral)
+    }
+
+    fn fim_answer(content: &str) -> MockResponse {
+        MockResponse::json(
+            200,
+            &json!({
+                "id": "fim-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "codestral-latest",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": "stop"
+                }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12 }
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_an_unresolvable_host_as_a_network_error() {
+        mock_server::redirect("http://api.acai.invalid/v1/fim/completions");
+        let mut client = mistral_client();
+
+        let error = client.send_message("fn main() {", None).await.unwrap_err();
+
+        let error = error.downcast::<RequestError>().unwrap();
+        assert_eq!(error.kind, RequestErrorKind::Network);
+        assert!(
+            error.details.starts_with("cannot reach api.acai.invalid"),
+            "{}",
+            error.details
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_the_prefix_and_suffix_as_separate_fields() {
+        let server = MockServer::start(vec![fim_answer("a + b")]).await;
+        let mut client = mistral_client();
+
+        let message = client
+            .send_message(
+                "fn add(a: i32, b: i32) -> i32 {\n    ",
+                Some("\n}".to_string()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        let body = &server.requests()[0].body;
+        assert_eq!(body["prompt"], "fn add(a: i32, b: i32) -> i32 {\n    ");
+        assert_eq!(body["suffix"], "\n}");
+        assert!(body.get("messages").is_none());
+        assert_eq!(message.content, "a + b");
+    }
+
+    #[tokio::test]
+    async fn returns_the_first_line_before_the_stream_ends() {
+        let delta = |text: &str| json!({ "choices": [{ "delta": { "content": text } }] });
+        let server = MockServer::start(vec![MockResponse::unfinished_stream(&[
+            delta("let sum"),
+            delta(" = a + b;\n    sum"),
+        ])])
+        .await;
+        let mut client = mistral_client().first_line(true);
+
+        let message = client
+            .send_message(
+                "fn add(a: i32, b: i32) -> i32 {\n    ",
+                Some("\n}".to_string()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(message.content, "let sum = a + b;");
+        assert_eq!(server.requests()[0].body["stream"], true);
+    }
+
+    #[test]
+    fn finds_the_end_of_the_first_non_empty_line() {
+        assert_eq!(first_line_end("\n  \nlet x = 1;\nlet y"), Some(14));
+        assert_eq!(first_line_end("let x = 1;"), None);
+    }
+
+    #[test]
+    fn truncates_at_the_start_of_the_overlap_with_the_suffix() {
+        let suffix = "    return total;\n}\n";
+
+        assert_eq!(
+            suffix_overlap("total += x;\n    return total;\n}", suffix),
+            Some(12)
+        );
+        assert_eq!(suffix_overlap("total += x;\n    ret", suffix), Some(12));
+        assert_eq!(suffix_overlap("total += x;", suffix), None);
+        assert_eq!(suffix_overlap("total += x;", ""), None);
     }
 }
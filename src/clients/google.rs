@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::models::{IntoMessage, Message, Role};
+use crate::models::{IntoMessage, IntoUsage, Message, Role, Usage};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Part {
@@ -23,7 +24,7 @@ impl From<&Message> for Instruction {
         let role = match value.role {
             crate::models::Role::System => "system".to_string(),
             crate::models::Role::Assistant => "assistant".to_string(),
-            crate::models::Role::User => "user".to_string(),
+            crate::models::Role::User | crate::models::Role::Tool => "user".to_string(),
         };
 
         Self {
@@ -39,6 +40,40 @@ impl From<&Message> for Instruction {
 pub struct Request {
     pub system_instruction: SystemInstruction,
     pub contents: Vec<Instruction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety_settings: Option<Vec<SafetySetting>>,
+}
+
+/// The harm categories that can be configured in `safetySettings`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum HarmCategory {
+    #[serde(rename = "HARM_CATEGORY_HARASSMENT")]
+    Harassment,
+    #[serde(rename = "HARM_CATEGORY_HATE_SPEECH")]
+    HateSpeech,
+    #[serde(rename = "HARM_CATEGORY_SEXUALLY_EXPLICIT")]
+    SexuallyExplicit,
+    #[serde(rename = "HARM_CATEGORY_DANGEROUS_CONTENT")]
+    DangerousContent,
+}
+
+/// The probability at or above which content in a category is blocked.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum HarmBlockThreshold {
+    #[serde(rename = "BLOCK_NONE")]
+    None,
+    #[serde(rename = "BLOCK_ONLY_HIGH")]
+    OnlyHigh,
+    #[serde(rename = "BLOCK_MEDIUM_AND_ABOVE")]
+    MediumAndAbove,
+    #[serde(rename = "BLOCK_LOW_AND_ABOVE")]
+    LowAndAbove,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SafetySetting {
+    pub category: HarmCategory,
+    pub threshold: HarmBlockThreshold,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -47,20 +82,174 @@ pub struct Content {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    pub content: Option<Content>,
+    pub finish_reason: Option<String>,
+    #[serde(default)]
+    pub safety_ratings: Vec<SafetyRating>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptFeedback {
+    pub block_reason: Option<String>,
+    #[serde(default)]
+    pub safety_ratings: Vec<SafetyRating>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SafetyRating {
+    pub category: String,
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Response {
-    pub contents: Vec<Content>,
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    pub prompt_feedback: Option<PromptFeedback>,
+    pub usage_metadata: Option<UsageMetadata>,
 }
 
-impl IntoMessage for Response {
-    fn into_message(self) -> Option<Message> {
-        if let Some(content) = self.contents.first() {
-            if let Some(part) = content.parts.first() {
-                return Some(Message {
-                    role: Role::Assistant,
-                    content: part.text.clone(),
-                });
+/// Returned when Gemini refuses to answer because of its safety filters.
+#[derive(Error, Debug)]
+#[error("response blocked: {category}")]
+pub struct Blocked {
+    pub category: String,
+}
+
+impl Response {
+    /// Returns why the prompt or the response was blocked, if it was.
+    pub fn blocked(&self) -> Option<Blocked> {
+        let blocked_category = |ratings: &[SafetyRating], reason: &str| {
+            let category = ratings
+                .iter()
+                .find(|rating| rating.blocked)
+                .map_or_else(|| reason.to_string(), |rating| rating.category.clone());
+            Blocked { category }
+        };
+
+        if let Some(feedback) = &self.prompt_feedback {
+            if let Some(reason) = &feedback.block_reason {
+                return Some(blocked_category(&feedback.safety_ratings, reason));
             }
         }
-        None
+
+        self.candidates
+            .iter()
+            .find(|candidate| candidate.finish_reason.as_deref() == Some("SAFETY"))
+            .map(|candidate| blocked_category(&candidate.safety_ratings, "SAFETY"))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u32,
+    #[serde(default)]
+    pub candidates_token_count: u32,
+}
+
+impl IntoMessage for Response {
+    fn into_message(self) -> Option<Message> {
+        self.candidates
+            .into_iter()
+            .next()
+            .and_then(Candidate::into_message)
+    }
+
+    fn into_messages(self) -> Vec<Message> {
+        self.candidates
+            .into_iter()
+            .filter_map(Candidate::into_message)
+            .collect()
+    }
+}
+
+impl Candidate {
+    fn into_message(self) -> Option<Message> {
+        let part = self.content?.parts.into_iter().next()?;
+
+        Some(Message {
+            role: Role::Assistant,
+            content: part.text,
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: self.finish_reason,
+            metadata: None,
+        })
+    }
+}
+
+impl IntoUsage for Response {
+    fn usage(&self) -> Option<Usage> {
+        self.usage_metadata.as_ref().map(|usage| Usage {
+            input_tokens: usage.prompt_token_count,
+            output_tokens: usage.candidates_token_count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(json: &str) -> Response {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn reports_a_blocked_prompt() {
+        let response = response(
+            r#"{
+                "promptFeedback": {
+                    "blockReason": "SAFETY",
+                    "safetyRatings": [
+                        {"category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE"},
+                        {"category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "HIGH", "blocked": true}
+                    ]
+                }
+            }"#,
+        );
+
+        let blocked = response.blocked().unwrap();
+        assert_eq!(
+            blocked.to_string(),
+            "response blocked: HARM_CATEGORY_DANGEROUS_CONTENT"
+        );
+        assert!(response.into_message().is_none());
+    }
+
+    #[test]
+    fn reports_a_blocked_candidate() {
+        let response =
+            response(r#"{"candidates": [{"finishReason": "SAFETY", "safetyRatings": []}]}"#);
+
+        assert_eq!(response.blocked().unwrap().category, "SAFETY");
+    }
+
+    #[test]
+    fn answers_are_not_blocked() {
+        let response = response(
+            r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}]}, "finishReason": "STOP"}]}"#,
+        );
+
+        assert!(response.blocked().is_none());
+        assert_eq!(response.into_message().unwrap().content, "Hi");
+    }
+
+    #[test]
+    fn keeps_the_finish_reason() {
+        let response = response(
+            r#"{"candidates": [{"content": {"parts": [{"text": "fn main() {"}]}, "finishReason": "MAX_TOKENS"}]}"#,
+        );
+
+        let message = response.into_message().unwrap();
+        assert_eq!(message.finish_reason.as_deref(), Some("MAX_TOKENS"));
+        assert!(message.is_truncated());
     }
 }
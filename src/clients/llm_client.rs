@@ -0,0 +1,200 @@
+use std::{error::Error, future::Future};
+
+use crate::models::{Message, Usage};
+
+use super::{ChatCompletionClient, CompletionClient};
+
+/// A chat or completion client behind one interface, so callers can send a
+/// message without caring which endpoint answers it.
+pub enum LLMClient {
+    Chat(ChatCompletionClient),
+    Completion(CompletionClient),
+}
+
+impl LLMClient {
+    /// Sends a message and returns the model's answer.
+    ///
+    /// Completion clients use the message content as the prompt, with no suffix.
+    pub async fn send_message(
+        &mut self,
+        message: Message,
+    ) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        match self {
+            Self::Chat(client) => client.send_message(message).await,
+            Self::Completion(client) => client.send_message(&message.content, None).await,
+        }
+    }
+
+    /// Sends a message like [`Self::send_message`], calling `on_delta` with
+    /// the text of the answer as it arrives, until `cancel` completes.
+    ///
+    /// Completion clients answer in one piece, so `on_delta` is called once.
+    pub async fn send_message_streaming<F, C>(
+        &mut self,
+        message: Message,
+        mut on_delta: F,
+        cancel: C,
+    ) -> Result<Option<Message>, Box<dyn Error + Send + Sync>>
+    where
+        F: FnMut(&str),
+        C: Future<Output = ()>,
+    {
+        match self {
+            Self::Chat(client) => {
+                client
+                    .send_message_streaming(message, on_delta, cancel)
+                    .await
+            }
+            Self::Completion(client) => {
+                let response = tokio::select! {
+                    response = client.send_message(&message.content, None) => response?,
+                    () = cancel => return Ok(None),
+                };
+                if let Some(msg) = &response {
+                    on_delta(&msg.content);
+                }
+                Ok(response)
+            }
+        }
+    }
+
+    /// Adds a system instruction for the rest of the conversation, returning
+    /// `false` for completion clients, which have no system prompt.
+    pub fn add_system_message(&mut self, content: &str) -> bool {
+        match self {
+            Self::Chat(client) => {
+                client.add_system_message(content);
+                true
+            }
+            Self::Completion(_) => false,
+        }
+    }
+
+    pub fn get_message_history(&self) -> Vec<Message> {
+        match self {
+            Self::Chat(client) => client.get_message_history(),
+            Self::Completion(client) => client.get_message_history(),
+        }
+    }
+
+    /// Returns the token usage accumulated over every request sent by this client.
+    pub const fn get_usage(&self) -> Usage {
+        match self {
+            Self::Chat(client) => client.get_usage(),
+            Self::Completion(client) => client.get_usage(),
+        }
+    }
+}
+
+impl From<ChatCompletionClient> for LLMClient {
+    fn from(client: ChatCompletionClient) -> Self {
+        Self::Chat(client)
+    }
+}
+
+impl From<CompletionClient> for LLMClient {
+    fn from(client: CompletionClient) -> Self {
+        Self::Completion(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        clients::{
+            mock_server::{MockResponse, MockServer},
+            providers::{Model, Provider},
+        },
+        models::Role,
+    };
+
+    fn user(content: &str) -> Message {
+        Message {
+            role: Role::User,
+            content: content.to_string(),
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        }
+    }
+
+    fn answer(content: &str) -> Value {
+        json!({
+            "choices": [{ "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 2 }
+        })
+    }
+
+    fn clients() -> [LLMClient; 2] {
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        std::env::set_var(Provider::Mistral.key_var(), "test-key");
+        [
+            ChatCompletionClient::new(Provider::OpenAI, Model::GPT4o, "Be brief.")
+                .stream(false)
+                .into(),
+            CompletionClient::new(Provider::Mistral, Model::Codestral).into(),
+        ]
+    }
+
+    #[tokio::test]
+    async fn sends_through_either_client_and_returns_its_history() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, &answer("Hi.")),
+            MockResponse::json(200, &answer("a + b")),
+        ])
+        .await;
+
+        for (mut client, expected) in clients().into_iter().zip(["Hi.", "a + b"]) {
+            let response = client.send_message(user("fn add")).await.unwrap().unwrap();
+            assert_eq!(response.content, expected);
+
+            let history = client.get_message_history();
+            let turns: Vec<&str> = history
+                .iter()
+                .filter(|msg| !matches!(msg.role, Role::System))
+                .map(|msg| msg.content.as_str())
+                .collect();
+            assert_eq!(turns, ["fn add", expected]);
+            assert_eq!(client.get_usage().output_tokens, 2);
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].body["messages"][1]["content"], "fn add");
+        assert_eq!(requests[1].body["prompt"], "fn add");
+    }
+
+    #[tokio::test]
+    async fn streams_either_client_and_takes_system_messages_only_for_chat() {
+        let _server = MockServer::start(vec![
+            MockResponse::stream(&[json!({
+                "choices": [{ "delta": { "content": "Hi." }, "finish_reason": "stop" }]
+            })]),
+            MockResponse::json(200, &answer("a + b")),
+        ])
+        .await;
+
+        let [mut chat, mut completion] = clients();
+        assert!(chat.add_system_message("Answer in French."));
+        assert!(!completion.add_system_message("Answer in French."));
+
+        for (client, expected) in [(&mut chat, "Hi."), (&mut completion, "a + b")] {
+            let mut streamed = String::new();
+            let response = client
+                .send_message_streaming(
+                    user("fn add"),
+                    |delta| streamed.push_str(delta),
+                    std::future::pending(),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(response.content, expected);
+            assert_eq!(streamed, expected);
+        }
+    }
+}
@@ -1,23 +1,68 @@
 use serde::{Deserialize, Serialize};
 
-use crate::models::{IntoMessage, Message};
+use crate::models::{IntoMessage, IntoUsage, Message, Usage};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
+    #[serde(default)]
     pub choices: Vec<Choice>,
+    pub usage: Option<ResponseUsage>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Choice {
     pub message: Message,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+impl Choice {
+    fn into_message(self) -> Message {
+        Message {
+            finish_reason: self.finish_reason,
+            ..self.message
+        }
+    }
 }
 
 impl IntoMessage for Response {
     fn into_message(self) -> Option<Message> {
-        if let Some(choice) = self.choices.first() {
-            let msg = choice.message.clone();
-            return Some(msg);
-        }
-        None
+        self.choices.into_iter().next().map(Choice::into_message)
+    }
+
+    fn into_messages(self) -> Vec<Message> {
+        self.choices.into_iter().map(Choice::into_message).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl IntoUsage for Response {
+    fn usage(&self) -> Option<Usage> {
+        self.usage.as_ref().map(|usage| Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_finish_reason() {
+        let response: Response = serde_json::from_str(
+            r#"{"choices": [{"message": {"role": "assistant", "content": "Hi"}, "finish_reason": "stop"}]}"#,
+        )
+        .unwrap();
+
+        let message = response.into_message().unwrap();
+        assert_eq!(message.finish_reason.as_deref(), Some("stop"));
+        assert!(!message.is_truncated());
     }
 }
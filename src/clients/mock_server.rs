@@ -0,0 +1,193 @@
+//! A local HTTP server standing in for the providers in tests.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+thread_local! {
+    static ENDPOINT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Returns the URL of the mock server started on this thread, if any, which
+/// replaces every provider endpoint.
+pub fn endpoint() -> Option<String> {
+    ENDPOINT.with(|endpoint| endpoint.borrow().clone())
+}
+
+/// Sends every provider request made from this thread to `url`, e.g. one
+/// that cannot be reached, without starting a server.
+pub fn redirect(url: &str) {
+    ENDPOINT.with(|endpoint| *endpoint.borrow_mut() = Some(url.to_string()));
+}
+
+/// A canned answer to one request.
+pub struct MockResponse {
+    status: u16,
+    content_type: &'static str,
+    chunks: Vec<String>,
+    /// Keeps the connection open after the last chunk instead of ending the body.
+    hang: bool,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: &Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            chunks: vec![body.to_string()],
+            hang: false,
+        }
+    }
+
+    /// A server-sent events stream of `events`, ended with `[DONE]`.
+    pub fn stream(events: &[Value]) -> Self {
+        let mut chunks: Vec<String> = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .collect();
+        chunks.push("data: [DONE]\n\n".to_string());
+
+        Self {
+            status: 200,
+            content_type: "text/event-stream",
+            chunks,
+            hang: false,
+        }
+    }
+
+    /// A stream of `events` that never ends, as if the model were still writing.
+    pub fn unfinished_stream(events: &[Value]) -> Self {
+        let mut response = Self::stream(events);
+        response.chunks.pop();
+        response.hang = true;
+        response
+    }
+}
+
+/// A request received by the mock server.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    /// The header values by lowercase name.
+    pub headers: HashMap<String, String>,
+    /// The body exactly as sent.
+    pub raw_body: String,
+    pub body: Value,
+}
+
+/// Answers requests with canned responses in order, recording each request.
+///
+/// Requests made from the thread that started the server, i.e. from a
+/// `#[tokio::test]`, are sent to it until it is dropped.
+pub struct MockServer {
+    requests: Arc<Mutex<Vec<Recorded>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start(responses: Vec<MockResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+
+        let task = tokio::spawn({
+            let requests = Arc::clone(&requests);
+            async move {
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        return;
+                    };
+                    let requests = Arc::clone(&requests);
+                    let responses = Arc::clone(&responses);
+                    tokio::spawn(async move {
+                        let (reader, mut writer) = stream.into_split();
+                        let Some(request) = read_request(BufReader::new(reader)).await else {
+                            return;
+                        };
+                        requests.lock().unwrap().push(request);
+
+                        let response = responses.lock().unwrap().pop_front().unwrap_or_else(|| {
+                            MockResponse::json(500, &json!({ "error": "no response left" }))
+                        });
+                        write_response(&mut writer, response).await;
+                    });
+                }
+            }
+        });
+
+        ENDPOINT.with(|endpoint| *endpoint.borrow_mut() = Some(url));
+
+        Self { requests, task }
+    }
+
+    /// Returns the requests received so far, in order.
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        ENDPOINT.with(|endpoint| *endpoint.borrow_mut() = None);
+    }
+}
+
+async fn read_request<R: AsyncBufReadExt + Unpin>(mut reader: R) -> Option<Recorded> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    line.split_whitespace().nth(1)?;
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await.ok()?;
+
+    Some(Recorded {
+        headers,
+        raw_body: String::from_utf8_lossy(&body).into_owned(),
+        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+    })
+}
+
+async fn write_response<W: AsyncWriteExt + Unpin>(writer: &mut W, response: MockResponse) {
+    let head = format!(
+        "HTTP/1.1 {} Mock\r\ncontent-type: {}\r\nconnection: close\r\n\r\n",
+        response.status, response.content_type
+    );
+
+    if writer.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+    for chunk in response.chunks {
+        if writer.write_all(chunk.as_bytes()).await.is_err() || writer.flush().await.is_err() {
+            return;
+        }
+    }
+
+    if response.hang {
+        std::future::pending::<()>().await;
+    }
+}
@@ -1,12 +1,136 @@
 mod anthropic;
+mod api_keys;
 mod chat_completion;
 mod completion;
 mod embeddings;
 mod google;
+mod llm_client;
 mod mistral;
+#[cfg(test)]
+pub mod mock_server;
 mod open_ai;
 pub mod providers;
+mod rate_limit;
 
+use std::{error::Error, sync::OnceLock, time::Duration};
+
+use log::warn;
+use reqwest::Client;
+use serde_json::Value;
+
+pub use api_keys::*;
 pub use chat_completion::*;
 pub use completion::*;
+#[allow(unused_imports)]
 pub use embeddings::*;
+pub use llm_client::*;
+
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Limits how long each request may take, including reading the response.
+///
+/// Takes effect only if called before the first request; requests have no
+/// timeout otherwise.
+pub fn set_timeout(timeout: Duration) {
+    if TIMEOUT.set(timeout).is_err() {
+        warn!("The request timeout is already set, ignoring {timeout:?}");
+    }
+}
+
+/// Returns the HTTP client shared by every request in the process.
+///
+/// Reusing one client keeps its connection pool warm for long-lived commands
+/// such as `serve` and `lsp`.
+pub fn http_client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| build_client(TIMEOUT.get().copied()))
+}
+
+/// Builds an HTTP client whose requests time out after `timeout`, if given.
+pub fn build_client(timeout: Option<Duration>) -> Client {
+    let mut builder = Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder
+        .build()
+        .expect("the HTTP client configuration is valid")
+}
+
+/// Describes a request that could not be sent, with a hint at the usual cause.
+///
+/// The URL is left out since some providers pass the API key in the query string.
+pub fn describe_send_error(error: &reqwest::Error) -> String {
+    let host = error
+        .url()
+        .and_then(|url| url.host_str())
+        .unwrap_or("the provider");
+
+    let summary = if error.is_timeout() {
+        format!("timed out waiting for {host}")
+    } else if error.is_connect() {
+        format!("cannot reach {host}; check your network connection and proxy settings")
+    } else {
+        format!("request to {host} failed")
+    };
+
+    let mut causes = Vec::new();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        // Some errors repeat their source in their own message.
+        let text = cause.to_string();
+        if !causes
+            .last()
+            .is_some_and(|last: &String| last.ends_with(&text))
+        {
+            causes.push(text);
+        }
+        source = cause.source();
+    }
+
+    if causes.is_empty() {
+        summary
+    } else {
+        format!("{summary}\n\ncaused by: {}", causes.join(": "))
+    }
+}
+
+/// Pulls the response text out of a body whose schema did not match the typed response.
+///
+/// Tries the locations used by the OpenAI, Anthropic and Google formats in turn.
+pub fn extract_text(value: &Value) -> Option<String> {
+    [
+        "/choices/0/message/content",
+        "/content/0/text",
+        "/candidates/0/content/parts/0/text",
+    ]
+    .iter()
+    .find_map(|pointer| value.pointer(pointer).and_then(Value::as_str))
+    .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn explains_unreachable_hosts() {
+        // Nothing listens on port 1, so the connection is refused.
+        let error = http_client()
+            .get("http://127.0.0.1:1/v1/chat/completions?key=secret")
+            .send()
+            .await
+            .unwrap_err();
+
+        let description = describe_send_error(&error);
+
+        assert!(
+            description.starts_with(
+                "cannot reach 127.0.0.1; check your network connection and proxy settings"
+            ),
+            "{description}"
+        );
+        assert!(description.contains("caused by: "));
+        assert!(!description.contains("secret"));
+    }
+}
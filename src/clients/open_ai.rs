@@ -1,23 +1,172 @@
 use serde::{Deserialize, Serialize};
 
-use crate::models::{IntoMessage, Message};
+use crate::models::{IntoMessage, IntoUsage, Message, Role, Usage};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
+    #[serde(default)]
     pub choices: Vec<Choice>,
+    pub usage: Option<ResponseUsage>,
 }
 
 impl IntoMessage for Response {
     fn into_message(self) -> Option<Message> {
-        if let Some(choice) = self.choices.first() {
-            let msg = choice.message.clone();
-            return Some(msg);
-        }
-        None
+        self.choices.into_iter().next().map(Choice::into_message)
+    }
+
+    fn into_messages(self) -> Vec<Message> {
+        self.choices.into_iter().map(Choice::into_message).collect()
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Choice {
     pub message: Message,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+impl Choice {
+    fn into_message(self) -> Message {
+        Message {
+            finish_reason: self.finish_reason,
+            ..self.message
+        }
+    }
+}
+
+/// A message as sent in a request, without the fields only kept locally.
+#[derive(Serialize, Debug)]
+pub struct RequestMessage<'a> {
+    pub role: Role,
+    pub content: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<&'a str>,
+}
+
+impl<'a> From<&'a Message> for RequestMessage<'a> {
+    fn from(value: &'a Message) -> Self {
+        Self {
+            role: value.role,
+            content: &value.content,
+            tool_call_id: value.tool_call_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl IntoUsage for Response {
+    fn usage(&self) -> Option<Usage> {
+        self.usage.as_ref().map(|usage| Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        })
+    }
+}
+
+/// A chunk of a streamed chat completion.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamChunk {
+    #[serde(default)]
+    pub choices: Vec<StreamChoice>,
+    pub usage: Option<ResponseUsage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamChoice {
+    pub delta: Delta,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Delta {
+    pub content: Option<String>,
+    /// The reasoning streamed by models that think before they answer.
+    #[serde(default, alias = "reasoning_content")]
+    pub reasoning: Option<String>,
+}
+
+impl StreamChunk {
+    /// Returns why the model stopped, sent with the last chunk of the answer.
+    pub fn finish_reason(&self) -> Option<String> {
+        self.choices
+            .first()
+            .and_then(|choice| choice.finish_reason.clone())
+    }
+
+    /// Returns the reasoning added by this chunk.
+    pub fn reasoning(&self) -> Option<&str> {
+        self.choices
+            .first()
+            .and_then(|choice| choice.delta.reasoning.as_deref())
+    }
+
+    /// Returns the text added by this chunk.
+    pub fn into_delta(self) -> Option<String> {
+        self.choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.delta.content)
+    }
+}
+
+impl IntoUsage for StreamChunk {
+    fn usage(&self) -> Option<Usage> {
+        self.usage.as_ref().map(|usage| Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(json: &str) -> StreamChunk {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn captures_the_usage_chunk_of_a_stream() {
+        let text = chunk(r#"{"choices": [{"delta": {"content": "Hi"}, "finish_reason": null}]}"#);
+        assert_eq!(text.usage(), None);
+        assert_eq!(text.into_delta().as_deref(), Some("Hi"));
+
+        let last = chunk(
+            r#"{"choices": [], "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15}}"#,
+        );
+        assert_eq!(
+            last.usage(),
+            Some(Usage {
+                input_tokens: 12,
+                output_tokens: 3
+            })
+        );
+        assert_eq!(last.into_delta(), None);
+    }
+
+    #[test]
+    fn returns_every_choice_in_order() {
+        let response: Response = serde_json::from_str(
+            r#"{"choices": [
+                {"message": {"role": "assistant", "content": "One"}, "finish_reason": "stop"},
+                {"message": {"role": "assistant", "content": "Two"}, "finish_reason": "length"},
+                {"message": {"role": "assistant", "content": "Three"}, "finish_reason": "stop"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let messages = response.into_messages();
+
+        let contents: Vec<_> = messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["One", "Two", "Three"]);
+        assert_eq!(messages[1].finish_reason.as_deref(), Some("length"));
+    }
 }
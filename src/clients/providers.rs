@@ -1,15 +1,75 @@
-use std::fmt;
+use std::{fmt, str::FromStr};
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
+use crate::models::Usage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
 pub enum Provider {
     Anthropic,
+    #[value(name = "openai")]
     OpenAI,
     Mistral,
     Google,
+    Fireworks,
+    Together,
+}
+
+impl Provider {
+    /// Returns the name used for the provider on the command line and in the config file.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Anthropic => "anthropic",
+            Self::OpenAI => "openai",
+            Self::Mistral => "mistral",
+            Self::Google => "google",
+            Self::Fireworks => "fireworks",
+            Self::Together => "together",
+        }
+    }
+
+    /// Returns the environment variable holding the provider's API key.
+    pub const fn key_var(self) -> &'static str {
+        match self {
+            Self::Anthropic => "CLAUDE_API_KEY",
+            Self::OpenAI => "OPENAI_API_KEY",
+            Self::Mistral => "MISTRAL_API_KEY",
+            Self::Google => "GOOGLE_API_KEY",
+            Self::Fireworks => "FIREWORKS_API_KEY",
+            Self::Together => "TOGETHER_API_KEY",
+        }
+    }
+
+    /// Infers the provider of a full model id, such as `gpt-4o-mini`, from its prefix.
+    pub fn detect(model_id: &str) -> Option<Self> {
+        if model_id.starts_with("claude-") {
+            Some(Self::Anthropic)
+        } else if model_id.starts_with("gpt-") || model_id.starts_with("o1-") {
+            Some(Self::OpenAI)
+        } else if model_id.starts_with("gemini-") {
+            Some(Self::Google)
+        } else if model_id.starts_with("codestral") || model_id.starts_with("mistral-") {
+            Some(Self::Mistral)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the context window assumed for a model of the provider whose
+    /// own window is unknown. Hosts of open models get the smallest common one.
+    pub const fn context_window(self) -> u32 {
+        match self {
+            Self::Anthropic => 200_000,
+            Self::OpenAI => 128_000,
+            Self::Mistral => 32_000,
+            Self::Google => 1_048_576,
+            Self::Fireworks | Self::Together => 8_192,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Model {
     #[serde(rename = "gpt-4o")]
     GPT4o,
@@ -31,6 +91,9 @@ pub enum Model {
     GeminiFlash,
     #[serde(rename = "gemini-1.5-pro-latest")]
     GeminiPro,
+    /// A model id passed through to the provider as-is, e.g. Fireworks' `accounts/...` ids.
+    #[serde(untagged)]
+    Custom(String),
 }
 
 impl fmt::Display for Model {
@@ -46,18 +109,274 @@ impl fmt::Display for Model {
             Self::Claude3_5Sonnet => write!(f, "Claude 3.5 Sonnet"),
             Self::GeminiFlash => write!(f, "Gemini 1.5 Flash"),
             Self::GeminiPro => write!(f, "Gemini 1.5 Pro"),
+            Self::Custom(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+impl Model {
+    /// The models with known limits and prices.
+    const KNOWN: [Self; 10] = [
+        Self::GPT4o,
+        Self::GPT4Turbo,
+        Self::GPT3Turbo,
+        Self::Claude3_5Sonnet,
+        Self::Claude3Opus,
+        Self::Claude3Sonnet,
+        Self::Claude3Haiku,
+        Self::Codestral,
+        Self::GeminiFlash,
+        Self::GeminiPro,
+    ];
+
+    /// Returns the known model sent as `id`, or a custom model for any other id.
+    pub fn from_id(id: &str) -> Self {
+        Self::KNOWN
+            .into_iter()
+            .find(|model| model.id() == id)
+            .unwrap_or_else(|| Self::Custom(id.to_string()))
+    }
+
+    /// Returns the model id sent to the provider.
+    pub fn id(&self) -> &str {
+        match self {
+            Self::GPT4o => "gpt-4o",
+            Self::GPT4Turbo => "gpt-4-turbo-preview",
+            Self::GPT3Turbo => "gpt-3-turbo",
+            Self::Claude3_5Sonnet => "claude-3-5-sonnet-20240620",
+            Self::Claude3Opus => "claude-3-opus-20240229",
+            Self::Claude3Sonnet => "claude-3-sonnet-20240229",
+            Self::Claude3Haiku => "claude-3-haiku-20240307",
+            Self::Codestral => "codestral-latest",
+            Self::GeminiFlash => "gemini-1.5-flash-latest",
+            Self::GeminiPro => "gemini-1.5-pro-latest",
+            Self::Custom(id) => id,
+        }
+    }
+
+    /// Returns the maximum number of tokens the model accepts, prompt and output combined.
+    ///
+    /// Custom models get the window of the provider their id is routed to.
+    pub fn context_window(&self) -> u32 {
+        match self {
+            Self::GPT4o | Self::GPT4Turbo => 128_000,
+            Self::GPT3Turbo => 16_385,
+            Self::Claude3_5Sonnet
+            | Self::Claude3Opus
+            | Self::Claude3Sonnet
+            | Self::Claude3Haiku => 200_000,
+            Self::Codestral => 32_000,
+            Self::GeminiFlash => 1_048_576,
+            Self::GeminiPro => 2_097_152,
+            // Ids without a known prefix are mostly open models on a host.
+            Self::Custom(id) => Provider::detect(id)
+                .unwrap_or(Provider::Together)
+                .context_window(),
+        }
+    }
+
+    /// Returns the maximum number of tokens the model generates in one response.
+    pub const fn max_output_tokens(&self) -> u32 {
+        match self {
+            Self::GeminiFlash | Self::GeminiPro => 8_192,
+            _ => 4_096,
+        }
+    }
+
+    /// Returns the published list price of the model. Custom models have no
+    /// known price and must be priced through the config file.
+    pub const fn pricing(&self) -> Pricing {
+        let (input, output) = match self {
+            Self::GPT4o => (5.0, 15.0),
+            Self::GPT4Turbo => (10.0, 30.0),
+            Self::GPT3Turbo => (0.5, 1.5),
+            Self::Claude3_5Sonnet | Self::Claude3Sonnet => (3.0, 15.0),
+            Self::Claude3Opus => (15.0, 75.0),
+            Self::Claude3Haiku => (0.25, 1.25),
+            Self::Codestral => (1.0, 3.0),
+            Self::GeminiFlash => (0.35, 1.05),
+            Self::GeminiPro => (3.5, 10.5),
+            Self::Custom(_) => (0.0, 0.0),
+        };
+
+        Pricing { input, output }
+    }
+
+    /// Returns the inputs the model accepts besides text. Custom models are
+    /// assumed to accept everything and left to the provider to reject.
+    pub const fn capabilities(&self) -> Capabilities {
+        let (vision, tools) = match self {
+            Self::GPT3Turbo => (false, true),
+            Self::Codestral => (false, false),
+            _ => (true, true),
+        };
+
+        Capabilities { vision, tools }
+    }
+
+    /// Returns how many prompt tokens fit once `max_tokens` are reserved for the output.
+    pub fn context_budget(&self, max_tokens: MaxTokens) -> u32 {
+        let reserved = match max_tokens {
+            MaxTokens::Fixed(max_tokens) => max_tokens,
+            MaxTokens::Auto => self.max_output_tokens(),
+        };
+
+        self.context_window().saturating_sub(reserved)
+    }
+}
+
+/// The inputs a model accepts besides text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Accepts images in user messages.
+    pub vision: bool,
+    /// Accepts tool definitions and answers with tool calls.
+    pub tools: bool,
+}
+
+impl Capabilities {
+    /// Checks that `model` supports everything `required` asks for, naming
+    /// the first missing capability otherwise.
+    pub fn check(self, required: Self, model: &Model) -> Result<(), String> {
+        if required.vision && !self.vision {
+            return Err(format!("{model} does not accept images"));
+        }
+        if required.tools && !self.tools {
+            return Err(format!("{model} does not support tools"));
+        }
+        Ok(())
+    }
+}
+
+/// How much effort a reasoning model spends thinking before it answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    /// Returns the OpenAI `reasoning_effort` value.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+
+    /// Returns the Anthropic extended thinking budget in tokens.
+    pub const fn budget_tokens(self) -> u32 {
+        match self {
+            Self::Low => 1_024,
+            Self::Medium => 4_096,
+            Self::High => 16_384,
         }
     }
 }
 
+/// The output token limit for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxTokens {
+    /// Sizes the output to the context left after the prompt, up to the model's output cap.
+    Auto,
+    Fixed(u32),
+}
+
+/// The output token limit used when none is given.
+pub const DEFAULT_MAX_TOKENS: MaxTokens = MaxTokens::Fixed(1028);
+
+impl MaxTokens {
+    /// Resolves the limit for a prompt of `prompt_tokens` sent to `model`.
+    pub fn resolve(self, model: &Model, prompt_tokens: u32) -> u32 {
+        match self {
+            Self::Fixed(max_tokens) => max_tokens,
+            Self::Auto => model
+                .context_window()
+                .saturating_sub(prompt_tokens)
+                .min(model.max_output_tokens())
+                .max(1),
+        }
+    }
+
+    /// Checks a fixed limit against the model's output cap, so an oversized
+    /// value fails with a clear message instead of a provider error.
+    ///
+    /// Custom models have no known cap and are not checked.
+    pub fn validate(self, model: &Model) -> Result<(), String> {
+        match self {
+            Self::Fixed(max_tokens)
+                if !matches!(model, Model::Custom(_)) && max_tokens > model.max_output_tokens() =>
+            {
+                Err(format!(
+                    "--max-tokens {max_tokens} exceeds the {} output token limit of {model}",
+                    model.max_output_tokens()
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for MaxTokens {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+
+        value
+            .parse()
+            .map(Self::Fixed)
+            .map_err(|_| format!("expected a number or `auto`, got `{value}`"))
+    }
+}
+
+/// Token rates in US dollars per million tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl Pricing {
+    /// Estimates the cost in US dollars of the given usage.
+    pub fn estimate_cost(&self, usage: &Usage) -> f64 {
+        (f64::from(usage.input_tokens) * self.input + f64::from(usage.output_tokens) * self.output)
+            / 1_000_000.0
+    }
+}
+
 pub struct ProviderModel {
     pub provider: Provider,
     pub model: Model,
 }
 
 impl ProviderModel {
-    pub fn get_or_default(model_name: &str, default: (Provider, Model)) -> Self {
+    /// Looks up the provider and model for a short model name, such as `sonnet`.
+    ///
+    /// Models hosted by Fireworks and Together are selected with a provider
+    /// prefix and passed through unchanged, e.g. `together:meta-llama/Llama-3-70b-chat-hf`.
+    /// Other full model ids are routed to a provider by their prefix.
+    pub fn get(model_name: &str) -> Option<Self> {
+        if let Some((prefix, id)) = model_name.split_once(':') {
+            let provider = match prefix {
+                "fireworks" => Provider::Fireworks,
+                "together" => Provider::Together,
+                _ => return None,
+            };
+
+            return Some(Self {
+                provider,
+                model: Model::Custom(id.to_string()),
+            });
+        }
+
         let result = match model_name {
+            "gpt-4o" => (Provider::OpenAI, Model::GPT4o),
             "gpt-4-turbo" => (Provider::OpenAI, Model::GPT4Turbo),
             "gpt-3-turbo" => (Provider::OpenAI, Model::GPT3Turbo),
             "sonnet" => (Provider::Anthropic, Model::Claude3_5Sonnet),
@@ -66,12 +385,187 @@ impl ProviderModel {
             "haiku" => (Provider::Anthropic, Model::Claude3Haiku),
             "gemini-flash" => (Provider::Google, Model::GeminiFlash),
             "gemini-pro" => (Provider::Google, Model::GeminiPro),
-            _ => default,
+            _ => return Self::detect(model_name),
         };
 
-        Self {
+        Some(Self {
             provider: result.0,
             model: result.1,
+        })
+    }
+
+    /// Routes a full model id to the provider its prefix names. Known ids keep
+    /// their model so its limits and price apply.
+    fn detect(model_id: &str) -> Option<Self> {
+        Provider::detect(model_id).map(|provider| Self {
+            provider,
+            model: Model::from_id(model_id),
+        })
+    }
+
+    pub fn get_or_default(model_name: &str, default: (Provider, Model)) -> Self {
+        Self::get(model_name).unwrap_or(Self {
+            provider: default.0,
+            model: default.1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detected(model_id: &str) -> (Provider, Model) {
+        let detected = ProviderModel::get(model_id).expect("the model id is routed");
+        (detected.provider, detected.model)
+    }
+
+    #[test]
+    fn routes_full_ids_by_prefix() {
+        for (model_id, provider) in [
+            ("claude-3-7-sonnet-latest", Provider::Anthropic),
+            ("gpt-4o-mini", Provider::OpenAI),
+            ("o1-preview", Provider::OpenAI),
+            ("gemini-2.0-flash", Provider::Google),
+            ("codestral-2405", Provider::Mistral),
+            ("mistral-large-latest", Provider::Mistral),
+        ] {
+            assert_eq!(
+                detected(model_id),
+                (provider, Model::Custom(model_id.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_known_models_for_their_full_ids() {
+        assert_eq!(
+            detected("claude-3-5-sonnet-20240620"),
+            (Provider::Anthropic, Model::Claude3_5Sonnet)
+        );
+        assert_eq!(
+            detected("gpt-4-turbo-preview"),
+            (Provider::OpenAI, Model::GPT4Turbo)
+        );
+        assert_eq!(
+            detected("gemini-1.5-pro-latest"),
+            (Provider::Google, Model::GeminiPro)
+        );
+        assert_eq!(
+            detected("codestral-latest"),
+            (Provider::Mistral, Model::Codestral)
+        );
+        assert!(detected("claude-3-haiku-20240307").1.pricing().input > 0.0);
+    }
+
+    #[test]
+    fn gives_detected_ids_the_window_of_their_provider() {
+        for (model_id, window) in [
+            ("claude-3-7-sonnet-latest", 200_000),
+            ("gpt-4o-mini", 128_000),
+            ("gemini-2.0-flash", 1_048_576),
+            ("mistral-large-latest", 32_000),
+            ("meta-llama/Llama-3-70b-chat-hf", 8_192),
+        ] {
+            assert_eq!(
+                Model::Custom(model_id.to_string()).context_window(),
+                window,
+                "{model_id}"
+            );
         }
+
+        let (_, mini) = detected("gpt-4o-mini");
+        assert_eq!(MaxTokens::Auto.resolve(&mini, 20_000), 4_096);
+        assert_eq!(mini.context_budget(MaxTokens::Auto), 128_000 - 4_096);
+    }
+
+    #[test]
+    fn leaves_unknown_ids_unrouted() {
+        assert!(ProviderModel::get("llama-3-70b").is_none());
+        assert!(ProviderModel::get("acme:model").is_none());
+    }
+
+    #[test]
+    fn passes_hosted_ids_through() {
+        let hosted = ProviderModel::get("together:meta-llama/Llama-3-70b-chat-hf").unwrap();
+        assert_eq!(hosted.provider, Provider::Together);
+        assert_eq!(
+            hosted.model,
+            Model::Custom("meta-llama/Llama-3-70b-chat-hf".to_string())
+        );
+    }
+
+    #[test]
+    fn estimates_cost_per_million_tokens() {
+        let usage = Usage {
+            input_tokens: 200_000,
+            output_tokens: 10_000,
+        };
+
+        let cost = Model::GPT4o.pricing().estimate_cost(&usage);
+        assert!((cost - (0.2 * 5.0 + 0.01 * 15.0)).abs() < 1e-9, "{cost}");
+        assert!(
+            Model::Custom("local".to_string())
+                .pricing()
+                .estimate_cost(&usage)
+                .abs()
+                < f64::EPSILON
+        );
+    }
+
+    #[test]
+    fn sizes_auto_max_tokens_to_the_remaining_context() {
+        assert_eq!("auto".parse(), Ok(MaxTokens::Auto));
+        assert_eq!("AUTO".parse(), Ok(MaxTokens::Auto));
+        assert_eq!("512".parse(), Ok(MaxTokens::Fixed(512)));
+        assert!("lots".parse::<MaxTokens>().is_err());
+
+        // Plenty of room left: capped by the model's output limit.
+        assert_eq!(MaxTokens::Auto.resolve(&Model::GPT4o, 1_000), 4_096);
+        // A nearly full context leaves only the rest for the output.
+        assert_eq!(MaxTokens::Auto.resolve(&Model::GPT4o, 126_000), 2_000);
+        // An overfull prompt still asks for at least one token.
+        assert_eq!(MaxTokens::Auto.resolve(&Model::GPT3Turbo, 20_000), 1);
+        assert_eq!(MaxTokens::Fixed(300).resolve(&Model::GPT4o, 126_000), 300);
+
+        assert_eq!(
+            Model::GPT4o.context_budget(MaxTokens::Auto),
+            128_000 - 4_096
+        );
+        assert_eq!(
+            Model::GPT4o.context_budget(MaxTokens::Fixed(1_000)),
+            127_000
+        );
+    }
+
+    #[test]
+    fn maps_every_known_id_back_to_its_model() {
+        for model in Model::KNOWN {
+            assert_eq!(Model::from_id(model.id()), model);
+        }
+    }
+
+    #[test]
+    fn rejects_max_tokens_over_the_model_cap() {
+        let cap = Model::Claude3Haiku.max_output_tokens();
+
+        let error = MaxTokens::Fixed(cap + 1)
+            .validate(&Model::Claude3Haiku)
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            format!(
+                "--max-tokens {} exceeds the {cap} output token limit of {}",
+                cap + 1,
+                Model::Claude3Haiku
+            )
+        );
+        assert_eq!(MaxTokens::Fixed(cap).validate(&Model::Claude3Haiku), Ok(()));
+        assert_eq!(MaxTokens::Auto.validate(&Model::Claude3Haiku), Ok(()));
+        assert_eq!(
+            MaxTokens::Fixed(u32::MAX).validate(&Model::Custom("local".to_string())),
+            Ok(())
+        );
     }
 }
@@ -0,0 +1,290 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::header::HeaderMap;
+
+use super::providers::Provider;
+
+/// The request and token budget a provider reported in its last response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub remaining_requests: Option<u32>,
+    pub remaining_tokens: Option<u32>,
+    /// When the request budget is replenished.
+    pub reset_requests: Option<Instant>,
+    /// When the token budget is replenished.
+    pub reset_tokens: Option<Instant>,
+}
+
+/// The latest rate limit of each provider, shared by every client in the
+/// process since the budget belongs to the API key rather than the client.
+fn latest_by_provider() -> &'static Mutex<HashMap<Provider, RateLimit>> {
+    static LATEST: OnceLock<Mutex<HashMap<Provider, RateLimit>>> = OnceLock::new();
+    LATEST.get_or_init(Mutex::default)
+}
+
+impl RateLimit {
+    /// Reads the OpenAI `x-ratelimit-*` or Anthropic `anthropic-ratelimit-*`
+    /// headers, returning `None` if the response has neither.
+    ///
+    /// OpenAI gives the reset as a duration such as `6m0s`, Anthropic as an
+    /// RFC 3339 timestamp.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let number = |names: [&str; 2]| {
+            names
+                .into_iter()
+                .find_map(|name| header(name)?.parse().ok())
+        };
+        let reset = |openai: &str, anthropic: &str| {
+            let wait = header(openai).and_then(parse_duration).or_else(|| {
+                let at = parse_timestamp(header(anthropic)?)?;
+                Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+            })?;
+            Instant::now().checked_add(wait)
+        };
+
+        let rate_limit = Self {
+            remaining_requests: number([
+                "x-ratelimit-remaining-requests",
+                "anthropic-ratelimit-requests-remaining",
+            ]),
+            remaining_tokens: number([
+                "x-ratelimit-remaining-tokens",
+                "anthropic-ratelimit-tokens-remaining",
+            ]),
+            reset_requests: reset(
+                "x-ratelimit-reset-requests",
+                "anthropic-ratelimit-requests-reset",
+            ),
+            reset_tokens: reset(
+                "x-ratelimit-reset-tokens",
+                "anthropic-ratelimit-tokens-reset",
+            ),
+        };
+
+        (rate_limit != Self::default()).then_some(rate_limit)
+    }
+
+    /// Records the rate limit `provider` reported with its latest response.
+    pub fn record(self, provider: Provider) {
+        latest_by_provider()
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .insert(provider, self);
+    }
+
+    /// Returns the rate limit `provider` reported with its latest response to
+    /// any client in the process.
+    pub fn latest(provider: Provider) -> Option<Self> {
+        latest_by_provider()
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(&provider)
+            .copied()
+    }
+
+    /// Returns how long to wait before the next request to avoid a 429, if the
+    /// budget is exhausted and has not been replenished yet.
+    pub fn wait(&self) -> Option<Duration> {
+        let requests = self
+            .reset_requests
+            .filter(|_| self.remaining_requests == Some(0));
+        let tokens = self
+            .reset_tokens
+            .filter(|_| self.remaining_tokens == Some(0));
+
+        requests
+            .max(tokens)
+            .map(|reset| reset.saturating_duration_since(Instant::now()))
+            .filter(|wait| !wait.is_zero())
+    }
+}
+
+/// Parses durations such as `1s`, `6m0s`, `250ms` or `1h2m3.5s`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = &rest[number_end..];
+
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" | "" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+
+        total += number * seconds;
+    }
+
+    Duration::try_from_secs_f64(total).ok()
+}
+
+/// Parses RFC 3339 timestamps such as `2024-08-01T12:00:30Z` or
+/// `2024-08-01T14:00:30.5+02:00`.
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    let (date, time) = value.trim().split_once(['T', 't', ' '])?;
+
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else {
+        let sign_at = time.rfind(['+', '-'])?;
+        let (clock, offset) = time.split_at(sign_at);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        (
+            clock,
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60),
+        )
+    };
+
+    let mut clock = clock.splitn(3, ':');
+    let hours: i64 = clock.next()?.parse().ok()?;
+    let minutes: i64 = clock.next()?.parse().ok()?;
+    let seconds: f64 = clock.next()?.parse().ok()?;
+    if hours > 23 || minutes > 59 || !(0.0..61.0).contains(&seconds) {
+        return None;
+    }
+
+    // Days since the epoch of the proleptic Gregorian date, after Howard Hinnant.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let whole = days * 86_400 + hours * 3600 + minutes * 60 - offset;
+    let since_epoch = Duration::from_secs(u64::try_from(whole).ok()?)
+        .checked_add(Duration::try_from_secs_f64(seconds).ok()?)?;
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (*name, HeaderValue::from_str(value).unwrap()))
+            .fold(HeaderMap::new(), |mut headers, (name, value)| {
+                headers.insert(name, value);
+                headers
+            })
+    }
+
+    fn secs_until(reset: Option<Instant>) -> u64 {
+        reset
+            .unwrap()
+            .saturating_duration_since(Instant::now())
+            .as_secs()
+    }
+
+    #[test]
+    fn parses_openai_headers() {
+        let rate_limit = RateLimit::from_headers(&headers(&[
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-remaining-tokens", "1500"),
+            ("x-ratelimit-reset-requests", "6m0s"),
+            ("x-ratelimit-reset-tokens", "250ms"),
+        ]))
+        .unwrap();
+
+        assert_eq!(rate_limit.remaining_requests, Some(0));
+        assert_eq!(rate_limit.remaining_tokens, Some(1500));
+        assert!((358..=360).contains(&secs_until(rate_limit.reset_requests)));
+        assert!(rate_limit.wait().unwrap() > Duration::from_secs(350));
+    }
+
+    #[test]
+    fn parses_anthropic_headers() {
+        let rate_limit = RateLimit::from_headers(&headers(&[
+            ("anthropic-ratelimit-requests-remaining", "49"),
+            ("anthropic-ratelimit-tokens-remaining", "0"),
+            ("anthropic-ratelimit-requests-reset", "2000-01-01T00:00:00Z"),
+            ("anthropic-ratelimit-tokens-reset", "2999-01-01T00:00:00Z"),
+        ]))
+        .unwrap();
+
+        assert_eq!(rate_limit.remaining_requests, Some(49));
+        assert_eq!(rate_limit.remaining_tokens, Some(0));
+        // A reset in the past means the budget is already replenished.
+        assert_eq!(secs_until(rate_limit.reset_requests), 0);
+        assert!(rate_limit.wait().unwrap() > Duration::from_secs(365 * 86_400));
+    }
+
+    #[test]
+    fn ignores_responses_without_rate_limit_headers() {
+        assert_eq!(RateLimit::from_headers(&HeaderMap::new()), None);
+        assert_eq!(RateLimit::default().wait(), None);
+    }
+
+    #[test]
+    fn shares_the_latest_rate_limit_per_provider() {
+        let rate_limit =
+            RateLimit::from_headers(&headers(&[("x-ratelimit-remaining-requests", "7")])).unwrap();
+
+        rate_limit.record(Provider::Together);
+
+        assert_eq!(RateLimit::latest(Provider::Together), Some(rate_limit));
+        assert_eq!(RateLimit::latest(Provider::Fireworks), None);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(
+            parse_duration("1h2m3.5s"),
+            Some(Duration::from_secs_f64(3723.5))
+        );
+        assert_eq!(parse_duration("5x"), None);
+    }
+
+    #[test]
+    fn parses_rfc3339_timestamps() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), at(0));
+        assert_eq!(parse_timestamp("2024-08-01T12:00:30Z"), at(1_722_513_630));
+        assert_eq!(
+            parse_timestamp("2024-08-01T14:00:30+02:00"),
+            at(1_722_513_630)
+        );
+        assert_eq!(parse_timestamp("2024-02-29T00:00:00Z"), at(1_709_164_800));
+        assert_eq!(
+            parse_timestamp("2024-08-01T12:00:30.5Z"),
+            Some(UNIX_EPOCH + Duration::from_secs_f64(1_722_513_630.5))
+        );
+        assert_eq!(parse_timestamp("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_timestamp("soon"), None);
+    }
+}
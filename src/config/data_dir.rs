@@ -1,9 +1,23 @@
 use std::{
     fs,
+    sync::atomic::{AtomicBool, Ordering},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(not(test))]
+static SAVE_HISTORY: AtomicBool = AtomicBool::new(true);
+
+#[cfg(test)]
+thread_local! {
+    /// Each test runs on its own thread, so turning saving off stays local to it.
+    static SAVE_HISTORY: AtomicBool = const { AtomicBool::new(true) };
+
+    /// Replaces the data directory in the home directory on this thread.
+    static MOCK_DATA_DIR: std::cell::RefCell<Option<std::path::PathBuf>> =
+        const { std::cell::RefCell::new(None) };
+}
 
 pub struct DataDir {
     data_dir: std::path::PathBuf,
@@ -30,6 +44,11 @@ impl DataDir {
     /// let instance = DataDir::new();
     /// ```
     pub fn new() -> Self {
+        #[cfg(test)]
+        if let Some(data_dir) = MOCK_DATA_DIR.with(|dir| dir.borrow().clone()) {
+            return Self { data_dir };
+        }
+
         let home_dir = dirs::home_dir().expect("Home dir not found.");
         let data_dir = home_dir.join(".cache/coding-assistant");
 
@@ -40,7 +59,52 @@ impl DataDir {
         Self { data_dir }
     }
 
-    pub fn save_messages<T: Serialize>(&self, messages: &[T]) {
+    /// Makes `new` on this thread return `data_dir` instead of the directory
+    /// in the home directory.
+    #[cfg(test)]
+    pub fn set_mock(data_dir: &std::path::Path) {
+        MOCK_DATA_DIR.with(|dir| *dir.borrow_mut() = Some(data_dir.to_path_buf()));
+    }
+
+    /// Returns the directory holding user prompt overrides.
+    pub fn prompts_dir(&self) -> std::path::PathBuf {
+        self.data_dir.join("prompts")
+    }
+
+    /// Returns the path of the log file, creating the `logs` directory if needed.
+    pub fn log_file(&self) -> std::path::PathBuf {
+        let logs_dir = self.data_dir.join("logs");
+
+        if !logs_dir.exists() {
+            fs::create_dir_all(&logs_dir).expect("Failed to create logs directory");
+        }
+
+        logs_dir.join("coding-assistant.log")
+    }
+
+    /// Turns history persistence on or off for the rest of the process.
+    pub fn set_save_history(enabled: bool) {
+        #[cfg(test)]
+        SAVE_HISTORY.with(|save| save.store(enabled, Ordering::Relaxed));
+        #[cfg(not(test))]
+        SAVE_HISTORY.store(enabled, Ordering::Relaxed);
+    }
+
+    fn saves_history() -> bool {
+        #[cfg(test)]
+        return SAVE_HISTORY.with(|save| save.load(Ordering::Relaxed));
+        #[cfg(not(test))]
+        SAVE_HISTORY.load(Ordering::Relaxed)
+    }
+
+    /// Saves the messages to a new session file, unless saving is turned off.
+    ///
+    /// Returns the id of the saved session.
+    pub fn save_messages<T: Serialize>(&self, messages: &[T]) -> Option<u128> {
+        if !Self::saves_history() {
+            return None;
+        }
+
         let in_ms = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
@@ -56,9 +120,111 @@ impl DataDir {
             Ok(json_string) => {
                 if let Err(e) = std::fs::write(output_path, json_string) {
                     eprintln!("Failed to write to file: {e}");
+                    return None;
                 }
+                Some(in_ms)
             }
-            Err(e) => eprintln!("Failed to serialize messages: {e}"),
+            Err(e) => {
+                eprintln!("Failed to serialize messages: {e}");
+                None
+            }
+        }
+    }
+
+    /// Saves the title of the session `id` next to its messages.
+    pub fn save_title(&self, id: u128, title: &str) {
+        if let Err(e) = fs::write(self.title_path(id), title) {
+            eprintln!("Failed to write the session title: {e}");
         }
     }
+
+    /// Returns the saved title of the session `id`, if it has one.
+    pub fn load_title(&self, id: u128) -> Option<String> {
+        fs::read_to_string(self.title_path(id))
+            .ok()
+            .filter(|title| !title.trim().is_empty())
+    }
+
+    /// Removes the title of the session `id`, if it has one.
+    pub fn remove_title(&self, id: u128) {
+        let _ = fs::remove_file(self.title_path(id));
+    }
+
+    fn title_path(&self, id: u128) -> std::path::PathBuf {
+        self.data_dir.join("history").join(format!("{id}.title"))
+    }
+
+    /// Returns the saved session files with their ids, oldest first.
+    ///
+    /// Session files are named after the millisecond timestamp at which they
+    /// were saved, which doubles as the session id.
+    pub fn sessions(&self) -> Vec<(u128, std::path::PathBuf)> {
+        let Ok(entries) = fs::read_dir(self.data_dir.join("history")) else {
+            return Vec::new();
+        };
+
+        let mut sessions: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                let timestamp = path.file_stem()?.to_str()?.parse::<u128>().ok()?;
+                Some((timestamp, path))
+            })
+            .collect();
+        sessions.sort_by_key(|(timestamp, _)| *timestamp);
+
+        sessions
+    }
+
+    /// Returns the most recently saved session file, if any.
+    pub fn latest_session(&self) -> Option<std::path::PathBuf> {
+        self.sessions().pop().map(|(_, path)| path)
+    }
+
+    pub fn load_messages<T: DeserializeOwned>(
+        &self,
+        path: &std::path::Path,
+    ) -> Result<Vec<T>, Box<dyn std::error::Error + Send + Sync>> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_dir(name: &str) -> DataDir {
+        let data_dir =
+            std::env::temp_dir().join(format!("acai-data-{}-{name}", std::process::id()));
+        fs::create_dir_all(data_dir.join("history")).unwrap();
+        DataDir { data_dir }
+    }
+
+    #[test]
+    fn picks_the_latest_session_by_timestamp() {
+        let dir = data_dir("latest");
+        let history = dir.data_dir.join("history");
+        for file in [
+            "1700000000000.json",
+            "1700000002000.json",
+            "1700000001000.json",
+        ] {
+            fs::write(history.join(file), "[]").unwrap();
+        }
+        fs::write(history.join("1800000000000.title"), "Not a session").unwrap();
+        fs::write(history.join("notes.json"), "[]").unwrap();
+
+        let ids: Vec<_> = dir.sessions().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(
+            ids,
+            [1_700_000_000_000, 1_700_000_001_000, 1_700_000_002_000]
+        );
+        assert_eq!(
+            dir.latest_session(),
+            Some(history.join("1700000002000.json"))
+        );
+
+        fs::remove_dir_all(dir.data_dir).unwrap();
+    }
 }
@@ -1,3 +1,5 @@
 mod data_dir;
+mod settings;
 
 pub use data_dir::*;
+pub use settings::*;
@@ -0,0 +1,214 @@
+use std::{collections::HashMap, fs, sync::OnceLock};
+
+use clap::ValueEnum;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use termimad::MadSkin;
+
+use crate::clients::providers::{Model, Pricing, Provider, ProviderModel};
+
+/// User settings read from `config.json` in the platform config directory.
+///
+/// Every field is optional so a missing or partial file falls back to the
+/// built-in defaults.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    /// Per-model pricing overrides keyed by model id, e.g. `gpt-4o`.
+    #[serde(default)]
+    pub pricing: HashMap<String, Pricing>,
+
+    /// The marker separating prefix and suffix in fill-in-the-middle completions.
+    pub fim_marker: Option<String>,
+
+    /// The markdown theme used to render chat responses.
+    pub theme: Option<Theme>,
+
+    /// The model used when `--model` is omitted, e.g. `sonnet`.
+    pub default_model: Option<String>,
+
+    /// Named option bundles selected with `--profile`.
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+
+    /// Anthropic beta features to opt into, e.g. `context-1m-2025-08-07`.
+    #[serde(default)]
+    pub anthropic_beta: Vec<String>,
+
+    /// Formatter commands keyed by file extension, e.g. `"rs": "rustfmt"`.
+    ///
+    /// Each reads generated code on stdin and prints the formatted code.
+    #[serde(default)]
+    pub formatters: HashMap<String, String>,
+
+    /// Larger-context models keyed by the model they stand in for, e.g.
+    /// `"gpt-4o": "gemini-pro"`. A request rejected for exceeding the model's
+    /// context window is retried once with its larger sibling.
+    #[serde(default)]
+    pub long_context_models: HashMap<String, String>,
+
+    /// System prompts for a single provider, keyed by provider and then by
+    /// prompt name, e.g. `"anthropic": {"chat": "..."}`. They replace the
+    /// generic prompt whenever that provider is selected.
+    #[serde(default)]
+    pub provider_prompts: HashMap<String, HashMap<String, String>>,
+
+    /// The model that names sessions saved with `--auto-title`, ideally a
+    /// cheap one. Defaults to the default model.
+    pub title_model: Option<String>,
+}
+
+/// Defaults for a command's model options, filled in wherever the matching
+/// flag is omitted.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Profile {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub top_p: Option<f32>,
+    /// The prompt used by commands that take `--prompt`.
+    pub prompt: Option<String>,
+}
+
+/// Markdown rendering presets for terminal output.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// Colors suited to a dark terminal background.
+    Dark,
+    /// Colors suited to a light terminal background.
+    Light,
+    /// Prints the raw markdown without styling.
+    None,
+}
+
+impl Theme {
+    /// Returns the skin for this theme, or `None` when rendering is disabled.
+    pub fn skin(self) -> Option<MadSkin> {
+        match self {
+            Self::Dark => Some(MadSkin::default_dark()),
+            Self::Light => Some(MadSkin::default_light()),
+            Self::None => None,
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+impl Config {
+    /// Returns the config, which is read once per process, from `main`, and
+    /// shared by every command, operation and request after that.
+    pub fn get() -> &'static Self {
+        CONFIG.get_or_init(Self::load)
+    }
+
+    /// Loads the config file, returning the defaults if it does not exist or cannot be parsed.
+    fn load() -> Self {
+        let Some(config_dir) = dirs::config_dir() else {
+            return Self::default();
+        };
+
+        let path = config_dir.join("coding-assistant").join("config.json");
+
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return Self::default();
+        };
+
+        serde_json::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring invalid config file {}: {e}", path.display());
+            Self::default()
+        })
+    }
+
+    /// Returns the configured system prompt named `name` for `provider`, if any.
+    pub fn provider_prompt(&self, provider: Provider, name: &str) -> Option<&str> {
+        self.provider_prompts
+            .get(provider.name())?
+            .get(name)
+            .map(String::as_str)
+    }
+
+    /// Returns the pricing for a model, preferring a configured override.
+    pub fn pricing(&self, model: &Model) -> Pricing {
+        self.pricing
+            .get(model.id())
+            .copied()
+            .unwrap_or_else(|| model.pricing())
+    }
+
+    /// Returns the named profile.
+    pub fn profile(&self, name: &str) -> Result<&Profile, String> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| format!("unknown profile `{name}`"))
+    }
+
+    /// Resolves the model to use: an explicit name wins, then the configured
+    /// default, then GPT-4o.
+    pub fn resolve_model(&self, model_name: Option<&str>) -> ProviderModel {
+        model_name
+            .and_then(ProviderModel::get)
+            .or_else(|| self.default_model.as_deref().and_then(ProviderModel::get))
+            .unwrap_or(ProviderModel {
+                provider: Provider::OpenAI,
+                model: Model::GPT4o,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_configured_pricing() {
+        let config: Config =
+            serde_json::from_str(r#"{"pricing": {"gpt-4o": {"input": 1.0, "output": 2.0}}}"#)
+                .unwrap();
+
+        assert_eq!(
+            config.pricing(&Model::GPT4o),
+            Pricing {
+                input: 1.0,
+                output: 2.0
+            }
+        );
+        assert_eq!(
+            config.pricing(&Model::Claude3Haiku),
+            Model::Claude3Haiku.pricing()
+        );
+    }
+
+    #[test]
+    fn resolves_the_configured_default_model_unless_a_flag_is_given() {
+        let config: Config = serde_json::from_str(r#"{"default_model": "sonnet"}"#).unwrap();
+
+        let default = config.resolve_model(None);
+        assert_eq!(default.provider, Provider::Anthropic);
+        assert_eq!(default.model, Model::Claude3_5Sonnet);
+
+        let flagged = config.resolve_model(Some("gpt-4o"));
+        assert_eq!(flagged.provider, Provider::OpenAI);
+        assert_eq!(flagged.model, Model::GPT4o);
+
+        let unconfigured = Config::default().resolve_model(None);
+        assert_eq!(unconfigured.provider, Provider::OpenAI);
+    }
+
+    #[test]
+    fn uses_the_system_prompt_of_the_selected_provider() {
+        let config: Config = serde_json::from_str(
+            r#"{"provider_prompts": {"anthropic": {"chat": "Think step by step."}}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.provider_prompt(Provider::Anthropic, "chat"),
+            Some("Think step by step.")
+        );
+        assert_eq!(
+            config.provider_prompt(Provider::Anthropic, "instruct"),
+            None
+        );
+        assert_eq!(config.provider_prompt(Provider::OpenAI, "chat"), None);
+    }
+}
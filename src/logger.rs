@@ -0,0 +1,131 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::config::DataDir;
+
+/// Controls whether log records are echoed to the console.
+///
+/// Records are always written to the log file in the data directory. Commands
+/// that use stdout/stderr for data, such as `pipe`, should use `Quiet` so the
+/// console only carries their output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConsoleMode {
+    /// Records at `Info` and above are also written to stderr.
+    Stderr,
+    /// Records are only written to the log file.
+    Quiet,
+}
+
+pub struct Logger {
+    console: ConsoleMode,
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    pub fn new(console: ConsoleMode) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(DataDir::new().log_file())
+            .ok()
+            .map(Mutex::new);
+
+        Self { console, file }
+    }
+
+    /// Returns whether records at `level` are echoed to stderr.
+    fn echoes(&self, level: Level) -> bool {
+        self.console == ConsoleMode::Stderr && level <= Level::Info
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if self.echoes(record.level()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+
+        if let Some(file) = &self.file {
+            let in_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis());
+
+            if let Ok(mut file) = file.lock() {
+                let _ = writeln!(
+                    file,
+                    "{in_ms} [{}] {}: {}",
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// Installs the global logger with the given console routing.
+pub fn init(console: ConsoleMode) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(Logger::new(console)))?;
+    log::set_max_level(LevelFilter::Debug);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVELS: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    #[test]
+    fn quiet_mode_keeps_every_record_off_stderr() {
+        let logger = Logger {
+            console: ConsoleMode::Quiet,
+            file: None,
+        };
+
+        assert!(LEVELS.iter().all(|level| !logger.echoes(*level)));
+    }
+
+    #[test]
+    fn stderr_mode_echoes_info_and_above() {
+        let logger = Logger {
+            console: ConsoleMode::Stderr,
+            file: None,
+        };
+
+        let echoed: Vec<_> = LEVELS
+            .into_iter()
+            .filter(|level| logger.echoes(*level))
+            .collect();
+        assert_eq!(echoed, [Level::Error, Level::Warn, Level::Info]);
+    }
+}
@@ -1,28 +1,37 @@
-use std::cmp::max;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OnceCell};
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::{
     CodeAction, CodeActionKind, CodeActionOptions, CodeActionOrCommand, CodeActionParams,
-    CodeActionProviderCapability, CodeActionResponse, CompletionItem, CompletionOptions,
-    CompletionParams, CompletionResponse, DidChangeConfigurationParams,
-    DidChangeTextDocumentParams, DidChangeWatchedFilesParams, DidChangeWorkspaceFoldersParams,
-    DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
-    ExecuteCommandOptions, ExecuteCommandParams, InitializeParams, InitializeResult,
-    InitializedParams, MessageType, Position, Range, SaveOptions, ServerCapabilities,
-    TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
-    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
-    TextDocumentSyncSaveOptions, TextEdit, Url, VersionedTextDocumentIdentifier,
-    WorkDoneProgressOptions, WorkspaceEdit,
+    CodeActionProviderCapability, CodeActionResponse, Command, CompletionContext, CompletionItem,
+    CompletionOptions, CompletionParams, CompletionResponse, CompletionTriggerKind, Diagnostic,
+    DiagnosticSeverity, DidChangeConfigurationParams, DidChangeTextDocumentParams,
+    DidChangeWatchedFilesParams, DidChangeWorkspaceFoldersParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, ExecuteCommandOptions,
+    ExecuteCommandParams, InitializeParams, InitializeResult, InitializedParams, MessageType,
+    OneOf, Position, Range, SaveOptions, ServerCapabilities, TextDocumentContentChangeEvent,
+    TextDocumentIdentifier, TextDocumentItem, TextDocumentSyncCapability, TextDocumentSyncKind,
+    TextDocumentSyncOptions, TextDocumentSyncSaveOptions, TextEdit, Url,
+    VersionedTextDocumentIdentifier, WorkDoneProgressOptions, WorkspaceEdit, WorkspaceFolder,
+    WorkspaceFoldersServerCapabilities, WorkspaceServerCapabilities,
 };
 use tower_lsp::{Client, LanguageServer};
 
-use crate::operations::{Complete, Document, Fix, Instruct, Optimize, Suggest};
+use crate::clients::providers::{Capabilities, Model};
+use crate::config::Config;
+use crate::operations::{
+    format_output, Complete, OperationArgs, OperationKind, Suggest, Suggestions, DEFAULT_FIM_MARKER,
+};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum AiCodeAction {
@@ -61,6 +70,51 @@ impl AiCodeAction {
         }
     }
 
+    /// Returns the kind editors use to group the action in their menus.
+    const fn kind(self) -> CodeActionKind {
+        match self {
+            Self::Fix => CodeActionKind::QUICKFIX,
+            Self::Instruct | Self::Optimize | Self::FillInMiddle => {
+                CodeActionKind::REFACTOR_REWRITE
+            }
+            Self::Document | Self::Suggest | Self::Test => CodeActionKind::SOURCE,
+        }
+    }
+
+    /// Returns where the answer goes relative to the selection by default.
+    const fn placement(self) -> EditPlacement {
+        match self {
+            Self::Document => EditPlacement::Before,
+            Self::Test => EditPlacement::After,
+            Self::Instruct | Self::Fix | Self::Optimize | Self::Suggest | Self::FillInMiddle => {
+                EditPlacement::Replace
+            }
+        }
+    }
+
+    /// Returns the operation the action sends, or `None` for actions that
+    /// are not operations.
+    const fn operation(self) -> Option<OperationKind> {
+        match self {
+            Self::Instruct => Some(OperationKind::Instruct),
+            Self::Document => Some(OperationKind::Document),
+            Self::Fix => Some(OperationKind::Fix),
+            Self::Optimize => Some(OperationKind::Optimize),
+            Self::Suggest => Some(OperationKind::Suggest),
+            Self::FillInMiddle => Some(OperationKind::Complete),
+            Self::Test => None,
+        }
+    }
+
+    /// Returns what the action needs from the model besides text. No action
+    /// attaches images or tools yet.
+    const fn requires(self) -> Capabilities {
+        Capabilities {
+            vision: false,
+            tools: false,
+        }
+    }
+
     /// Returns all the commands that the server currently supports.
     const fn all() -> [Self; 7] {
         [
@@ -92,6 +146,228 @@ impl FromStr for AiCodeAction {
     }
 }
 
+/// Where a code action's answer is placed relative to the selected range.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum EditPlacement {
+    /// Replaces the selection.
+    Replace,
+    /// Inserts the answer on its own lines above the selection.
+    Before,
+    /// Inserts the answer on its own lines below the selection.
+    After,
+}
+
+impl EditPlacement {
+    /// Returns the edit placing `text` relative to `range` in `source`.
+    ///
+    /// Inserted text is re-indented to the first selected line and separated
+    /// from the surrounding code by exactly one line break on each side.
+    fn edit(self, range: Range, text: String, source: &str) -> TextEdit {
+        let line_at = |line: u32| {
+            usize::try_from(line)
+                .ok()
+                .and_then(|line| source.lines().nth(line))
+                .unwrap_or_default()
+        };
+        let indent: String = line_at(range.start.line)
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .collect();
+
+        match self {
+            Self::Replace => TextEdit {
+                range,
+                new_text: text,
+            },
+            Self::Before => {
+                let start = Position {
+                    line: range.start.line,
+                    character: 0,
+                };
+
+                TextEdit {
+                    range: Range { start, end: start },
+                    new_text: format!("{}\n", reindent(&text, &indent)),
+                }
+            }
+            Self::After => {
+                let text = reindent(&text, &indent);
+                let end_line = line_at(range.end.line);
+                let rest = end_line
+                    .char_indices()
+                    .nth(usize::try_from(range.end.character).unwrap_or(usize::MAX))
+                    .map_or("", |(index, _)| &end_line[index..]);
+
+                // A selection ending at the start of a line already ends with
+                // its line break. One ending mid-line is extended past any
+                // trailing whitespace, and code after it moves to its own line.
+                let (at, new_text) = if range.end.character == 0 {
+                    (range.end, format!("{text}\n"))
+                } else if rest.trim().is_empty() {
+                    let at = Position {
+                        line: range.end.line,
+                        character: range.end.character + utf16_len(rest),
+                    };
+                    (at, format!("\n{text}"))
+                } else {
+                    (range.end, format!("\n{text}\n"))
+                };
+
+                TextEdit {
+                    range: Range { start: at, end: at },
+                    new_text,
+                }
+            }
+        }
+    }
+}
+
+/// Indents every line of `text` with `indent` in place of the indentation its
+/// lines share, dropping blank lines at either end.
+fn reindent(text: &str, indent: &str) -> String {
+    let lines: Vec<&str> = text
+        .lines()
+        .skip_while(|line| line.trim().is_empty())
+        .collect();
+    let lines = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(&lines[..0], |last| &lines[..=last]);
+
+    let shared = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.chars().take_while(|c| c.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| {
+            if line.trim().is_empty() {
+                String::new()
+            } else {
+                let unindented: String = line.chars().skip(shared).collect();
+                format!("{indent}{unindented}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Asks for only the documentation, since it is inserted above the code.
+const DOC_COMMENT_PROMPT: &str =
+    "Respond with only the documentation comment for the code, without the code itself.";
+
+/// The number of lines above the cursor sent as completion context.
+const FIM_LINES_BEFORE: usize = 50;
+
+/// The number of lines below the cursor sent as completion context.
+const FIM_LINES_AFTER: usize = 20;
+
+/// Identifier of the command that applies the edit held back by a preview action.
+const APPLY_LAST_COMMAND: &str = "ai.applyLast";
+
+/// Identifier of the command that applies the edit of a resolved code action,
+/// passed as its argument.
+const APPLY_EDIT_COMMAND: &str = "acai.applyEdit";
+
+/// Identifier of the action, and of the command for key bindings, that runs
+/// the action chosen with `defaultAction`.
+const DEFAULT_ACTION: &str = "ai.default";
+
+/// Identifier of the command that reverts the last applied edit of a document.
+const UNDO_LAST_COMMAND: &str = "acai.undoLast";
+
+/// Tells the model how to answer when a change spans several files.
+const MULTI_FILE_PROMPT: &str = r#"If the request requires changes to other files, respond with only a JSON object of the form {"files": [{"path": "<path relative to the workspace root>", "content": "<the full new file contents>"}]}. Otherwise respond with the revised code only."#;
+
+/// A model answer that replaces the contents of one or more files.
+#[derive(Deserialize, Debug)]
+struct FileEdits {
+    files: Vec<FileEdit>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FileEdit {
+    path: String,
+    content: String,
+}
+
+impl FileEdits {
+    /// Parses the answer to the action `action_id` as multi-file edits. Only
+    /// the action that asks for them with `MULTI_FILE_PROMPT` may return them;
+    /// any other answer is code, even when it happens to be JSON.
+    fn from_answer(action_id: &str, answer: &str) -> Option<Self> {
+        if action_id != AiCodeAction::Instruct.identifier() {
+            return None;
+        }
+        serde_json::from_str(answer.trim()).ok()
+    }
+}
+
+/// Options the client can pass in `initializationOptions`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InitializationOptions {
+    /// Preview edits and wait for an explicit "Apply last" before changing the document.
+    #[serde(default)]
+    interactive_edit: bool,
+    /// How long to wait for further typing before sending a completion request.
+    #[serde(default = "default_completion_debounce_ms")]
+    completion_debounce_ms: u64,
+    /// The characters that start a completion as they are typed. Completion
+    /// otherwise only runs when explicitly invoked.
+    #[serde(default = "default_completion_trigger_characters")]
+    completion_trigger_characters: Vec<String>,
+    /// Asks the client to send the full text on save. When off, the saved
+    /// file is read back from disk.
+    #[serde(default = "default_save_include_text")]
+    save_include_text: bool,
+    /// Overrides where each action's answer goes, keyed by command identifier,
+    /// e.g. `{"ai.document": "replace"}`.
+    #[serde(default)]
+    edit_placement: HashMap<String, EditPlacement>,
+    /// The action run by the catch-all "default" action, e.g. `fix` or `ai.fix`.
+    #[serde(default)]
+    default_action: Option<String>,
+}
+
+impl Default for InitializationOptions {
+    fn default() -> Self {
+        Self {
+            interactive_edit: false,
+            completion_debounce_ms: default_completion_debounce_ms(),
+            completion_trigger_characters: default_completion_trigger_characters(),
+            save_include_text: default_save_include_text(),
+            edit_placement: HashMap::new(),
+            default_action: None,
+        }
+    }
+}
+
+const fn default_completion_debounce_ms() -> u64 {
+    300
+}
+
+fn default_completion_trigger_characters() -> Vec<String> {
+    vec![".".to_owned(), ":".to_owned()]
+}
+
+const fn default_save_include_text() -> bool {
+    true
+}
+
+/// The arguments of the `ai.default` command: the document and range to act on.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ActionTarget {
+    #[serde(alias = "document_uri")]
+    document_uri: Url,
+    range: Range,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct CodeActionData {
     id: String,
@@ -99,66 +375,383 @@ struct CodeActionData {
     range: Range,
 }
 
+/// Identifies an operation request so identical concurrent requests share one send.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RequestKey {
+    operation: String,
+    document_uri: Url,
+    context_hash: u64,
+    model: Option<String>,
+}
+
+impl RequestKey {
+    fn new(
+        operation: &str,
+        document_uri: &Url,
+        context: Option<&str>,
+        model: Option<&str>,
+    ) -> Self {
+        let mut hasher = DefaultHasher::new();
+        context.hash(&mut hasher);
+
+        Self {
+            operation: operation.to_string(),
+            document_uri: document_uri.clone(),
+            context_hash: hasher.finish(),
+            model: model.map(ToString::to_string),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct State {
     sources: HashMap<Url, String>,
+    interactive_edit: bool,
+    pending_edit: Option<WorkspaceEdit>,
+    root_uri: Option<Url>,
+    /// The workspace folders open in the client, kept up to date as folders
+    /// are added and removed.
+    workspace_folders: Vec<WorkspaceFolder>,
+    completion_debounce: Duration,
+    /// The characters that start a completion as they are typed.
+    completion_trigger_characters: Vec<String>,
+    /// The latest completion request per document; older requests are dropped.
+    completion_generations: HashMap<Url, u64>,
+    /// The edits reverting the last applied edit of each document.
+    undo_edits: HashMap<Url, Vec<TextEdit>>,
+    /// The document most recently changed by an applied edit.
+    last_edited: Option<Url>,
+    /// Requests being sent, shared by identical requests made in the meantime.
+    in_flight: HashMap<RequestKey, Arc<OnceCell<Option<String>>>>,
+    /// The diagnostics published for each document, cleared once it changes or closes.
+    diagnostics: HashMap<Url, Vec<Diagnostic>>,
+    /// The placements configured by the client, keyed by command identifier.
+    edit_placement: HashMap<String, EditPlacement>,
+    /// The action run by the catch-all action, if the client chose one.
+    default_action: Option<AiCodeAction>,
 }
 
 impl State {
     fn new() -> Self {
         Self {
             sources: HashMap::new(),
+            interactive_edit: false,
+            pending_edit: None,
+            root_uri: None,
+            workspace_folders: Vec::new(),
+            completion_debounce: Duration::from_millis(default_completion_debounce_ms()),
+            completion_trigger_characters: default_completion_trigger_characters(),
+            completion_generations: HashMap::new(),
+            undo_edits: HashMap::new(),
+            last_edited: None,
+            in_flight: HashMap::new(),
+            diagnostics: HashMap::new(),
+            edit_placement: HashMap::new(),
+            default_action: None,
         }
     }
 
-    fn insert_source(&mut self, document: &TextDocumentItem) {
-        if !self.sources.contains_key(&document.uri) {
-            self.sources
-                .insert(document.uri.clone(), document.text.clone());
+    /// Returns the action named by the command identifier `id`, resolving the
+    /// catch-all `DEFAULT_ACTION` to the action chosen with `defaultAction`.
+    fn resolve_action(&self, id: &str) -> std::result::Result<AiCodeAction, String> {
+        if id == DEFAULT_ACTION {
+            return self
+                .default_action
+                .ok_or_else(|| "No defaultAction is configured".to_string());
+        }
+
+        AiCodeAction::from_str(id).map_err(|err| err.to_string())
+    }
+
+    /// Keeps `edit` for "Apply last" when edits are previewed, replacing any
+    /// edit kept before, or returns it to be applied right away otherwise.
+    fn hold(&mut self, edit: WorkspaceEdit) -> Option<WorkspaceEdit> {
+        if !self.interactive_edit {
+            return Some(edit);
+        }
+
+        self.pending_edit = Some(edit);
+        None
+    }
+
+    /// Returns where the answer of the action goes, preferring the client's setting.
+    fn placement(&self, action: AiCodeAction) -> EditPlacement {
+        self.edit_placement
+            .get(action.identifier())
+            .copied()
+            .unwrap_or_else(|| action.placement())
+    }
+
+    /// Returns whether a completion request should be sent to the model.
+    ///
+    /// Explicit invocations always complete; typing only does for a configured
+    /// trigger character. Clients that send no context are treated as invoking.
+    fn should_complete(&self, context: Option<&CompletionContext>) -> bool {
+        let Some(context) = context else {
+            return true;
+        };
+
+        match context.trigger_kind {
+            CompletionTriggerKind::TRIGGER_CHARACTER => context
+                .trigger_character
+                .as_ref()
+                .is_some_and(|trigger| self.completion_trigger_characters.contains(trigger)),
+            CompletionTriggerKind::TRIGGER_FOR_INCOMPLETE_COMPLETIONS => false,
+            _ => true,
+        }
+    }
+
+    /// Returns the edits reverting `edit`, for each document whose text is known.
+    ///
+    /// Must be called before the edit changes the tracked sources.
+    fn undo_for(&self, edit: &WorkspaceEdit) -> HashMap<Url, Vec<TextEdit>> {
+        let Some(changes) = &edit.changes else {
+            return HashMap::new();
+        };
+
+        changes
+            .iter()
+            .filter_map(|(uri, edits)| {
+                let source = self.sources.get(uri).cloned().or_else(|| {
+                    uri.to_file_path()
+                        .ok()
+                        .and_then(|path| std::fs::read_to_string(path).ok())
+                })?;
+                Some((uri.clone(), inverse_edits(&source, edits)))
+            })
+            .collect()
+    }
+
+    /// Remembers how to revert an applied edit, replacing any earlier undo for
+    /// the same documents.
+    fn record_undo(&mut self, undo: HashMap<Url, Vec<TextEdit>>) {
+        for (uri, edits) in undo {
+            self.last_edited = Some(uri.clone());
+            self.undo_edits.insert(uri, edits);
+        }
+    }
+
+    /// Takes the undo edit for `uri`, or for the most recently edited document.
+    fn take_undo(&mut self, uri: Option<Url>) -> Option<WorkspaceEdit> {
+        let uri = uri.or_else(|| self.last_edited.clone())?;
+        let edits = self.undo_edits.remove(&uri)?;
+
+        if self.last_edited.as_ref() == Some(&uri) {
+            self.last_edited = None;
         }
+
+        Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(uri, edits)])),
+            document_changes: None,
+            change_annotations: None,
+        })
+    }
+
+    /// Registers a new completion request for the document and returns its generation.
+    fn next_completion(&mut self, uri: &Url) -> u64 {
+        let generation = self.completion_generations.entry(uri.clone()).or_insert(0);
+        *generation += 1;
+        *generation
+    }
+
+    fn is_latest_completion(&self, uri: &Url, generation: u64) -> bool {
+        self.completion_generations.get(uri) == Some(&generation)
     }
 
+    /// Tracks an opened document. The client's text replaces anything kept
+    /// from before, since the file may have changed while it was closed.
+    fn insert_source(&mut self, document: &TextDocumentItem) {
+        self.sources
+            .insert(document.uri.clone(), document.text.clone());
+    }
+
+    /// Updates a saved document with the text sent on save, or else with the
+    /// file as written to disk.
     fn update_source(&mut self, document: &TextDocumentIdentifier, text: Option<String>) {
+        let text = text.or_else(|| {
+            document
+                .uri
+                .to_file_path()
+                .ok()
+                .and_then(|path| std::fs::read_to_string(path).ok())
+        });
+
         if let Some(text) = text {
             self.sources.insert(document.uri.clone(), text);
         }
     }
 
+    /// Stops tracking a closed document, so stale text is never used for it.
+    fn remove_source(&mut self, document: &TextDocumentIdentifier) {
+        self.sources.remove(&document.uri);
+    }
+
+    /// Applies a batch of content changes to the tracked source.
+    ///
+    /// Each change is spliced in by byte offset, so later changes in the batch
+    /// see the text left by earlier ones and line endings are kept as sent.
     fn reload_source(
         &mut self,
         document: &VersionedTextDocumentIdentifier,
         changes: Vec<TextDocumentContentChangeEvent>,
     ) {
-        if let Some(src) = self.sources.get(&document.uri) {
-            let mut source = src.to_owned();
-            for change in changes {
-                if (change.range, change.range_length) == (None, None) {
-                    source = change.text;
-                } else if let Some(range) = change.range {
-                    let mut lines: Vec<&str> = source.lines().collect();
-                    let new_lines: Vec<&str> = change.text.lines().collect();
-                    let start = usize::try_from(range.start.line).unwrap();
-                    let end = usize::try_from(range.end.line).unwrap();
-                    lines.splice(start..end, new_lines);
-                    source = lines.join("\n");
+        let Some(source) = self.sources.get_mut(&document.uri) else {
+            return;
+        };
+
+        for change in changes {
+            match change.range {
+                Some(range) => {
+                    let start = byte_offset(source, range.start);
+                    let end = byte_offset(source, range.end).max(start);
+                    source.replace_range(start..end, &change.text);
                 }
+                None => *source = change.text,
+            }
+        }
+    }
+
+    /// Resolves a path from a multi-file answer against the workspace.
+    ///
+    /// A relative path resolves inside the workspace folder it starts with by
+    /// name, or else the first folder already holding that file. Any other
+    /// path resolves against the workspace root.
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            return path.to_path_buf();
+        }
+
+        let folders: Vec<(&str, PathBuf)> = self
+            .workspace_folders
+            .iter()
+            .filter_map(|folder| Some((folder.name.as_str(), folder.uri.to_file_path().ok()?)))
+            .collect();
+
+        let in_named_folder = folders.iter().find_map(|(name, dir)| {
+            let rest = path.strip_prefix(name).ok()?;
+            (!rest.as_os_str().is_empty()).then(|| dir.join(rest))
+        });
+        let in_folder_with_file = || {
+            folders
+                .iter()
+                .map(|(_, dir)| dir.join(path))
+                .find(|candidate| {
+                    candidate.exists()
+                        || Url::from_file_path(candidate)
+                            .is_ok_and(|uri| self.sources.contains_key(&uri))
+                })
+        };
+
+        in_named_folder
+            .or_else(in_folder_with_file)
+            .or_else(|| {
+                self.root_uri
+                    .as_ref()
+                    .and_then(|root| root.to_file_path().ok())
+                    .map(|root| root.join(path))
+            })
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// Builds edits replacing the whole contents of each file, resolving
+    /// paths with [`Self::resolve_path`].
+    fn file_edits(&self, file_edits: FileEdits) -> HashMap<Url, Vec<TextEdit>> {
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+
+        for file_edit in file_edits.files {
+            let path = self.resolve_path(&file_edit.path);
+
+            let Ok(uri) = Url::from_file_path(&path) else {
+                continue;
+            };
+
+            let current = self
+                .sources
+                .get(&uri)
+                .cloned()
+                .or_else(|| std::fs::read_to_string(&path).ok())
+                .unwrap_or_default();
+
+            let end = Position {
+                line: u32::try_from(current.lines().count()).unwrap_or(u32::MAX),
+                character: 0,
+            };
+
+            changes.entry(uri).or_default().push(TextEdit {
+                range: Range {
+                    start: Position::default(),
+                    end,
+                },
+                new_text: file_edit.content,
+            });
+        }
+
+        changes
+    }
+
+    /// Returns the text around `position` with the FIM marker at the cursor.
+    ///
+    /// At most `FIM_LINES_BEFORE` lines above and `FIM_LINES_AFTER` lines
+    /// below the cursor are included.
+    fn get_fim_context(&self, document_uri: &Url, position: Position) -> Option<String> {
+        let source = self.sources.get(document_uri)?;
+        let lines: Vec<&str> = source.lines().collect();
+        let cursor_line = usize::try_from(position.line).ok()?;
+        let current = lines.get(cursor_line).copied().unwrap_or_default();
+
+        let split_at = byte_offset(
+            current,
+            Position {
+                line: 0,
+                character: position.character,
+            },
+        );
+        let (before_cursor, after_cursor) = current.split_at(split_at);
+
+        let first_line = cursor_line.saturating_sub(FIM_LINES_BEFORE);
+        let mut prefix = lines
+            .get(first_line..cursor_line)
+            .map(|lines| lines.join("\n"))
+            .unwrap_or_default();
+        if cursor_line > first_line {
+            prefix.push('\n');
+        }
+        prefix.push_str(before_cursor);
+
+        let mut suffix = after_cursor.to_string();
+        let last_line = (cursor_line + 1 + FIM_LINES_AFTER).min(lines.len());
+        if let Some(following) = lines.get(cursor_line + 1..last_line) {
+            if !following.is_empty() {
+                suffix.push('\n');
+                suffix.push_str(&following.join("\n"));
             }
-            self.sources.insert(document.uri.clone(), source);
-        } else {
-            panic!("attempted to reload source that does not exist");
         }
+
+        Some(format!("{prefix}{DEFAULT_FIM_MARKER}{suffix}"))
     }
 
+    /// Returns the lines covered by the range, or the text between its
+    /// characters when it starts and ends on the same line, such as a selection
+    /// within a minified file. Lines past the end of the document are clamped.
     fn get_source_range(&self, document_uri: &Url, range: &Range) -> Option<String> {
-        self.sources.get(document_uri).and_then(|src| {
-            let source = src.to_owned();
-            let lines: Vec<&str> = source.lines().collect();
-            let start = usize::try_from(range.start.line).unwrap();
-            let end = usize::try_from(range.end.line).unwrap();
-            let range_lines = lines.get(start..end);
-
-            range_lines.map(|target_lines| target_lines.join("\n"))
-        })
+        let source = self.sources.get(document_uri)?;
+
+        if range.start.line == range.end.line {
+            let start = byte_offset(source, range.start);
+            let end = byte_offset(source, range.end).max(start);
+            return Some(source[start..end].to_string());
+        }
+
+        let lines: Vec<&str> = source.lines().collect();
+        let end = usize::try_from(range.end.line)
+            .unwrap_or(usize::MAX)
+            .min(lines.len());
+        let start = usize::try_from(range.start.line)
+            .unwrap_or(usize::MAX)
+            .min(end);
+
+        Some(lines[start..end].join("\n"))
     }
 }
 
@@ -176,6 +769,36 @@ impl Backend {
         }
     }
 
+    /// Runs `send` unless an identical request is already in flight, in which
+    /// case its result is awaited and shared instead.
+    async fn coalesce<F, Fut>(&self, key: RequestKey, send: F) -> Option<String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<String>>,
+    {
+        let cell = self
+            .state
+            .lock()
+            .await
+            .in_flight
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let result = cell.get_or_init(send).await.clone();
+
+        let mut state = self.state.lock().await;
+        if state
+            .in_flight
+            .get(&key)
+            .is_some_and(|in_flight| Arc::ptr_eq(in_flight, &cell))
+        {
+            state.in_flight.remove(&key);
+        }
+
+        result
+    }
+
     async fn on_code_action(&self, params: CodeActionParams) -> CodeActionResponse {
         self.client
             .log_message(MessageType::INFO, "on code action")
@@ -189,16 +812,52 @@ impl Backend {
 
         let mut response = CodeActionResponse::new();
 
+        let (interactive_edit, has_pending_edit, has_undo, default_action) = {
+            let state = self.state.lock().await;
+            (
+                state.interactive_edit,
+                state.pending_edit.is_some(),
+                state.undo_edits.contains_key(&document_uri),
+                state.default_action,
+            )
+        };
+
+        if let Some(code_action) = default_action {
+            let title = if interactive_edit {
+                format!("{} (default, preview)", code_action.label())
+            } else {
+                format!("{} (default)", code_action.label())
+            };
+
+            response.push(CodeActionOrCommand::from(CodeAction {
+                title,
+                kind: Some(code_action.kind()),
+                is_preferred: Some(true),
+                data: Some(serde_json::json!(CodeActionData {
+                    id: DEFAULT_ACTION.to_string(),
+                    document_uri: document_uri.clone(),
+                    range,
+                })),
+                ..CodeAction::default()
+            }));
+        }
+
         let code_actions = AiCodeAction::all();
 
         for code_action in &code_actions {
+            let title = if interactive_edit {
+                format!("{} (preview)", code_action.label())
+            } else {
+                code_action.label().to_string()
+            };
+
             let action = CodeAction {
-                title: code_action.label().to_string(),
+                title,
                 command: None,
                 diagnostics: None,
                 edit: None,
                 disabled: None,
-                kind: Some(CodeActionKind::QUICKFIX),
+                kind: Some(code_action.kind()),
                 is_preferred: Some(true),
                 data: Some(serde_json::json!(CodeActionData {
                     id: code_action.identifier().to_string(),
@@ -209,183 +868,381 @@ impl Backend {
             response.push(CodeActionOrCommand::from(action));
         }
 
+        if has_pending_edit {
+            response.push(CodeActionOrCommand::Command(Command {
+                title: "Acai - Apply last".to_string(),
+                command: APPLY_LAST_COMMAND.to_string(),
+                arguments: None,
+            }));
+        }
+
+        if has_undo {
+            response.push(CodeActionOrCommand::Command(Command {
+                title: "Acai - Undo last".to_string(),
+                command: UNDO_LAST_COMMAND.to_string(),
+                arguments: Some(vec![serde_json::json!(document_uri)]),
+            }));
+        }
+
         response
     }
 
     async fn on_code_action_resolve(&self, params: CodeAction) -> CodeAction {
         let mut new_params = params.clone();
 
-        let data = params.data;
+        let cad = match params.data.map(serde_json::from_value::<CodeActionData>) {
+            Some(Ok(cad)) => cad,
+            Some(Err(err)) => {
+                self.client.log_message(MessageType::ERROR, err).await;
+                return new_params;
+            }
+            None => return new_params,
+        };
 
-        let code_action_data = data.map_or_else(
-            || None,
-            |json_obj| {
-                let result: core::result::Result<CodeActionData, serde_json::Error> =
-                    serde_json::from_value::<CodeActionData>(json_obj);
-                Some(result)
-            },
-        );
+        self.client
+            .log_message(
+                MessageType::INFO,
+                format!("Executing {}", params.title.as_str()),
+            )
+            .await;
 
-        let args = if let Some(some_cad) = code_action_data {
-            match some_cad {
-                Ok(cad) => {
-                    self.client
-                        .log_message(MessageType::INFO, format!("Range {:#?}", &cad.range))
-                        .await;
+        if let Some((edit, preview)) = self.action_edit(&cad.id, cad.document_uri, cad.range).await
+        {
+            if let Some(edit) = self.hold_for_preview(edit, &preview).await {
+                // The server applies the edit itself, so its undo is only
+                // recorded once the client reports that it was applied.
+                new_params.command = Some(Command {
+                    title: params.title.clone(),
+                    command: APPLY_EDIT_COMMAND.to_string(),
+                    arguments: Some(vec![serde_json::json!(edit)]),
+                });
+            }
+        }
 
-                    let context = self
-                        .state
-                        .lock()
-                        .await
-                        .get_source_range(&cad.document_uri, &cad.range);
+        new_params
+    }
 
-                    Some((cad.document_uri.clone(), cad.range, context, cad.id))
-                }
+    /// Runs the action `id` on `range` of the document and returns the edit
+    /// placing its answer, along with the answer itself. The catch-all
+    /// `DEFAULT_ACTION` runs the action chosen with `defaultAction`.
+    async fn action_edit(
+        &self,
+        id: &str,
+        document_uri: Url,
+        range: Range,
+    ) -> Option<(WorkspaceEdit, String)> {
+        self.client
+            .log_message(MessageType::INFO, format!("Range {range:#?}"))
+            .await;
+
+        let (action, context, placement) = {
+            let state = self.state.lock().await;
+            match state.resolve_action(id) {
+                Ok(action) => (
+                    action,
+                    state.get_source_range(&document_uri, &range),
+                    state.placement(action),
+                ),
                 Err(err) => {
+                    drop(state);
                     self.client.log_message(MessageType::ERROR, err).await;
-                    None
+                    return None;
                 }
             }
-        } else {
-            None
         };
 
-        if let Some(arg) = args {
-            self.client
-                .log_message(
-                    MessageType::INFO,
-                    format!("Executing {}", params.title.as_str()),
-                )
-                .await;
+        self.client
+            .log_message(MessageType::INFO, format!("Context {context:?}"))
+            .await;
 
-            let document_uri = arg.0;
-            let range = arg.1;
-            let context = arg.2;
-            let id = arg.3;
+        if !self.model_supports(action.requires()).await {
+            return None;
+        }
 
-            self.client
-                .log_message(MessageType::INFO, format!("Context {context:?}"))
-                .await;
+        let response = if action == AiCodeAction::Suggest {
+            self.suggest(&document_uri, &range, context).await
+        } else {
+            // LSP requests always use the default model.
+            let key = RequestKey::new(action.identifier(), &document_uri, context.as_deref(), None);
+            self.coalesce(key, || execute_operation(action, context, placement))
+                .await
+        }?;
+
+        let changes =
+            if let Some(file_edits) = FileEdits::from_answer(action.identifier(), &response) {
+                self.state.lock().await.file_edits(file_edits)
+            } else {
+                let extension = document_uri
+                    .to_file_path()
+                    .ok()
+                    .and_then(|path| Some(path.extension()?.to_str()?.to_string()));
+
+                let text = format_output(response.clone(), extension.as_deref()).await;
+                let source = self
+                    .state
+                    .lock()
+                    .await
+                    .sources
+                    .get(&document_uri)
+                    .cloned()
+                    .unwrap_or_default();
+
+                HashMap::from([(document_uri, vec![placement.edit(range, text, &source)])])
+            };
 
-            let response = execute_operation(id, context).await;
+        let edit = WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        };
 
-            if let Some(str_edit) = response {
-                let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        Some((edit, response))
+    }
 
-                let edits = changes.entry(document_uri).or_default();
+    /// Holds `edit` for "Apply last" and shows the answer when edits are
+    /// previewed, or returns it to be applied right away otherwise.
+    async fn hold_for_preview(&self, edit: WorkspaceEdit, preview: &str) -> Option<WorkspaceEdit> {
+        let edit = self.state.lock().await.hold(edit);
+        if edit.is_some() {
+            return edit;
+        }
 
-                let edit = TextEdit {
-                    range,
-                    new_text: str_edit,
-                };
+        self.client
+            .show_message(
+                MessageType::INFO,
+                format!("Proposed edit, run \"Acai - Apply last\" to apply:\n{preview}"),
+            )
+            .await;
 
-                edits.push(edit);
+        None
+    }
 
-                let edit = Some(WorkspaceEdit {
-                    changes: Some(changes),
-                    document_changes: None,
-                    change_annotations: None,
-                });
+    /// Publishes `diagnostics` for a document, replacing any published before.
+    async fn publish_diagnostics(&self, uri: &Url, diagnostics: Vec<Diagnostic>) {
+        self.state
+            .lock()
+            .await
+            .diagnostics
+            .insert(uri.clone(), diagnostics.clone());
 
-                new_params.edit = edit;
-            }
-        }
+        self.client
+            .publish_diagnostics(uri.clone(), diagnostics, None)
+            .await;
+    }
 
-        new_params
+    /// Clears the diagnostics published for a document, since they may no
+    /// longer match its text.
+    async fn clear_diagnostics(&self, uri: &Url) {
+        let removed = self.state.lock().await.diagnostics.remove(uri);
+
+        if removed.is_some() {
+            self.client
+                .publish_diagnostics(uri.clone(), Vec::new(), None)
+                .await;
+        }
     }
-}
 
-async fn execute_operation(op_title: String, context: Option<String>) -> Option<String> {
-    let code_action = AiCodeAction::from_str(op_title.as_str()).unwrap();
+    /// Checks that the default model supports what `required` asks for, and
+    /// warns instead when the request would be rejected by the provider.
+    async fn model_supports(&self, required: Capabilities) -> bool {
+        let model = Config::get().resolve_model(None).model;
+        let Some(warning) = capability_warning(&model, required) else {
+            return true;
+        };
 
-    if matches!(code_action, AiCodeAction::Test) {
-        return None::<String>;
+        self.client
+            .show_message(MessageType::WARNING, warning)
+            .await;
+        false
     }
 
-    if matches!(code_action, AiCodeAction::FillInMiddle) {
-        let response = Complete {
-            model: None,
-            temperature: None,
-            max_tokens: None,
-            top_p: None,
-            prompt: None,
+    /// Publishes structured suggestions as diagnostics on the selected range.
+    ///
+    /// Returns the model's answer as an edit only when it could not be parsed
+    /// into suggestions.
+    async fn suggest(
+        &self,
+        document_uri: &Url,
+        range: &Range,
+        context: Option<String>,
+    ) -> Option<String> {
+        let response = Suggest(OperationArgs {
             context,
-        }
-        .send()
+            ..OperationArgs::default()
+        })
+        .send_structured()
         .await;
 
-        return if let Ok(Some(response_msg)) = response {
-            Some(response_msg)
-        } else {
-            None
-        };
-    }
-
-    let result = match code_action {
-        AiCodeAction::Instruct => Some(
-            Instruct {
-                model: None,
-                temperature: None,
-                max_tokens: None,
-                top_p: None,
-                prompt: None,
-                context,
-            }
-            .send()
-            .await,
-        ),
-        AiCodeAction::Document => Some(
-            Document {
-                model: None,
-                temperature: None,
-                max_tokens: None,
-                top_p: None,
-                prompt: None,
-                context,
-            }
-            .send()
-            .await,
-        ),
-        AiCodeAction::Fix => Some(
-            Fix {
-                model: None,
-                temperature: None,
-                max_tokens: None,
-                top_p: None,
-                prompt: None,
-                context,
+        match response {
+            Ok(Some(Suggestions::Structured(suggestions))) => {
+                let diagnostics = suggestions
+                    .into_iter()
+                    .map(|suggestion| {
+                        let line = range.start.line + suggestion.line.saturating_sub(1);
+                        Diagnostic {
+                            range: Range {
+                                start: Position { line, character: 0 },
+                                end: Position {
+                                    line: line + 1,
+                                    character: 0,
+                                },
+                            },
+                            severity: Some(DiagnosticSeverity::INFORMATION),
+                            source: Some("acai".to_string()),
+                            message: suggestion.comment,
+                            ..Diagnostic::default()
+                        }
+                    })
+                    .collect();
+
+                self.publish_diagnostics(document_uri, diagnostics).await;
+
+                None
             }
-            .send()
-            .await,
-        ),
-        AiCodeAction::Optimize => Some(
-            Optimize {
-                model: None,
-                temperature: None,
-                max_tokens: None,
-                top_p: None,
-                prompt: None,
-                context,
+            Ok(Some(Suggestions::Text(text))) => Some(text),
+            Ok(None) => None,
+            Err(err) => {
+                self.client
+                    .log_message(MessageType::ERROR, err.to_string())
+                    .await;
+                None
             }
-            .send()
-            .await,
-        ),
-        AiCodeAction::Suggest => Some(
-            Suggest {
-                model: None,
-                temperature: None,
-                max_tokens: None,
-                top_p: None,
-                prompt: None,
-                context,
-            }
-            .send()
-            .await,
-        ),
-        _ => None,
+        }
+    }
+}
+
+/// Returns the warning shown instead of sending `model` a request that needs
+/// `required`, or `None` when the model supports it.
+fn capability_warning(model: &Model, required: Capabilities) -> Option<String> {
+    model
+        .capabilities()
+        .check(required, model)
+        .err()
+        .map(|err| format!("Request not sent: {err}"))
+}
+
+/// Registers a completion request for `uri` and waits for typing to settle,
+/// returning its generation, or `None` if a newer request for the document
+/// arrived in the meantime and supersedes it.
+async fn settle_completion(state: &Mutex<State>, uri: &Url) -> Option<u64> {
+    let (generation, debounce) = {
+        let mut state = state.lock().await;
+        (state.next_completion(uri), state.completion_debounce)
     };
 
-    result.and_then(|response| response.map_or(None, |result| result.map(|msg| msg.content)))
+    tokio::time::sleep(debounce).await;
+
+    state
+        .lock()
+        .await
+        .is_latest_completion(uri, generation)
+        .then_some(generation)
+}
+
+async fn execute_operation(
+    code_action: AiCodeAction,
+    context: Option<String>,
+    placement: EditPlacement,
+) -> Option<String> {
+    let args = OperationArgs {
+        context,
+        ..OperationArgs::default()
+    };
+    let args = match code_action {
+        AiCodeAction::Instruct => OperationArgs {
+            prompt: Some(MULTI_FILE_PROMPT.to_string()),
+            ..args
+        },
+        AiCodeAction::Document if placement == EditPlacement::Before => OperationArgs {
+            prompt: Some(DOC_COMMENT_PROMPT.to_string()),
+            ..args
+        },
+        _ => args,
+    };
+
+    code_action.operation()?.send(args).await.ok().flatten()
+}
+
+/// Returns the edits that revert `edits` once they are applied to `source`.
+///
+/// The edits' ranges all refer to `source`, as in a `WorkspaceEdit`, so they
+/// are applied in document order and each inverse range is located in the
+/// edited text, after the shifts caused by the edits before it.
+fn inverse_edits(source: &str, edits: &[TextEdit]) -> Vec<TextEdit> {
+    let mut spans: Vec<(usize, usize, &str)> = edits
+        .iter()
+        .map(|edit| {
+            let start = byte_offset(source, edit.range.start);
+            let end = byte_offset(source, edit.range.end).max(start);
+            (start, end, edit.new_text.as_str())
+        })
+        .collect();
+    spans.sort_by_key(|&(start, end, _)| (start, end));
+
+    let mut edited = String::with_capacity(source.len());
+    let mut inserted = Vec::with_capacity(spans.len());
+    let mut last = 0;
+    for &(start, end, new_text) in &spans {
+        let start = start.max(last);
+        edited.push_str(&source[last..start]);
+        inserted.push((
+            edited.len(),
+            edited.len() + new_text.len(),
+            &source[start..end.max(start)],
+        ));
+        edited.push_str(new_text);
+        last = end.max(start);
+    }
+    edited.push_str(&source[last..]);
+
+    inserted
+        .into_iter()
+        .map(|(start, end, old_text)| TextEdit {
+            range: Range {
+                start: position_at(&edited, start),
+                end: position_at(&edited, end),
+            },
+            new_text: old_text.to_string(),
+        })
+        .collect()
+}
+
+/// Converts a byte offset in `source` to an LSP position counted in UTF-16 code units.
+fn position_at(source: &str, offset: usize) -> Position {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+
+    Position {
+        line: u32::try_from(before.matches('\n').count()).unwrap_or(u32::MAX),
+        character: utf16_len(&before[line_start..]),
+    }
+}
+
+/// Converts an LSP position, counted in UTF-16 code units, to a byte offset in `source`.
+fn byte_offset(source: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match source[line_start..].find('\n') {
+            Some(index) => line_start += index + 1,
+            None => return source.len(),
+        }
+    }
+
+    let line = source[line_start..].split('\n').next().unwrap_or_default();
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let mut units = 0;
+    for (index, ch) in line.char_indices() {
+        if units >= position.character {
+            return line_start + index;
+        }
+        units += u32::try_from(ch.len_utf16()).unwrap_or(u32::MAX);
+    }
+
+    line_start + line.len()
+}
+
+fn utf16_len(text: &str) -> u32 {
+    u32::try_from(text.encode_utf16().count()).unwrap_or(u32::MAX)
 }
 
 #[tower_lsp::async_trait]
@@ -394,16 +1251,78 @@ impl LanguageServer for Backend {
         self.client
             .log_message(
                 MessageType::INFO,
-                format!("Initializing {:?}", params.root_uri.unwrap().path()),
+                format!("Initializing {:?}", params.root_uri),
             )
             .await;
 
+        // Editors in single-file mode send no root, so fall back to the first
+        // workspace folder and then to the working directory.
+        let root_uri = params
+            .root_uri
+            .clone()
+            .or_else(|| {
+                params
+                    .workspace_folders
+                    .as_ref()?
+                    .first()
+                    .map(|folder| folder.uri.clone())
+            })
+            .or_else(|| {
+                std::env::current_dir()
+                    .ok()
+                    .and_then(|dir| Url::from_directory_path(dir).ok())
+            });
+
+        if params.root_uri.is_none() {
+            let message = root_uri.as_ref().map_or_else(
+                || "No root URI given and no working directory, using none".to_string(),
+                |root| format!("No root URI given, using {root} as the workspace root"),
+            );
+            self.client.log_message(MessageType::INFO, message).await;
+        }
+
+        {
+            let mut state = self.state.lock().await;
+            state.workspace_folders = params.workspace_folders.clone().unwrap_or_default();
+            state.root_uri = root_uri;
+        }
+
+        let options = params
+            .initialization_options
+            .and_then(|value| serde_json::from_value::<InitializationOptions>(value).ok())
+            .unwrap_or_default();
+
+        {
+            let mut state = self.state.lock().await;
+            state.interactive_edit = options.interactive_edit;
+            state.completion_debounce = Duration::from_millis(options.completion_debounce_ms);
+            state
+                .completion_trigger_characters
+                .clone_from(&options.completion_trigger_characters);
+            state.edit_placement.clone_from(&options.edit_placement);
+        }
+
+        if let Some(name) = &options.default_action {
+            let identifier = format!("ai.{}", name.strip_prefix("ai.").unwrap_or(name));
+            match AiCodeAction::from_str(&identifier) {
+                Ok(action) => self.state.lock().await.default_action = Some(action),
+                Err(err) => {
+                    self.client
+                        .log_message(
+                            MessageType::WARNING,
+                            format!("Ignoring defaultAction: {err}"),
+                        )
+                        .await;
+                }
+            }
+        }
+
         // Text Document Sync Configuration
         let text_document_sync = TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
             open_close: Some(true),
-            change: Some(TextDocumentSyncKind::FULL),
+            change: Some(TextDocumentSyncKind::INCREMENTAL),
             save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
-                include_text: Some(true),
+                include_text: Some(options.save_include_text),
             })),
             ..TextDocumentSyncOptions::default()
         });
@@ -412,25 +1331,39 @@ impl LanguageServer for Backend {
             server_info: None,
             capabilities: ServerCapabilities {
                 text_document_sync: Some(text_document_sync),
-                // completion_provider: Some(CompletionOptions {
-                //     resolve_provider: Some(true),
-                //     trigger_characters: Some(vec![".".to_owned(), ":".to_owned()]),
-                //     work_done_progress_options: WorkDoneProgressOptions::default(),
-                //     all_commit_characters: None,
-                //     ..Default::default()
-                // }),
+                completion_provider: Some(CompletionOptions {
+                    trigger_characters: Some(options.completion_trigger_characters),
+                    ..CompletionOptions::default()
+                }),
                 execute_command_provider: Some(ExecuteCommandOptions {
-                    commands: vec!["codingassistant/instruct".to_owned()],
+                    commands: vec![
+                        "codingassistant/instruct".to_owned(),
+                        APPLY_LAST_COMMAND.to_owned(),
+                        APPLY_EDIT_COMMAND.to_owned(),
+                        UNDO_LAST_COMMAND.to_owned(),
+                        DEFAULT_ACTION.to_owned(),
+                    ],
                     work_done_progress_options: WorkDoneProgressOptions::default(),
                 }),
                 code_action_provider: Some(CodeActionProviderCapability::Options(
                     CodeActionOptions {
-                        code_action_kinds: Some(vec![CodeActionKind::QUICKFIX]),
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::QUICKFIX,
+                            CodeActionKind::REFACTOR_REWRITE,
+                            CodeActionKind::SOURCE,
+                        ]),
                         resolve_provider: Some(true),
                         work_done_progress_options: WorkDoneProgressOptions::default(),
                     },
                 )),
                 // Some(CodeActionProviderCapability::Simple(true)),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
                 ..ServerCapabilities::default()
             },
         })
@@ -446,9 +1379,32 @@ impl LanguageServer for Backend {
         Ok(())
     }
 
-    async fn did_change_workspace_folders(&self, _: DidChangeWorkspaceFoldersParams) {
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        let count = {
+            let mut state = self.state.lock().await;
+            let folders = &mut state.workspace_folders;
+
+            folders.retain(|folder| {
+                !params
+                    .event
+                    .removed
+                    .iter()
+                    .any(|removed| removed.uri == folder.uri)
+            });
+            for added in params.event.added {
+                if !folders.iter().any(|folder| folder.uri == added.uri) {
+                    folders.push(added);
+                }
+            }
+
+            folders.len()
+        };
+
         self.client
-            .log_message(MessageType::INFO, "workspace folders changed!")
+            .log_message(
+                MessageType::INFO,
+                format!("workspace folders changed, {count} open"),
+            )
             .await;
     }
 
@@ -464,13 +1420,93 @@ impl LanguageServer for Backend {
             .await;
     }
 
-    async fn execute_command(&self, _: ExecuteCommandParams) -> Result<Option<Value>> {
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
         self.client
             .log_message(MessageType::INFO, "command executed!")
             .await;
 
-        match self.client.apply_edit(WorkspaceEdit::default()).await {
-            Ok(res) if res.applied => self.client.log_message(MessageType::INFO, "applied").await,
+        let edit = if params.command == APPLY_LAST_COMMAND {
+            let pending_edit = self.state.lock().await.pending_edit.take();
+            if let Some(edit) = pending_edit {
+                edit
+            } else {
+                self.client
+                    .show_message(MessageType::WARNING, "No previewed edit to apply")
+                    .await;
+                return Ok(None);
+            }
+        } else if params.command == DEFAULT_ACTION {
+            let target = params
+                .arguments
+                .first()
+                .and_then(|argument| serde_json::from_value::<ActionTarget>(argument.clone()).ok());
+            let Some(target) = target else {
+                self.client
+                    .show_message(
+                        MessageType::WARNING,
+                        "ai.default needs a {\"documentUri\", \"range\"} argument",
+                    )
+                    .await;
+                return Ok(None);
+            };
+
+            let edit = self
+                .action_edit(DEFAULT_ACTION, target.document_uri, target.range)
+                .await;
+            let Some((edit, preview)) = edit else {
+                return Ok(None);
+            };
+            match self.hold_for_preview(edit, &preview).await {
+                Some(edit) => edit,
+                None => return Ok(None),
+            }
+        } else if params.command == APPLY_EDIT_COMMAND {
+            let edit = params.arguments.first().and_then(|argument| {
+                serde_json::from_value::<WorkspaceEdit>(argument.clone()).ok()
+            });
+            if let Some(edit) = edit {
+                edit
+            } else {
+                self.client
+                    .log_message(MessageType::ERROR, "acai.applyEdit needs an edit to apply")
+                    .await;
+                return Ok(None);
+            }
+        } else if params.command == UNDO_LAST_COMMAND {
+            let uri = params
+                .arguments
+                .first()
+                .and_then(|argument| serde_json::from_value::<Url>(argument.clone()).ok());
+
+            let undo_edit = self.state.lock().await.take_undo(uri);
+            if let Some(edit) = undo_edit {
+                edit
+            } else {
+                self.client
+                    .show_message(MessageType::WARNING, "No edit to undo")
+                    .await;
+                return Ok(None);
+            }
+        } else {
+            WorkspaceEdit::default()
+        };
+
+        // Undo edits are not themselves recorded, so only one step can be undone.
+        // The undo is worked out from the text before the edit but only kept
+        // once the client has applied it.
+        let undo = if params.command == UNDO_LAST_COMMAND {
+            None
+        } else {
+            Some(self.state.lock().await.undo_for(&edit))
+        };
+
+        match self.client.apply_edit(edit).await {
+            Ok(res) if res.applied => {
+                if let Some(undo) = undo {
+                    self.state.lock().await.record_undo(undo);
+                }
+                self.client.log_message(MessageType::INFO, "applied").await;
+            }
             Ok(_) => self.client.log_message(MessageType::INFO, "rejected").await,
             Err(err) => self.client.log_message(MessageType::ERROR, err).await,
         }
@@ -497,7 +1533,12 @@ impl LanguageServer for Backend {
             )
             .await;
 
-        // reload_source(&self.state, &params.text_document, params.content_changes).await;
+        self.state
+            .lock()
+            .await
+            .reload_source(&params.text_document, params.content_changes);
+
+        self.clear_diagnostics(&params.text_document.uri).await;
     }
 
     // Test
@@ -509,7 +1550,6 @@ impl LanguageServer for Backend {
             )
             .await;
 
-        // Update source
         self.state
             .lock()
             .await
@@ -523,10 +1563,14 @@ impl LanguageServer for Backend {
             .await;
     }
 
-    async fn did_close(&self, _: DidCloseTextDocumentParams) {
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
         self.client
             .log_message(MessageType::INFO, "file closed!")
             .await;
+
+        self.state.lock().await.remove_source(&params.text_document);
+
+        self.clear_diagnostics(&params.text_document.uri).await;
     }
 
     async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
@@ -553,34 +1597,50 @@ impl LanguageServer for Backend {
         let uri = params.text_document_position.text_document.uri;
         let position = params.text_document_position.position;
 
-        self.client
-            .log_message(MessageType::INFO, uri.clone())
-            .await;
+        if !self
+            .state
+            .lock()
+            .await
+            .should_complete(params.context.as_ref())
+        {
+            return Ok(None);
+        }
 
-        let range = Range {
-            start: Position {
-                line: max(position.line - 3, 0),
-                character: 0,
-            },
-            end: position,
+        let Some(generation) = settle_completion(&self.state, &uri).await else {
+            return Ok(None);
         };
 
-        let context = self.state.lock().await.get_source_range(&uri, &range);
-
         self.client
-            .log_message(MessageType::INFO, context.clone().unwrap())
+            .log_message(MessageType::INFO, uri.clone())
             .await;
 
-        let op = Complete {
-            model: None,
-            temperature: None,
-            max_tokens: None,
-            top_p: None,
-            prompt: None,
+        if !self
+            .model_supports(AiCodeAction::FillInMiddle.requires())
+            .await
+        {
+            return Ok(None);
+        }
+
+        let context = self.state.lock().await.get_fim_context(&uri, position);
+
+        let op = Complete(OperationArgs {
             context,
-        };
+            fim_marker: Some(DEFAULT_FIM_MARKER.to_string()),
+            ..OperationArgs::default()
+        });
+
+        // Only the first line is streamed; if the client cancels the request the
+        // future is dropped, which abandons the stream.
+        let response = op.send_first_line().await;
 
-        let response = op.send().await;
+        if !self
+            .state
+            .lock()
+            .await
+            .is_latest_completion(&uri, generation)
+        {
+            return Ok(None);
+        }
 
         let msg = if let Ok(Some(response_msg)) = response {
             Some(response_msg)
@@ -588,10 +1648,6 @@ impl LanguageServer for Backend {
             None
         };
 
-        self.client
-            .log_message(MessageType::INFO, msg.clone().unwrap())
-            .await;
-
         msg.map_or(Ok(None), |msg| {
             Ok(Some(CompletionResponse::Array(vec![
                 CompletionItem::new_simple(msg.clone(), msg),
@@ -599,3 +1655,749 @@ impl LanguageServer for Backend {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tower_lsp::lsp_types::WorkspaceFoldersChangeEvent;
+
+    use super::*;
+
+    fn uri() -> Url {
+        Url::parse("file:///tmp/main.rs").unwrap()
+    }
+
+    fn state_with(source: &str) -> State {
+        let mut state = State::new();
+        state.sources.insert(uri(), source.to_string());
+        state
+    }
+
+    /// Returns a server whose client socket is dropped, so messages to the
+    /// client are discarded instead of filling its queue.
+    fn service() -> tower_lsp::LspService<Backend> {
+        let (service, _) = tower_lsp::LspService::new(Backend::new);
+        service
+    }
+
+    const fn position(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    #[test]
+    fn counts_positions_in_utf16_units() {
+        let source = "let a = 1;\nlet 😀 = \"é\";\r\nend";
+
+        assert_eq!(byte_offset(source, position(0, 4)), 4);
+        // The emoji is two UTF-16 units and four bytes.
+        assert_eq!(byte_offset(source, position(1, 6)), 11 + 8);
+        assert_eq!(&source[byte_offset(source, position(1, 10))..][..2], "é");
+        // Positions past the end of a line stop before its line break.
+        assert_eq!(
+            byte_offset(source, position(1, 99)),
+            source.find("\r\n").unwrap()
+        );
+        assert_eq!(byte_offset(source, position(9, 0)), source.len());
+    }
+
+    fn change(start: Position, end: Position, text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range { start, end }),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    fn document() -> VersionedTextDocumentIdentifier {
+        VersionedTextDocumentIdentifier {
+            uri: uri(),
+            version: 2,
+        }
+    }
+
+    #[test]
+    fn applies_each_change_to_the_text_left_by_the_last() {
+        let mut state = state_with("let a = 1;\nlet b = 2;\n");
+
+        state.reload_source(
+            &document(),
+            vec![
+                // Adds a line, shifting `let b` down.
+                change(position(1, 0), position(1, 0), "let c = 3;\n"),
+                // Refers to the shifted line.
+                change(position(2, 8), position(2, 9), "20"),
+            ],
+        );
+
+        assert_eq!(
+            state.sources[&uri()],
+            "let a = 1;\nlet c = 3;\nlet b = 20;\n"
+        );
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let mut state = state_with("one\r\ntwo\r\nthree\r\n");
+
+        state.reload_source(
+            &document(),
+            vec![
+                change(position(1, 0), position(1, 3), "TWO"),
+                change(position(2, 5), position(2, 5), "!"),
+            ],
+        );
+
+        assert_eq!(state.sources[&uri()], "one\r\nTWO\r\nthree!\r\n");
+    }
+
+    #[test]
+    fn selects_within_a_single_long_line() {
+        let state = state_with("var a=1;var b=2;var c=3;");
+
+        let range = Range {
+            start: position(0, 8),
+            end: position(0, 16),
+        };
+
+        assert_eq!(
+            state.get_source_range(&uri(), &range).as_deref(),
+            Some("var b=2;")
+        );
+    }
+
+    #[test]
+    fn clamps_out_of_bounds_ranges() {
+        let state = state_with("one\ntwo\nthree\n");
+
+        let past_the_end = Range {
+            start: position(1, 0),
+            end: position(40, 0),
+        };
+        let after_the_end = Range {
+            start: position(20, 0),
+            end: position(30, 0),
+        };
+
+        assert_eq!(
+            state.get_source_range(&uri(), &past_the_end).as_deref(),
+            Some("two\nthree")
+        );
+        assert_eq!(
+            state.get_source_range(&uri(), &after_the_end).as_deref(),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn inserts_documentation_above_and_replaces_fixes() {
+        let source = "fn add(a: i32, b: i32) -> i32 {\n    a - b\n}\n";
+        let selection = Range {
+            start: position(0, 0),
+            end: position(2, 1),
+        };
+        let fixed = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}";
+
+        let documented = AiCodeAction::Document.placement().edit(
+            selection,
+            "/// Adds two numbers.".to_string(),
+            source,
+        );
+        assert_eq!(documented.range.start, position(0, 0));
+        assert_eq!(documented.range.end, position(0, 0));
+        assert_eq!(documented.new_text, "/// Adds two numbers.\n");
+
+        let replaced = AiCodeAction::Fix
+            .placement()
+            .edit(selection, fixed.to_string(), source);
+        assert_eq!(replaced.range, selection);
+        assert_eq!(replaced.new_text, fixed);
+    }
+
+    #[test]
+    fn inserts_above_an_indented_block_at_its_indentation() {
+        let source = "impl Stack {\n    fn pop(&mut self) {\n        self.items.pop();\n    }\n}\n";
+        let selection = Range {
+            start: position(1, 4),
+            end: position(3, 5),
+        };
+
+        let edit = EditPlacement::Before.edit(
+            selection,
+            "\n/// Removes the top item.\n/// Does nothing when empty.\n\n".to_string(),
+            source,
+        );
+
+        assert_eq!(edit.range.start, position(1, 0));
+        assert_eq!(edit.range.end, position(1, 0));
+        assert_eq!(
+            edit.new_text,
+            "    /// Removes the top item.\n    /// Does nothing when empty.\n"
+        );
+    }
+
+    #[test]
+    fn inserts_below_a_function_with_one_separating_line_break() {
+        let test = "#[test]\nfn adds() {}\n".to_string();
+
+        // The selection ends at the start of the next line.
+        let after_newline = EditPlacement::After.edit(
+            Range {
+                start: position(0, 0),
+                end: position(1, 0),
+            },
+            test.clone(),
+            "fn add() {}\nfn sub() {}\n",
+        );
+        assert_eq!(after_newline.range.start, position(1, 0));
+        assert_eq!(after_newline.new_text, "#[test]\nfn adds() {}\n");
+
+        // The selection ends mid-line before trailing whitespace.
+        let at_line_end = EditPlacement::After.edit(
+            Range {
+                start: position(0, 0),
+                end: position(0, 11),
+            },
+            test.clone(),
+            "fn add() {}  ",
+        );
+        assert_eq!(at_line_end.range.start, position(0, 13));
+        assert_eq!(at_line_end.new_text, "\n#[test]\nfn adds() {}");
+
+        // Code follows the selection on the same line.
+        let before_code = EditPlacement::After.edit(
+            Range {
+                start: position(0, 0),
+                end: position(0, 11),
+            },
+            test,
+            "fn add() {} fn sub() {}",
+        );
+        assert_eq!(before_code.range.start, position(0, 11));
+        assert_eq!(before_code.new_text, "\n#[test]\nfn adds() {}\n");
+    }
+
+    #[test]
+    fn splits_fim_context_at_the_utf16_cursor() {
+        let state = state_with("fn main() {\n    let s = \"😀😀\"; foo\n}\n");
+
+        // After `let s = "😀😀";`, where each emoji counts twice.
+        let context = state.get_fim_context(&uri(), position(1, 19)).unwrap();
+
+        assert_eq!(
+            context,
+            format!("fn main() {{\n    let s = \"😀😀\";{DEFAULT_FIM_MARKER} foo\n}}")
+        );
+    }
+
+    fn workspace_edit(new_text: &str) -> WorkspaceEdit {
+        WorkspaceEdit {
+            changes: Some(HashMap::from([(
+                uri(),
+                vec![text_edit(position(0, 0), position(0, 0), new_text)],
+            )])),
+            document_changes: None,
+            change_annotations: None,
+        }
+    }
+
+    #[test]
+    fn previews_edits_until_applied() {
+        let mut state = State::new();
+        assert_eq!(
+            state.hold(workspace_edit("now")),
+            Some(workspace_edit("now"))
+        );
+        assert_eq!(state.pending_edit, None);
+
+        state.interactive_edit = true;
+        assert_eq!(state.hold(workspace_edit("first")), None);
+        assert_eq!(state.hold(workspace_edit("second")), None);
+
+        // "Apply last" applies the latest proposal, once.
+        assert_eq!(state.pending_edit.take(), Some(workspace_edit("second")));
+        assert_eq!(state.pending_edit.take(), None);
+    }
+
+    #[test]
+    fn lists_actions_in_a_stable_order_and_kind() {
+        let actions: Vec<_> = AiCodeAction::all()
+            .into_iter()
+            .map(|action| (action.identifier(), action.kind()))
+            .collect();
+
+        assert_eq!(
+            actions,
+            [
+                ("ai.instruct", CodeActionKind::REFACTOR_REWRITE),
+                ("ai.document", CodeActionKind::SOURCE),
+                ("ai.fix", CodeActionKind::QUICKFIX),
+                ("ai.optimize", CodeActionKind::REFACTOR_REWRITE),
+                ("ai.suggest", CodeActionKind::SOURCE),
+                ("ai.fillInMiddle", CodeActionKind::REFACTOR_REWRITE),
+                ("ai.test", CodeActionKind::SOURCE),
+            ]
+        );
+
+        for action in AiCodeAction::all() {
+            assert_eq!(AiCodeAction::from_str(action.identifier()).unwrap(), action);
+        }
+    }
+
+    #[test]
+    fn routes_the_catch_all_to_the_default_action() {
+        let mut state = State::new();
+        assert!(state.resolve_action(DEFAULT_ACTION).is_err());
+
+        state.default_action = Some(AiCodeAction::Fix);
+        assert_eq!(state.resolve_action(DEFAULT_ACTION), Ok(AiCodeAction::Fix));
+        assert_eq!(
+            state.resolve_action("ai.document"),
+            Ok(AiCodeAction::Document)
+        );
+        assert!(state.resolve_action("ai.unknown").is_err());
+    }
+
+    #[test]
+    fn blocks_images_for_text_only_models() {
+        let image = Capabilities {
+            vision: true,
+            tools: false,
+        };
+
+        let warning = capability_warning(&Model::Codestral, image).unwrap();
+        assert!(warning.contains("does not accept images"), "{warning}");
+        assert_eq!(capability_warning(&Model::GPT4o, image), None);
+
+        for action in AiCodeAction::all() {
+            assert_eq!(
+                capability_warning(&Model::Codestral, action.requires()),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn parses_file_edits_only_for_the_multi_file_action() {
+        let answer = r#" {"files": [{"path": "src/lib.rs", "content": "pub fn a() {}"}]} "#;
+
+        let edits = FileEdits::from_answer("ai.instruct", answer).unwrap();
+        assert_eq!(edits.files.len(), 1);
+        assert_eq!(edits.files[0].path, "src/lib.rs");
+
+        assert!(FileEdits::from_answer("ai.fix", answer).is_none());
+        assert!(FileEdits::from_answer("ai.instruct", "fn main() {}").is_none());
+    }
+
+    #[test]
+    fn edits_every_file_of_a_multi_file_answer() {
+        let root = Url::parse("file:///acai-missing-project/").unwrap();
+        let main = root.join("src/main.rs").unwrap();
+        let lib = root.join("src/lib.rs").unwrap();
+
+        let mut state = State::new();
+        state.root_uri = Some(root);
+        state
+            .sources
+            .insert(main.clone(), "mod lib;\nfn main() {}\n".to_string());
+
+        let answer = r#"{"files": [
+            {"path": "src/main.rs", "content": "mod lib;\nfn main() { lib::run() }\n"},
+            {"path": "src/lib.rs", "content": "pub fn run() {}\n"}
+        ]}"#;
+        let changes = state.file_edits(FileEdits::from_answer("ai.instruct", answer).unwrap());
+
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[&main],
+            [text_edit(
+                position(0, 0),
+                position(2, 0),
+                "mod lib;\nfn main() { lib::run() }\n"
+            )]
+        );
+        // A new file is created by replacing its empty contents.
+        assert_eq!(
+            changes[&lib],
+            [text_edit(
+                position(0, 0),
+                position(0, 0),
+                "pub fn run() {}\n"
+            )]
+        );
+    }
+
+    fn text_edit(start: Position, end: Position, new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range { start, end },
+            new_text: new_text.to_string(),
+        }
+    }
+
+    /// Applies non-overlapping edits whose ranges all refer to `source`.
+    fn apply(source: &str, edits: &[TextEdit]) -> String {
+        let mut edits = edits.to_vec();
+        edits.sort_by_key(|edit| std::cmp::Reverse(byte_offset(source, edit.range.start)));
+
+        let mut result = source.to_string();
+        for edit in edits {
+            let start = byte_offset(source, edit.range.start);
+            let end = byte_offset(source, edit.range.end);
+            result.replace_range(start..end, &edit.new_text);
+        }
+        result
+    }
+
+    #[test]
+    fn inverts_several_edits_to_one_document() {
+        let source = "fn a() {}\nfn b() {}\nfn c() {}\n";
+        let edits = [
+            text_edit(position(2, 3), position(2, 4), "gamma"),
+            text_edit(position(0, 3), position(0, 4), "alpha\n// 😀 split"),
+            text_edit(position(1, 0), position(1, 0), "// inserted\n"),
+        ];
+
+        let edited = apply(source, &edits);
+        assert_eq!(
+            edited,
+            "fn alpha\n// 😀 split() {}\n// inserted\nfn b() {}\nfn gamma() {}\n"
+        );
+
+        let inverse = inverse_edits(source, &edits);
+        assert_eq!(
+            inverse[0].range,
+            Range {
+                start: position(0, 3),
+                end: position(1, 11)
+            }
+        );
+        assert_eq!(apply(&edited, &inverse), source);
+    }
+
+    #[test]
+    fn records_undo_only_when_asked() {
+        let mut state = state_with("let x = 1;\n");
+        let edit = WorkspaceEdit {
+            changes: Some(HashMap::from([(
+                uri(),
+                vec![text_edit(position(0, 8), position(0, 9), "42")],
+            )])),
+            document_changes: None,
+            change_annotations: None,
+        };
+
+        let undo = state.undo_for(&edit);
+        assert!(state.take_undo(None).is_none());
+
+        state.record_undo(undo);
+        let reverted = state.take_undo(None).unwrap().changes.unwrap();
+        assert_eq!(
+            reverted[&uri()],
+            vec![text_edit(position(0, 8), position(0, 10), "1")]
+        );
+    }
+
+    #[test]
+    fn tracks_documents_from_open_to_close() {
+        let path = std::env::temp_dir().join(format!("ca-lsp-{}.rs", std::process::id()));
+        std::fs::write(&path, "on disk").unwrap();
+        let uri = Url::from_file_path(&path).unwrap();
+        let document = TextDocumentIdentifier { uri: uri.clone() };
+        let opened = |text: &str| TextDocumentItem {
+            uri: uri.clone(),
+            language_id: "rust".to_string(),
+            version: 1,
+            text: text.to_string(),
+        };
+
+        let mut state = State::new();
+        state.insert_source(&opened("first"));
+        state.insert_source(&opened("reopened"));
+        assert_eq!(state.sources[&uri], "reopened");
+
+        state.update_source(&document, Some("saved".to_string()));
+        assert_eq!(state.sources[&uri], "saved");
+
+        state.update_source(&document, None);
+        assert_eq!(state.sources[&uri], "on disk");
+
+        state.remove_source(&document);
+        assert!(!state.sources.contains_key(&uri));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn applies_incremental_changes_in_order() {
+        let mut state = state_with("hello world\nsecond 😀 line\n");
+        let document = VersionedTextDocumentIdentifier {
+            uri: uri(),
+            version: 2,
+        };
+        let change = |start: Position, end: Position, text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range { start, end }),
+            range_length: None,
+            text: text.to_string(),
+        };
+
+        state.reload_source(
+            &document,
+            vec![
+                change(position(0, 6), position(0, 11), "there"),
+                change(position(1, 7), position(1, 9), "🎉"),
+                change(position(0, 0), position(0, 0), "// "),
+            ],
+        );
+
+        assert_eq!(state.sources[&uri()], "// hello there\nsecond 🎉 line\n");
+
+        state.reload_source(
+            &document,
+            vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "replaced".to_string(),
+            }],
+        );
+        assert_eq!(state.sources[&uri()], "replaced");
+    }
+
+    #[tokio::test]
+    async fn sends_only_the_last_of_rapid_completions() {
+        let mut state = State::new();
+        state.completion_debounce = Duration::from_millis(50);
+        let state = Mutex::new(state);
+
+        let keystroke = |delay| {
+            let state = &state;
+            async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                settle_completion(state, &uri()).await
+            }
+        };
+        let settled = tokio::join!(keystroke(0), keystroke(10), keystroke(20));
+
+        assert_eq!(settled, (None, None, Some(3)));
+
+        // A request after the window is sent on its own.
+        assert_eq!(settle_completion(&state, &uri()).await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn sends_each_action_with_its_operation_prompt() {
+        use crate::clients::mock_server::{MockResponse, MockServer};
+        use crate::config::DataDir;
+        use crate::operations::{Document, Fix, Instruct, Operation, Optimize};
+
+        let actions = [
+            (AiCodeAction::Instruct, Instruct::DEFAULT_PROMPT),
+            (AiCodeAction::Document, Document::DEFAULT_PROMPT),
+            (AiCodeAction::Fix, Fix::DEFAULT_PROMPT),
+            (AiCodeAction::Optimize, Optimize::DEFAULT_PROMPT),
+            (AiCodeAction::Suggest, Suggest::DEFAULT_PROMPT),
+        ];
+        let answer = serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": "done" }, "finish_reason": "stop" }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 1 }
+        });
+        let server = MockServer::start(
+            actions
+                .iter()
+                .map(|_| MockResponse::json(200, &answer))
+                .collect(),
+        )
+        .await;
+        DataDir::set_save_history(false);
+        let provider = Config::get().resolve_model(None).provider;
+        std::env::set_var(provider.key_var(), "test-key");
+
+        for (action, _) in actions {
+            let answer =
+                execute_operation(action, Some("fn main() {}".to_string()), action.placement())
+                    .await;
+            assert_eq!(answer.as_deref(), Some("done"), "{action:?}");
+        }
+
+        for (request, (action, prompt)) in server.requests().iter().zip(actions) {
+            let system = request.body["system"]
+                .as_str()
+                .or_else(|| request.body["messages"][0]["content"].as_str())
+                .unwrap();
+            assert!(system.ends_with(prompt), "{action:?} sent {system}");
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_fill_in_the_middle_through_the_complete_operation() {
+        use crate::clients::mock_server::{MockResponse, MockServer};
+        use crate::clients::providers::Provider;
+        use crate::config::DataDir;
+
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            &serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": "a + b" }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 2 }
+            }),
+        )])
+        .await;
+        DataDir::set_save_history(false);
+        std::env::set_var(Provider::Mistral.key_var(), "test-key");
+
+        let action = AiCodeAction::FillInMiddle;
+        let answer = execute_operation(
+            action,
+            Some(format!(
+                "fn add(a: i32, b: i32) -> i32 {{ {DEFAULT_FIM_MARKER} }}"
+            )),
+            action.placement(),
+        )
+        .await;
+
+        assert_eq!(
+            answer.as_deref(),
+            Some("fn add(a: i32, b: i32) -> i32 { a + b }")
+        );
+        let body = &server.requests()[0].body;
+        assert_eq!(body["prompt"], "fn add(a: i32, b: i32) -> i32 { ");
+        assert_eq!(body["suffix"], " }");
+    }
+
+    #[tokio::test]
+    async fn shares_one_send_between_identical_requests() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let service = service();
+        let backend = service.inner();
+        let sends = AtomicUsize::new(0);
+
+        let key = RequestKey::new("fix", &uri(), Some("fn main() {}"), None);
+        let send = || async {
+            sends.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Some("fixed".to_string())
+        };
+
+        let results = tokio::join!(
+            backend.coalesce(key.clone(), send),
+            backend.coalesce(key.clone(), send)
+        );
+
+        assert_eq!(results.0.as_deref(), Some("fixed"));
+        assert_eq!(results.1.as_deref(), Some("fixed"));
+        assert_eq!(sends.load(Ordering::SeqCst), 1);
+        assert!(backend.state.lock().await.in_flight.is_empty());
+
+        // Once the request is answered, the next one is sent again.
+        backend.coalesce(key, send).await;
+        assert_eq!(sends.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn clears_diagnostics_when_the_document_closes() {
+        let service = service();
+        let backend = service.inner();
+        let suggestion = Diagnostic {
+            message: "Handle the error".to_string(),
+            ..Diagnostic::default()
+        };
+        backend.publish_diagnostics(&uri(), vec![suggestion]).await;
+        assert_eq!(backend.state.lock().await.diagnostics[&uri()].len(), 1);
+
+        backend
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri: uri() },
+            })
+            .await;
+
+        let state = backend.state.lock().await;
+        assert!(state.diagnostics.is_empty());
+        assert!(state.sources.is_empty());
+    }
+
+    #[tokio::test]
+    async fn completes_only_on_invocation_or_configured_triggers() {
+        let service = service();
+        let backend = service.inner();
+        backend
+            .initialize(InitializeParams {
+                initialization_options: Some(serde_json::json!({
+                    "completionTriggerCharacters": ["("]
+                })),
+                ..InitializeParams::default()
+            })
+            .await
+            .unwrap();
+
+        let typed = |character: &str| CompletionContext {
+            trigger_kind: CompletionTriggerKind::TRIGGER_CHARACTER,
+            trigger_character: Some(character.to_string()),
+        };
+        let invoked = CompletionContext {
+            trigger_kind: CompletionTriggerKind::INVOKED,
+            trigger_character: None,
+        };
+
+        let state = backend.state.lock().await;
+        assert!(state.should_complete(Some(&typed("("))));
+        assert!(!state.should_complete(Some(&typed("."))));
+        assert!(state.should_complete(Some(&invoked)));
+        assert!(state.should_complete(None));
+    }
+
+    fn folder(name: &str) -> WorkspaceFolder {
+        WorkspaceFolder {
+            uri: Url::parse(&format!("file:///work/{name}/")).unwrap(),
+            name: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn initializes_without_a_root_uri() {
+        let service = service();
+        let backend = service.inner();
+
+        backend
+            .initialize(InitializeParams::default())
+            .await
+            .unwrap();
+        let cwd = Url::from_directory_path(std::env::current_dir().unwrap()).unwrap();
+        assert_eq!(backend.state.lock().await.root_uri, Some(cwd));
+
+        backend
+            .initialize(InitializeParams {
+                workspace_folders: Some(vec![folder("api"), folder("web")]),
+                ..InitializeParams::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(backend.state.lock().await.root_uri, Some(folder("api").uri));
+    }
+
+    #[tokio::test]
+    async fn tracks_added_and_removed_workspace_folders() {
+        let service = service();
+        let backend = service.inner();
+        backend
+            .initialize(InitializeParams {
+                workspace_folders: Some(vec![folder("api"), folder("web")]),
+                ..InitializeParams::default()
+            })
+            .await
+            .unwrap();
+
+        backend
+            .did_change_workspace_folders(DidChangeWorkspaceFoldersParams {
+                event: WorkspaceFoldersChangeEvent {
+                    added: vec![folder("docs"), folder("api")],
+                    removed: vec![folder("web")],
+                },
+            })
+            .await;
+
+        let state = backend.state.lock().await;
+        assert_eq!(state.workspace_folders, [folder("api"), folder("docs")]);
+        assert_eq!(
+            state.resolve_path("docs/guide.md"),
+            PathBuf::from("/work/docs/guide.md")
+        );
+    }
+}
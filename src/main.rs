@@ -2,23 +2,35 @@ mod cli;
 mod clients;
 mod config;
 mod errors;
+mod logger;
 mod lsp;
 mod models;
 mod operations;
 mod prompts;
 
-use std::error::Error;
+use std::{error::Error, time::Duration};
 
 use crate::cli::CmdRunner;
+use clap::builder::FalseyValueParser;
 use clap::Parser;
 use clap::Subcommand;
+use cli::bench;
 use cli::chat;
 use cli::complete;
+use cli::config as config_cmd;
+use cli::count_tokens;
+use cli::history;
 use cli::instruct;
 use cli::lsp as lsp_cmd;
 use cli::pipe;
 use cli::prompt_generator;
-use config::DataDir;
+use cli::raw_request;
+use cli::serve;
+use cli::suggest;
+use cli::watch;
+use config::{Config, DataDir};
+use log::warn;
+use logger::ConsoleMode;
 
 /// coding assistant commands
 #[derive(Parser)]
@@ -26,6 +38,30 @@ use config::DataDir;
 struct CodingAssistant {
     #[command(subcommand)]
     pub cmd: CodingAssistantCmd,
+
+    /// Sets where log records are echoed besides the log file
+    #[arg(long, global = true, value_enum, env = "ACAI_LOG_CONSOLE")]
+    pub log_console: Option<ConsoleMode>,
+
+    /// Prints only the result, without log records or status messages
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Sends context without redacting secrets
+    #[arg(long, global = true)]
+    pub no_redact: bool,
+
+    /// Does not save prompts and responses to the history
+    #[arg(long, global = true, env = "ACAI_NO_SAVE", value_parser = FalseyValueParser::new())]
+    pub no_save: bool,
+
+    /// Fills options not given on the command line from a profile in the config file
+    #[arg(long, global = true, env = "ACAI_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Sets how many seconds each provider request may take, including the response
+    #[arg(long, global = true, value_name = "SECONDS", value_parser = parse_timeout)]
+    pub timeout: Option<Duration>,
 }
 
 #[derive(Clone, Subcommand)]
@@ -36,6 +72,14 @@ enum CodingAssistantCmd {
     Complete(complete::Cmd),
     PromptGenerator(prompt_generator::Cmd),
     Lsp(lsp_cmd::Cmd),
+    Bench(bench::Cmd),
+    Serve(serve::Cmd),
+    Suggest(suggest::Cmd),
+    CountTokens(count_tokens::Cmd),
+    Watch(watch::Cmd),
+    History(history::Cmd),
+    Config(config_cmd::Cmd),
+    RawRequest(raw_request::Cmd),
 }
 
 #[tokio::main]
@@ -44,7 +88,49 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let args = CodingAssistant::parse();
 
-    match args.cmd {
+    logger::init(console_mode(&args))?;
+
+    cli::set_quiet(args.quiet);
+
+    prompts::set_redaction(!args.no_redact);
+    DataDir::set_save_history(!args.no_save);
+
+    if let Some(timeout) = args.timeout {
+        clients::set_timeout(timeout);
+    }
+
+    // Read once here; everything after shares it through `Config::get`.
+    let config = Config::get();
+
+    if let Err(e) = run(args.cmd, args.profile.as_deref(), config).await {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run(
+    mut cmd: CodingAssistantCmd,
+    profile: Option<&str>,
+    config: &Config,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Checked before the profile is applied so only explicit flags count.
+    warn_on_combined_sampling(&cmd);
+
+    if let Some(name) = profile {
+        let profile = config.profile(name)?;
+
+        match &mut cmd {
+            CodingAssistantCmd::Chat(chat_cmd) => chat_cmd.apply_profile(profile),
+            CodingAssistantCmd::Pipe(pipe_cmd) => pipe_cmd.apply_profile(profile),
+            CodingAssistantCmd::Instruct(instruct_cmd) => instruct_cmd.apply_profile(profile),
+            CodingAssistantCmd::Suggest(suggest_cmd) => suggest_cmd.apply_profile(profile),
+            _ => return Err("--profile is not supported by this command".into()),
+        }
+    }
+
+    match cmd {
         CodingAssistantCmd::Chat(chat_cmd) => chat_cmd.run().await?,
         CodingAssistantCmd::Pipe(pipe_cmd) => pipe_cmd.run().await?,
         CodingAssistantCmd::Instruct(instruct_cmd) => instruct_cmd.run().await?,
@@ -53,7 +139,140 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             prompt_generator_cmd.run().await?;
         }
         CodingAssistantCmd::Lsp(lsp_cmd) => lsp_cmd.run().await?,
+        CodingAssistantCmd::Bench(bench_cmd) => bench_cmd.run().await?,
+        CodingAssistantCmd::Serve(serve_cmd) => serve_cmd.run().await?,
+        CodingAssistantCmd::Suggest(suggest_cmd) => suggest_cmd.run().await?,
+        CodingAssistantCmd::CountTokens(count_tokens_cmd) => count_tokens_cmd.run().await?,
+        CodingAssistantCmd::Watch(watch_cmd) => watch_cmd.run().await?,
+        CodingAssistantCmd::History(history_cmd) => history_cmd.run().await?,
+        CodingAssistantCmd::Config(config_cmd) => config_cmd.run().await?,
+        CodingAssistantCmd::RawRequest(raw_request_cmd) => raw_request_cmd.run().await?,
     };
 
     Ok(())
 }
+
+/// Returns where log records are echoed: nowhere with `--quiet`, otherwise
+/// where `--log-console` says, defaulting to stderr except for commands that
+/// use the console for data.
+fn console_mode(args: &CodingAssistant) -> ConsoleMode {
+    if args.quiet {
+        return ConsoleMode::Quiet;
+    }
+
+    args.log_console.unwrap_or(match args.cmd {
+        CodingAssistantCmd::Pipe(_) | CodingAssistantCmd::Serve(_) => ConsoleMode::Quiet,
+        _ => ConsoleMode::Stderr,
+    })
+}
+
+/// Warns when both `--temperature` and `--top-p` are given.
+///
+/// Both values are sent as given and providers apply them together, which
+/// usually narrows sampling more than intended.
+fn warn_on_combined_sampling(cmd: &CodingAssistantCmd) {
+    if combines_sampling(cmd) {
+        warn!(
+            "Both --temperature and --top-p are set; providers apply both, which can make sampling behave unexpectedly. Set only one."
+        );
+    }
+}
+
+fn combines_sampling(cmd: &CodingAssistantCmd) -> bool {
+    let (temperature, top_p) = match cmd {
+        CodingAssistantCmd::Chat(cmd) => (cmd.temperature, cmd.top_p),
+        CodingAssistantCmd::Instruct(cmd) => (cmd.temperature, cmd.top_p),
+        CodingAssistantCmd::Pipe(cmd) => (cmd.temperature, cmd.top_p),
+        CodingAssistantCmd::Complete(cmd) => (cmd.temperature, cmd.top_p),
+        CodingAssistantCmd::Bench(cmd) => (cmd.temperature, cmd.top_p),
+        CodingAssistantCmd::Suggest(cmd) => (cmd.temperature, cmd.top_p),
+        CodingAssistantCmd::Watch(cmd) => (cmd.temperature, cmd.top_p),
+        _ => return false,
+    };
+
+    temperature.is_some() && top_p.is_some()
+}
+
+/// Parses the `--timeout` value: a positive number of seconds, e.g. `30` or `2.5`.
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid timeout `{value}`, expected seconds"))?;
+
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(format!("the timeout must be positive, got `{value}`"));
+    }
+
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("the timeout is too large, got `{value}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> CodingAssistant {
+        CodingAssistant::parse_from(std::iter::once("acai").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn parses_whole_and_fractional_seconds() {
+        assert_eq!(parse_timeout("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_timeout("2.5"), Ok(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn rejects_non_positive_and_invalid_timeouts() {
+        for value in ["0", "-1", "abc", "", "inf", "NaN", "1e300"] {
+            assert!(parse_timeout(value).is_err(), "{value} was accepted");
+        }
+    }
+
+    #[test]
+    fn quiet_silences_the_console_for_every_command() {
+        for args in [
+            ["--quiet", "instruct"].as_slice(),
+            &["instruct", "-q", "--log-console", "stderr"],
+            &["pipe"],
+        ] {
+            assert_eq!(console_mode(&parse(args)), ConsoleMode::Quiet, "{args:?}");
+        }
+
+        assert_eq!(console_mode(&parse(&["instruct"])), ConsoleMode::Stderr);
+        assert_eq!(
+            console_mode(&parse(&["pipe", "--log-console", "stderr"])),
+            ConsoleMode::Stderr
+        );
+    }
+
+    #[tokio::test]
+    async fn the_timeout_flag_reaches_the_http_client() {
+        // Accepts the connection but never answers.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _held = listener.accept().await;
+            std::future::pending::<()>().await;
+        });
+
+        let client = clients::build_client(parse(&["--timeout", "0.2", "instruct"]).timeout);
+
+        let started = std::time::Instant::now();
+        let error = client.get(url).send().await.unwrap_err();
+        assert!(error.is_timeout(), "{error}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn flags_only_combined_temperature_and_top_p() {
+        assert!(combines_sampling(
+            &parse(&["instruct", "--temperature", "0.2", "--top-p", "0.9"]).cmd
+        ));
+        assert!(!combines_sampling(
+            &parse(&["instruct", "--temperature", "0.2"]).cmd
+        ));
+        assert!(!combines_sampling(
+            &parse(&["instruct", "--top-p", "0.9"]).cmd
+        ));
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use super::Role;
+use super::{cached_tokens, Role, Tokenizer};
 
 /// A structure representing a message.
 ///
@@ -12,10 +12,68 @@ pub struct Message {
     pub role: Role,
     /// The content of the message as a string.
     pub content: String,
+    /// The reasoning or thinking the model returned alongside the answer.
+    ///
+    /// This is metadata only: it is never sent back to a provider or saved.
+    #[serde(default, alias = "reasoning_content", skip_serializing)]
+    pub reasoning: Option<String>,
+    /// The id of the tool call a `Role::Tool` message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Why the model stopped, as reported by the provider, e.g. `stop` or `length`.
+    ///
+    /// Like `reasoning`, this is metadata that is never sent or saved.
+    #[serde(skip)]
+    pub finish_reason: Option<String>,
+    /// The settings an assistant turn was generated with. Saved with the
+    /// history so results can be reproduced, but never sent to a provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<TurnMetadata>,
+}
+
+/// The model and sampling settings used for one assistant turn.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TurnMetadata {
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    pub max_tokens: u32,
+}
+
+impl Message {
+    /// Returns the token count of the content with `tokenizer`.
+    ///
+    /// Counts are cached by content, so recounting an unchanged history is cheap
+    /// and editing the content yields a fresh count.
+    pub fn tokens(&self, tokenizer: Tokenizer) -> u32 {
+        cached_tokens(&self.content, tokenizer)
+    }
+
+    /// Returns whether the answer was cut off by the output token limit.
+    pub fn is_truncated(&self) -> bool {
+        matches!(
+            self.finish_reason.as_deref(),
+            Some("length" | "max_tokens" | "MAX_TOKENS")
+        )
+    }
 }
 
 /// Define a trait named `IntoMessage`.
 pub trait IntoMessage {
     /// Define a method `into_message` that returns an optional `Message`.
     fn into_message(self) -> Option<Message>;
+
+    /// Returns every candidate in the response, in order.
+    ///
+    /// Providers that only ever return one candidate can rely on the default,
+    /// which wraps `into_message`.
+    #[allow(dead_code)]
+    fn into_messages(self) -> Vec<Message>
+    where
+        Self: Sized,
+    {
+        self.into_message().into_iter().collect()
+    }
 }
@@ -1,5 +1,11 @@
 mod messages;
 mod roles;
+mod tokenizer;
+mod tokens;
+mod usage;
 
 pub use messages::*;
 pub use roles::*;
+pub use tokenizer::*;
+pub use tokens::*;
+pub use usage::*;
@@ -14,4 +14,6 @@ pub enum Role {
     Assistant,
     /// Represents a user.
     User,
+    /// Represents the result of a tool call, answering the call in `tool_call_id`.
+    Tool,
 }
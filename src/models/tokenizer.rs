@@ -0,0 +1,108 @@
+use std::sync::OnceLock;
+
+use tiktoken_rs::{cl100k_base, o200k_base, CoreBPE};
+
+use crate::clients::providers::Model;
+
+use super::estimate_tokens;
+
+/// How the tokens of a model are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tokenizer {
+    /// OpenAI's `cl100k_base`, used by GPT-4 Turbo and GPT-3.5.
+    Cl100k,
+    /// OpenAI's `o200k_base`, used by GPT-4o and the o-series.
+    O200k,
+    /// Anthropic has not published the Claude 3 tokenizer, so Claude counts
+    /// are approximated with `cl100k_base`, which tracks it closely for code.
+    Claude,
+    /// Models without a public tokenizer, such as Gemini and Mistral, are
+    /// counted at four characters per token, as Google's docs suggest.
+    Approximate,
+}
+
+impl Tokenizer {
+    /// Counts the tokens in `text`.
+    pub fn count(self, text: &str) -> u32 {
+        let tokens = match self {
+            Self::Cl100k | Self::Claude => cl100k().encode_ordinary(text).len(),
+            Self::O200k => o200k().encode_ordinary(text).len(),
+            Self::Approximate => return estimate_tokens(text),
+        };
+
+        u32::try_from(tokens).unwrap_or(u32::MAX)
+    }
+}
+
+/// Returns the tokenizer used to count tokens for `model`.
+///
+/// Custom model ids are matched by prefix and fall back to the approximation.
+pub fn tokenizer_for(model: &Model) -> Tokenizer {
+    match model {
+        Model::GPT4o => Tokenizer::O200k,
+        Model::GPT4Turbo | Model::GPT3Turbo => Tokenizer::Cl100k,
+        Model::Claude3_5Sonnet
+        | Model::Claude3Opus
+        | Model::Claude3Sonnet
+        | Model::Claude3Haiku => Tokenizer::Claude,
+        Model::Codestral | Model::GeminiFlash | Model::GeminiPro => Tokenizer::Approximate,
+        Model::Custom(id) => {
+            if ["gpt-4o", "chatgpt-4o", "o1", "o3"]
+                .iter()
+                .any(|prefix| id.starts_with(prefix))
+            {
+                Tokenizer::O200k
+            } else if id.starts_with("gpt-") {
+                Tokenizer::Cl100k
+            } else if id.starts_with("claude-") {
+                Tokenizer::Claude
+            } else {
+                Tokenizer::Approximate
+            }
+        }
+    }
+}
+
+fn cl100k() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| cl100k_base().expect("cl100k_base is bundled"))
+}
+
+fn o200k() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| o200k_base().expect("o200k_base is bundled"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_each_model_family_tokenizer() {
+        assert_eq!(tokenizer_for(&Model::GPT4o), Tokenizer::O200k);
+        assert_eq!(tokenizer_for(&Model::GPT4Turbo), Tokenizer::Cl100k);
+        assert_eq!(tokenizer_for(&Model::Claude3Haiku), Tokenizer::Claude);
+        assert_eq!(tokenizer_for(&Model::GeminiPro), Tokenizer::Approximate);
+        assert_eq!(
+            tokenizer_for(&Model::Custom("o1-mini".to_string())),
+            Tokenizer::O200k
+        );
+        assert_eq!(
+            tokenizer_for(&Model::Custom(
+                "accounts/fireworks/models/llama".to_string()
+            )),
+            Tokenizer::Approximate
+        );
+    }
+
+    #[test]
+    fn counts_the_same_text_differently_per_model() {
+        let text = "// Возвращает сумму двух чисел.\nfn сумма(a: i32, b: i32) -> i32 { a + b }\n";
+
+        let gpt4o = tokenizer_for(&Model::GPT4o).count(text);
+        let gpt4_turbo = tokenizer_for(&Model::GPT4Turbo).count(text);
+
+        assert!(gpt4o > 0 && gpt4_turbo > 0);
+        assert_ne!(gpt4o, gpt4_turbo);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Mutex, OnceLock},
+};
+
+use super::Tokenizer;
+
+/// Estimates the number of tokens in `text`.
+///
+/// Uses the common approximation of four characters per token. Prefer
+/// [`Tokenizer::count`] when the model is known; this is its fallback for
+/// models without a public tokenizer.
+pub fn estimate_tokens(text: &str) -> u32 {
+    let chars = u32::try_from(text.chars().count()).unwrap_or(u32::MAX);
+    chars.div_ceil(4)
+}
+
+/// The number of counts kept by `cached_tokens` before the cache is cleared.
+const TOKEN_CACHE_LIMIT: usize = 4096;
+
+/// The token counts remembered by `cached_tokens`, keyed by `cache_key`.
+fn token_cache() -> &'static Mutex<HashMap<u64, u32>> {
+    static CACHE: OnceLock<Mutex<HashMap<u64, u32>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn cache_key(text: &str, tokenizer: Tokenizer) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tokenizer.hash(&mut hasher);
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Counts the tokens in `text` with `tokenizer`, remembering the count by a
+/// hash of both so unchanged text is only counted once.
+pub fn cached_tokens(text: &str, tokenizer: Tokenizer) -> u32 {
+    let key = cache_key(text, tokenizer);
+
+    let mut cache = token_cache()
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+    if let Some(tokens) = cache.get(&key) {
+        return *tokens;
+    }
+
+    if cache.len() >= TOKEN_CACHE_LIMIT {
+        cache.clear();
+    }
+
+    let tokens = tokenizer.count(text);
+    cache.insert(key, tokens);
+    tokens
+}
+
+/// Marks where `truncate_middle` removed text.
+pub const ELISION_MARKER: &str = "\n[... truncated ...]\n";
+
+/// Shortens `text` to roughly `budget` tokens, as counted by `tokenizer`, by
+/// keeping its head and tail.
+///
+/// The removed middle is replaced by `ELISION_MARKER`. Cuts are moved to the
+/// nearest line break inside the kept text so lines stay whole where possible.
+pub fn truncate_middle(text: &str, budget: u32, tokenizer: Tokenizer) -> String {
+    let tokens = tokenizer.count(text);
+    if tokens <= budget {
+        return text.to_string();
+    }
+
+    // Keep the share of characters the budget allows at this text's own
+    // characters-per-token ratio.
+    let chars = text.chars().count();
+    let keep = usize::try_from(u64::from(budget) * chars as u64 / u64::from(tokens))
+        .unwrap_or(usize::MAX)
+        .saturating_sub(ELISION_MARKER.chars().count());
+    let head_chars = keep / 2;
+    let tail_chars = keep - head_chars;
+
+    let head_end = text
+        .char_indices()
+        .nth(head_chars)
+        .map_or(text.len(), |(index, _)| index);
+    let tail_start = if tail_chars == 0 {
+        text.len()
+    } else {
+        text.char_indices()
+            .nth_back(tail_chars - 1)
+            .map_or(0, |(index, _)| index)
+    };
+
+    let head = &text[..head_end];
+    let head = head.rfind('\n').map_or(head, |index| &head[..index]);
+    let tail = &text[tail_start..];
+    let tail = tail.find('\n').map_or(tail, |index| &tail[index + 1..]);
+
+    format!("{head}{ELISION_MARKER}{tail}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_head_and_tail_of_a_large_text() {
+        let text: String = (1..=1000).map(|line| format!("line {line}\n")).collect();
+
+        let truncated = truncate_middle(&text, 100, Tokenizer::Approximate);
+
+        let (head, tail) = truncated.split_once(ELISION_MARKER).unwrap();
+        assert!(head.starts_with("line 1\nline 2\n"));
+        assert!(tail.ends_with("line 999\nline 1000\n"));
+        assert!(!truncated.contains("line 500\n"));
+        // Cuts fall on line breaks, so no line is split.
+        assert!(head
+            .lines()
+            .chain(tail.lines())
+            .all(|line| line.starts_with("line ")));
+        assert!(Tokenizer::Approximate.count(&truncated) <= 100);
+    }
+
+    #[test]
+    fn leaves_text_within_the_budget_alone() {
+        let text = "fn main() {}\n";
+
+        assert_eq!(truncate_middle(text, 100, Tokenizer::Cl100k), text);
+    }
+
+    #[test]
+    fn reuses_cached_counts_until_the_text_changes() {
+        let text = "fn cached() -> u32 { 42 }";
+        let counted = cached_tokens(text, Tokenizer::Cl100k);
+        assert_eq!(counted, Tokenizer::Cl100k.count(text));
+
+        // A planted count is returned as is, so the text is not tokenized again.
+        token_cache()
+            .lock()
+            .unwrap()
+            .insert(cache_key(text, Tokenizer::Cl100k), 1_000);
+        assert_eq!(cached_tokens(text, Tokenizer::Cl100k), 1_000);
+
+        let edited = "fn cached() -> u32 { 43 }";
+        assert_eq!(
+            cached_tokens(edited, Tokenizer::Cl100k),
+            Tokenizer::Cl100k.count(edited)
+        );
+        assert_eq!(
+            cached_tokens(text, Tokenizer::Approximate),
+            Tokenizer::Approximate.count(text)
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use std::ops::AddAssign;
+
+use serde::{Deserialize, Serialize};
+
+/// Token counts reported by a provider for a request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The number of tokens in the prompt, including history and system prompt.
+    pub input_tokens: u32,
+    /// The number of tokens generated in the response.
+    pub output_tokens: u32,
+}
+
+impl Usage {
+    pub const fn total_tokens(&self) -> u32 {
+        self.input_tokens + self.output_tokens
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.input_tokens += rhs.input_tokens;
+        self.output_tokens += rhs.output_tokens;
+    }
+}
+
+/// Define a trait named `IntoUsage`.
+pub trait IntoUsage {
+    /// Define a method `usage` that returns the token usage of a response, if reported.
+    fn usage(&self) -> Option<Usage>;
+}
@@ -0,0 +1,190 @@
+use std::{error::Error, future::Future, sync::Arc};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use super::{Complete, Document, Fix, Instruct, Operation, OperationArgs, Optimize, Suggest};
+
+/// The operations that can be chosen by name.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationKind {
+    Instruct,
+    Document,
+    Fix,
+    Optimize,
+    Suggest,
+    Complete,
+}
+
+impl OperationKind {
+    /// Sends the operation and returns the content of its answer.
+    pub async fn send(
+        self,
+        args: OperationArgs,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        match self {
+            Self::Instruct => send_operation(Instruct(args)).await,
+            Self::Document => send_operation(Document(args)).await,
+            Self::Fix => send_operation(Fix(args)).await,
+            Self::Optimize => send_operation(Optimize(args)).await,
+            Self::Suggest => send_operation(Suggest(args)).await,
+            Self::Complete => send_operation(Complete(args)).await,
+        }
+    }
+
+    /// Returns whether the operation can generate several candidates and keep
+    /// the best, so `best_of` is ignored for the others.
+    pub const fn supports_best_of(self) -> bool {
+        matches!(self, Self::Optimize | Self::Suggest)
+    }
+}
+
+/// One item of a batch.
+#[derive(Debug, Clone)]
+pub struct OperationRequest {
+    pub kind: OperationKind,
+    pub args: OperationArgs,
+}
+
+/// The answer to a batch item, or `None` if the model returned nothing.
+pub type OperationResult = Option<String>;
+
+/// Runs the items, each holding a permit of `limiter` while it is sent, and
+/// returns the results in input order. A failed item does not stop the rest
+/// of the batch.
+///
+/// The limiter belongs to the caller, so batches and single requests sharing
+/// it never exceed its permits together.
+pub async fn run_batch(
+    items: Vec<OperationRequest>,
+    limiter: Arc<Semaphore>,
+) -> Vec<Result<OperationResult, Box<dyn Error + Send + Sync>>> {
+    run_limited(items, limiter, |item| item.kind.send(item.args)).await
+}
+
+async fn run_limited<T, F, Fut>(
+    items: Vec<T>,
+    limiter: Arc<Semaphore>,
+    send: F,
+) -> Vec<Result<OperationResult, Box<dyn Error + Send + Sync>>>
+where
+    T: Send + 'static,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<OperationResult, Box<dyn Error + Send + Sync>>> + Send + 'static,
+{
+    let handles: Vec<_> = items
+        .into_iter()
+        .map(|item| {
+            let limiter = Arc::clone(&limiter);
+            let request = send(item);
+            tokio::spawn(async move {
+                let _permit = limiter.acquire_owned().await?;
+                request.await
+            })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        results.push(handle.await.unwrap_or_else(|e| Err(e.into())));
+    }
+    results
+}
+
+async fn send_operation<O: Operation>(
+    operation: O,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    Ok(operation.send().await?.map(|msg| msg.content))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        clients::{mock_server::MockServer, providers::Provider},
+        config::DataDir,
+    };
+
+    #[tokio::test]
+    async fn sends_nothing_for_blank_inputs() {
+        DataDir::set_save_history(false);
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let server = MockServer::start(Vec::new()).await;
+        let blank = || OperationArgs {
+            model: Some("gpt-4o".to_string()),
+            prompt: Some("  ".to_string()),
+            context: Some("\n\t\n".to_string()),
+            ..OperationArgs::default()
+        };
+
+        for kind in OperationKind::value_variants() {
+            assert_eq!(kind.send(blank()).await.unwrap(), None, "{kind:?}");
+        }
+        assert!(Suggest(blank()).send_structured().await.unwrap().is_none());
+        assert!(Suggest(blank())
+            .send_structured_streaming(|_| {})
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_input_order_and_isolates_failures() {
+        let items = vec![
+            (30, Ok("first")),
+            (0, Err("second failed")),
+            (10, Ok("third")),
+        ];
+
+        let results = run_limited(
+            items,
+            Arc::new(Semaphore::new(3)),
+            |(delay, result)| async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                result
+                    .map(|text| Some(text.to_string()))
+                    .map_err(Into::into)
+            },
+        )
+        .await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().as_deref(), Some("first"));
+        assert_eq!(
+            results[1].as_ref().unwrap_err().to_string(),
+            "second failed"
+        );
+        assert_eq!(results[2].as_ref().unwrap().as_deref(), Some("third"));
+    }
+
+    #[tokio::test]
+    async fn never_exceeds_the_limiter() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let results = run_limited(vec![(); 6], Arc::new(Semaphore::new(2)), |()| {
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(None)
+            }
+        })
+        .await;
+
+        assert_eq!(results.len(), 6);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+}
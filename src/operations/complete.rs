@@ -1,72 +1,238 @@
-use std::error::Error;
+use std::{error::Error, time::Instant};
 
 use crate::{
     clients::{
-        providers::{Model, Provider, ProviderModel},
+        providers::{Model, Provider, ProviderModel, DEFAULT_MAX_TOKENS},
         CompletionClient,
     },
-    config::DataDir,
+    config::{Config, DataDir},
+    models::{tokenizer_for, Message, Usage},
 };
 
-pub struct Complete {
-    /// Sets the model to use
-    pub model: Option<String>,
+use super::{non_blank, Estimate, Operation, OperationArgs, Stats};
 
-    /// Sets the temperature value
-    pub temperature: Option<f32>,
+/// The marker separating the prefix from the suffix when no other marker is configured.
+pub const DEFAULT_FIM_MARKER: &str = "<|fim|>";
 
-    /// Sets the max tokens value
-    pub max_tokens: Option<u32>,
+/// Fills in the gap that the marker leaves in the context.
+///
+/// The text before and after the marker is sent to a fill-in-the-middle model;
+/// the prompt is ignored.
+pub struct Complete(pub OperationArgs);
 
-    /// Sets the top-p value
-    pub top_p: Option<f32>,
+/// The answer to a fill-in-the-middle request.
+struct Fill {
+    prefix: String,
+    suffix: Option<String>,
+    infill: Option<Message>,
+    model: Model,
+    usage: Usage,
+}
+
+impl Operation for Complete {
+    const NAME: &'static str = "complete";
+
+    /// Fill-in-the-middle requests have no system prompt.
+    const DEFAULT_PROMPT: &'static str = "";
 
-    /// Sets the prompt
-    pub prompt: Option<String>,
+    fn args(&self) -> &OperationArgs {
+        &self.0
+    }
+
+    async fn send(&self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        Ok(self.send_with_stats().await?.map(|(response, _)| response))
+    }
 
-    /// Sets the context
-    pub context: Option<String>,
+    /// Completes the context, answering with the generated text filled in.
+    async fn send_with_stats(
+        &self,
+    ) -> Result<Option<(Message, Stats)>, Box<dyn Error + Send + Sync>> {
+        let started = Instant::now();
+        let Some(fill) = self.request(false).await? else {
+            return Ok(None);
+        };
+        let latency = started.elapsed();
+
+        let Some(infill) = fill.infill else {
+            return Ok(None);
+        };
+
+        let stats = Stats {
+            cost: Config::get()
+                .pricing(&fill.model)
+                .estimate_cost(&fill.usage),
+            model: fill.model,
+            usage: fill.usage,
+            latency,
+        };
+        let content = format!(
+            "{}{}{}",
+            fill.prefix,
+            infill.content,
+            fill.suffix.unwrap_or_default()
+        );
+
+        Ok(Some((Message { content, ..infill }, stats)))
+    }
+
+    fn estimate(&self) -> Result<Estimate, Box<dyn Error + Send + Sync>> {
+        let model = self.model_provider().model;
+
+        let prompt_tokens = non_blank(self.0.context.as_deref())
+            .map_or(0, |context| tokenizer_for(&model).count(context));
+        let max_tokens = self
+            .0
+            .max_tokens
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+            .resolve(&model, prompt_tokens);
+
+        let cost = Config::get().pricing(&model).estimate_cost(&Usage {
+            input_tokens: prompt_tokens,
+            output_tokens: max_tokens,
+        });
+
+        Ok(Estimate {
+            model,
+            prompt_tokens,
+            max_tokens,
+            cost,
+        })
+    }
 }
 
 impl Complete {
-    pub async fn send(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
-        let model_provider = ProviderModel::get_or_default(
-            self.model.clone().unwrap_or_default().as_str(),
+    /// Completes the context and returns only the generated text.
+    #[allow(dead_code)]
+    pub async fn send_infill(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .request(false)
+            .await?
+            .and_then(|fill| fill.infill)
+            .map(|msg| msg.content))
+    }
+
+    /// Completes the context and returns only the first line of generated text,
+    /// without waiting for the rest of the answer.
+    pub async fn send_first_line(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .request(true)
+            .await?
+            .and_then(|fill| fill.infill)
+            .map(|msg| msg.content))
+    }
+
+    /// Returns the model to send to, Codestral unless another is set.
+    fn model_provider(&self) -> ProviderModel {
+        ProviderModel::get_or_default(
+            self.0.model.as_deref().unwrap_or_default(),
             (Provider::Mistral, Model::Codestral),
-        );
+        )
+    }
 
-        let mut client = CompletionClient::new(model_provider.provider, model_provider.model)
-            .temperature(self.temperature)
-            .max_tokens(self.max_tokens);
+    /// Sends the split context, returning the prefix, suffix and generated text.
+    async fn request(
+        &self,
+        first_line: bool,
+    ) -> Result<Option<Fill>, Box<dyn Error + Send + Sync>> {
+        let Some(prompt) = non_blank(self.0.context.as_deref()) else {
+            return Ok(None);
+        };
 
-        let prompt = &self.context;
+        let model_provider = self.model_provider();
 
-        if let Some(prompt) = prompt {
-            let (prefix, suffix) = prompt.find("<fim>").map_or_else(
-                || (prompt.to_string(), None),
-                |index| {
-                    let (before, after) = prompt.split_at(index);
-                    (before.to_string(), Some(after[5..].to_string()))
-                },
-            );
+        let mut client =
+            CompletionClient::new(model_provider.provider, model_provider.model.clone())
+                .temperature(self.0.temperature)
+                .max_tokens(self.0.max_tokens)
+                .first_line(first_line);
+
+        let fim_marker = self
+            .0
+            .fim_marker
+            .clone()
+            .or_else(|| Config::get().fim_marker.clone())
+            .unwrap_or_else(|| DEFAULT_FIM_MARKER.to_string());
 
-            let response = client.send_message(&prefix, suffix.clone()).await?;
+        let (prefix, suffix) = split_fim(prompt, &fim_marker);
 
-            let result = if let Some(msg) = response {
-                if let Some(sfx) = suffix {
-                    Some(format!("{}{}{}", prefix, msg.content, sfx))
-                } else {
-                    Some(format!("{}{}", prefix, msg.content))
-                }
-            } else {
-                None
-            };
+        let infill = client.send_message(&prefix, suffix.clone()).await?;
+
+        DataDir::new().save_messages(&client.get_message_history());
+
+        Ok(Some(Fill {
+            prefix,
+            suffix,
+            infill,
+            model: model_provider.model,
+            usage: client.get_usage(),
+        }))
+    }
+}
+
+/// Splits `prompt` into the text before and after the first `marker`.
+///
+/// `str::split_once` slices on the match boundaries, so markers of any length,
+/// including multibyte ones, never split a character. An empty marker matches
+/// nothing and leaves the whole prompt as the prefix.
+fn split_fim(prompt: &str, marker: &str) -> (String, Option<String>) {
+    if marker.is_empty() {
+        return (prompt.to_string(), None);
+    }
+
+    prompt.split_once(marker).map_or_else(
+        || (prompt.to_string(), None),
+        |(before, after)| (before.to_string(), Some(after.to_string())),
+    )
+}
 
-            DataDir::new().save_messages(&client.get_message_history());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::mock_server::MockServer;
 
-            Ok(result)
-        } else {
-            Ok(None)
+    #[test]
+    fn splits_on_a_custom_multi_character_marker() {
+        assert_eq!(
+            split_fim("fn add(a: i32) -> i32 {<<CURSOR>>}", "<<CURSOR>>"),
+            ("fn add(a: i32) -> i32 {".to_string(), Some("}".to_string()))
+        );
+        // The default marker is ordinary text once another one is configured.
+        assert_eq!(
+            split_fim("let marker = \"<|fim|>\";", "<<CURSOR>>"),
+            ("let marker = \"<|fim|>\";".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn splits_markers_of_any_length() {
+        for marker in ["|", "<FILL>", "█", "<|fim_middle|>"] {
+            let prompt = format!("before{marker}after{marker}end");
+            assert_eq!(
+                split_fim(&prompt, marker),
+                ("before".to_string(), Some(format!("after{marker}end"))),
+                "{marker}"
+            );
         }
+
+        assert_eq!(split_fim("no gap", "<FILL>"), ("no gap".to_string(), None));
+        assert_eq!(split_fim("a<FILL>", ""), ("a<FILL>".to_string(), None));
+        assert_eq!(
+            split_fim("<FILL>", "<FILL>"),
+            (String::new(), Some(String::new()))
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_nothing_for_a_blank_context() {
+        let server = MockServer::start(Vec::new()).await;
+        let complete = Complete(OperationArgs {
+            model: Some("gpt-4o".to_string()),
+            context: Some(" \n ".to_string()),
+            ..OperationArgs::default()
+        });
+
+        assert!(complete.send().await.unwrap().is_none());
+        assert_eq!(complete.send_first_line().await.unwrap(), None);
+        assert!(server.requests().is_empty());
     }
 }
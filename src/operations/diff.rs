@@ -0,0 +1,63 @@
+use diffy::Patch;
+
+/// Asks the model for a diff instead of the full rewritten code.
+pub const DIFF_PROMPT: &str = "Respond with only a unified diff against the provided code, with `--- original` and `+++ modified` headers and `@@` hunk headers. Include three lines of unchanged context around each change.";
+
+/// Applies a unified diff returned by a model to `original`.
+///
+/// A surrounding Markdown code fence is ignored. Fails with the number of the
+/// first hunk that does not apply cleanly.
+pub fn apply_diff(original: &str, diff: &str) -> Result<String, String> {
+    let trimmed = diff.trim();
+    let diff = trimmed
+        .strip_prefix("```diff")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|body| body.strip_suffix("```"))
+        .unwrap_or(trimmed);
+    let diff = format!("{}\n", diff.trim_matches('\n'));
+
+    let patch = Patch::from_str(&diff).map_err(|e| format!("the model's diff is invalid: {e}"))?;
+    if patch.hunks().is_empty() {
+        return Err("the model's answer contains no diff hunks".into());
+    }
+
+    diffy::apply(original, &patch)
+        .map_err(|e| format!("the model's diff does not apply cleanly: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "fn main() {\n    let x = 1;\n    println!(\"{x}\");\n}\n";
+
+    const DIFF: &str = "--- original\n+++ modified\n@@ -1,4 +1,4 @@\n fn main() {\n-    let x = 1;\n+    let x = 2;\n     println!(\"{x}\");\n }\n";
+
+    #[test]
+    fn applies_a_unified_diff() {
+        assert_eq!(
+            apply_diff(ORIGINAL, DIFF).unwrap(),
+            "fn main() {\n    let x = 2;\n    println!(\"{x}\");\n}\n"
+        );
+    }
+
+    #[test]
+    fn ignores_a_surrounding_code_fence() {
+        let fenced = format!("```diff\n{DIFF}```");
+        assert_eq!(
+            apply_diff(ORIGINAL, &fenced).unwrap(),
+            apply_diff(ORIGINAL, DIFF).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_answers_without_hunks() {
+        assert!(apply_diff(ORIGINAL, "The code looks fine.").is_err());
+    }
+
+    #[test]
+    fn rejects_diffs_that_do_not_apply() {
+        let original = ORIGINAL.replace("let x = 1", "let y = 1");
+        assert!(apply_diff(&original, DIFF).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use std::process::Stdio;
+
+use log::warn;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::config::Config;
+
+/// Runs the formatter configured for `extension` over generated code.
+///
+/// The output is returned unchanged when no formatter is configured, or when
+/// the formatter is missing or fails, e.g. on a fragment it cannot parse.
+pub async fn format_output(output: String, extension: Option<&str>) -> String {
+    let Some(command) = extension.and_then(|ext| Config::get().formatters.get(ext).cloned()) else {
+        return output;
+    };
+
+    match run_formatter(&command, &output).await {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            warn!("Skipping formatter `{command}`: {e}");
+            output
+        }
+    }
+}
+
+async fn run_formatter(command: &str, input: &str) -> Result<String, String> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().ok_or("empty command")?;
+
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // A formatter that exits without reading its input closes the pipe;
+        // its exit status explains why.
+        match stdin.write_all(input.as_bytes()).await {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.to_string()),
+            _ => {}
+        }
+    }
+
+    let result = child.wait_with_output().await.map_err(|e| e.to_string())?;
+
+    if !result.status.success() {
+        return Err(format!(
+            "exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+
+    String::from_utf8(result.stdout).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_the_formatter_over_the_output() {
+        assert_eq!(
+            run_formatter("tr a-z A-Z", "fn main() {}\n").await,
+            Ok("FN MAIN() {}\n".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reports_missing_and_failing_formatters() {
+        assert!(run_formatter("acai-no-such-formatter", "x").await.is_err());
+
+        let failed = run_formatter("sh -c false", "x").await.unwrap_err();
+        assert!(failed.starts_with("exited with"), "{failed}");
+    }
+
+    #[tokio::test]
+    async fn leaves_output_without_a_formatter_unchanged() {
+        let output = "fn main() {}".to_string();
+
+        assert_eq!(format_output(output.clone(), None).await, output);
+        assert_eq!(
+            format_output(output.clone(), Some("acai-unconfigured")).await,
+            output
+        );
+    }
+}
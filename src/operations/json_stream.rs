@@ -0,0 +1,125 @@
+/// Picks the elements of a JSON array out of a streamed answer as each one
+/// completes, so they can be shown before the whole answer has arrived.
+///
+/// Text before the opening `[`, such as a Markdown code fence, is skipped and
+/// anything after the closing `]` is ignored.
+#[derive(Debug, Default)]
+pub struct JsonArrayStream {
+    buffer: String,
+    /// How far `buffer` has been scanned.
+    scanned: usize,
+    /// The nesting depth, where 1 is inside the top-level array.
+    depth: u32,
+    in_string: bool,
+    escaped: bool,
+    /// Where the element being read starts in `buffer`.
+    element_start: Option<usize>,
+    done: bool,
+}
+
+impl JsonArrayStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a chunk of the answer, returning the text of each element it completes.
+    pub fn push(&mut self, chunk: &str) -> Vec<String> {
+        let mut elements = Vec::new();
+        if self.done {
+            return elements;
+        }
+
+        self.buffer.push_str(chunk);
+
+        while self.scanned < self.buffer.len() && !self.done {
+            let index = self.scanned;
+            let byte = self.buffer.as_bytes()[index];
+            self.scanned += 1;
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                }
+                continue;
+            }
+
+            if self.depth == 0 {
+                if byte == b'[' {
+                    self.depth = 1;
+                }
+                continue;
+            }
+
+            if self.depth == 1
+                && self.element_start.is_none()
+                && !byte.is_ascii_whitespace()
+                && !matches!(byte, b',' | b']')
+            {
+                self.element_start = Some(index);
+            }
+
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    match self.depth {
+                        // An object or array element has closed.
+                        1 => elements.extend(self.take_element(index + 1)),
+                        // The top-level array has closed after a scalar element.
+                        0 => {
+                            elements.extend(self.take_element(index));
+                            self.done = true;
+                        }
+                        _ => {}
+                    }
+                }
+                b',' if self.depth == 1 => elements.extend(self.take_element(index)),
+                _ => {}
+            }
+        }
+
+        elements
+    }
+
+    fn take_element(&mut self, end: usize) -> Option<String> {
+        let start = self.element_start.take()?;
+        Some(self.buffer[start..end].trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yields_each_element_as_it_completes() {
+        let mut stream = JsonArrayStream::new();
+
+        assert!(stream
+            .push("```json\n[{\"line\": 3, \"text\": \"a, [b]")
+            .is_empty());
+        assert_eq!(
+            stream.push("\"}, {\"line\""),
+            vec![r#"{"line": 3, "text": "a, [b]"}"#]
+        );
+        assert!(stream.push(": 7, \"text\": \"say \\\"}\\\"\"").is_empty());
+        assert_eq!(
+            stream.push("}]\n```"),
+            vec![r#"{"line": 7, "text": "say \"}\""}"#]
+        );
+        assert!(stream.push("[{\"ignored\": true}]").is_empty());
+    }
+
+    #[test]
+    fn yields_scalar_elements() {
+        let mut stream = JsonArrayStream::new();
+
+        assert_eq!(stream.push("[1, 2"), vec!["1"]);
+        assert_eq!(stream.push(", 3]"), vec!["2", "3"]);
+    }
+}
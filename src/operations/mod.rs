@@ -1,13 +1,25 @@
+mod batch;
 mod complete;
+mod diff;
 mod document;
 mod fix;
+mod format;
 mod instruct;
+mod json_stream;
+mod operation;
 mod optimize;
 mod suggest;
+mod title;
 
+pub use batch::*;
 pub use complete::*;
+pub use diff::*;
 pub use document::*;
 pub use fix::*;
+pub use format::*;
 pub use instruct::*;
+pub use json_stream::*;
+pub use operation::*;
 pub use optimize::*;
 pub use suggest::*;
+pub use title::*;
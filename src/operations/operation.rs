@@ -0,0 +1,836 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    clients::{
+        providers::{MaxTokens, Model, Provider, ReasoningEffort, DEFAULT_MAX_TOKENS},
+        ChatCompletionClient, RequestError,
+    },
+    config::{Config, DataDir},
+    models::{tokenizer_for, Message, Role, Usage},
+    prompts::{provider_system_prompt, PromptBuilder},
+};
+
+use super::JsonArrayStream;
+
+/// The options shared by every operation.
+#[derive(Debug, Clone, Default)]
+pub struct OperationArgs {
+    /// Sets the model to use
+    pub model: Option<String>,
+
+    /// Sets the temperature value
+    pub temperature: Option<f32>,
+
+    /// Sets the max tokens value
+    pub max_tokens: Option<MaxTokens>,
+
+    /// Sets the top-p value
+    pub top_p: Option<f32>,
+
+    /// Sets the prompt
+    pub prompt: Option<String>,
+
+    /// Sets the context
+    pub context: Option<String>,
+
+    /// Sets the marker splitting the context into the prefix and suffix of a
+    /// fill-in-the-middle request
+    pub fim_marker: Option<String>,
+
+    /// Sets the models to try in order when the primary model's provider fails
+    pub fallback: Vec<String>,
+
+    /// Sets the reasoning effort for models that support it
+    pub reasoning: Option<ReasoningEffort>,
+
+    /// Sets how many times to ask the model to continue an answer cut off by
+    /// the token limit; 0 disables it
+    pub max_continuations: u32,
+
+    /// Sets how many candidate answers to generate before asking the model to
+    /// pick the best; 0 or 1 sends a single request
+    pub best_of: u32,
+}
+
+/// The temperature candidates are sampled at when none is set, since
+/// candidates sampled at the default of 0 would all be the same.
+const BEST_OF_TEMPERATURE: f32 = 0.8;
+
+/// The system prompt of the request picking the best candidate.
+const SELECTION_SYSTEM_PROMPT: &str = "You compare candidate answers and name the best one.";
+
+/// The most tokens the answer naming the best candidate may use.
+const SELECTION_MAX_TOKENS: u32 = 16;
+
+/// The follow-up sent when an answer is cut off by the token limit.
+const CONTINUE_PROMPT: &str = "Your answer was cut off. Continue exactly where you left off, without repeating anything or adding any commentary.";
+
+impl OperationArgs {
+    /// Sends the prompt and context as a single user message with the given system prompt.
+    pub async fn send(
+        &self,
+        system_prompt: &str,
+    ) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .send_with_stats(system_prompt)
+            .await?
+            .map(|(response, _)| response))
+    }
+
+    /// Sends the request like [`Self::send`], also returning the model that
+    /// answered, the tokens used, the latency and the estimated cost.
+    pub async fn send_with_stats(
+        &self,
+        system_prompt: &str,
+    ) -> Result<Option<(Message, Stats)>, Box<dyn Error + Send + Sync>> {
+        let Some(msg) = self.user_message()? else {
+            return Ok(None);
+        };
+
+        let started = Instant::now();
+        let (mut client, response, other_usage) = if self.best_of > 1 {
+            self.send_best_of(system_prompt, msg).await?
+        } else {
+            let (client, response) = self.send_with_fallback(system_prompt, msg).await?;
+            (client, response, Usage::default())
+        };
+        let response = match response {
+            Some(answer) => Some(self.continue_truncated(&mut client, answer).await?),
+            None => None,
+        };
+        let latency = started.elapsed();
+
+        DataDir::new().save_messages(&client.get_message_history());
+
+        let mut usage = client.get_usage();
+        usage += other_usage;
+        let stats = Stats {
+            model: client.model().clone(),
+            usage,
+            latency,
+            cost: Config::get().pricing(client.model()).estimate_cost(&usage),
+        };
+
+        Ok(response.map(|response| (response, stats)))
+    }
+
+    /// Sends the request and parses the answer as JSON.
+    ///
+    /// If the answer is not valid JSON the model is asked once to correct it.
+    /// When the corrected answer cannot be parsed either, its raw content is
+    /// returned as the error.
+    pub async fn send_json<T: DeserializeOwned>(
+        &self,
+        system_prompt: &str,
+    ) -> Result<Option<Result<T, String>>, Box<dyn Error + Send + Sync>> {
+        let Some(msg) = self.user_message()? else {
+            return Ok(None);
+        };
+
+        let (mut client, response) = self.send_with_fallback(system_prompt, msg).await?;
+
+        let Some(answer) = response else {
+            return Ok(None);
+        };
+        let answer = self.continue_truncated(&mut client, answer).await?;
+
+        let parsed = match parse_json::<T>(&answer.content) {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                warn!("Model returned invalid JSON, asking it to correct the output: {e}");
+
+                let retry = Message {
+                    role: Role::User,
+                    content: format!(
+                        "Your previous output was invalid JSON: {e}. Fix it and respond with only the corrected JSON."
+                    ),
+                    reasoning: None,
+                    tool_call_id: None,
+                    finish_reason: None,
+                    metadata: None,
+                };
+
+                match client.send_message(retry).await? {
+                    Some(corrected) => {
+                        parse_json::<T>(&corrected.content).map_err(|_| corrected.content)
+                    }
+                    None => Err(answer.content),
+                }
+            }
+        };
+
+        DataDir::new().save_messages(&client.get_message_history());
+
+        Ok(Some(parsed))
+    }
+
+    /// Streams the request and parses the answer as a JSON array, calling
+    /// `on_item` with each element as soon as it is complete.
+    ///
+    /// Unlike [`Self::send_json`], fallback models are not tried and an invalid
+    /// answer is not corrected, since some of its elements may already have
+    /// been handled. The raw content is returned as the error instead.
+    pub async fn send_json_array_streaming<T, F>(
+        &self,
+        system_prompt: &str,
+        mut on_item: F,
+    ) -> Result<Option<Result<Vec<T>, String>>, Box<dyn Error + Send + Sync>>
+    where
+        T: DeserializeOwned,
+        F: FnMut(T),
+    {
+        let Some(msg) = self.user_message()? else {
+            return Ok(None);
+        };
+
+        let mut client = self.client(&self.model.clone().unwrap_or_default(), system_prompt);
+        let mut stream = JsonArrayStream::new();
+
+        let response = client
+            .send_message_streaming(
+                msg,
+                |delta| {
+                    for element in stream.push(delta) {
+                        match serde_json::from_str::<T>(&element) {
+                            Ok(item) => on_item(item),
+                            Err(e) => debug!("Skipping unparsable element {element}: {e}"),
+                        }
+                    }
+                },
+                std::future::pending(),
+            )
+            .await?;
+
+        DataDir::new().save_messages(&client.get_message_history());
+
+        Ok(response.map(|answer| parse_json(&answer.content).map_err(|_| answer.content)))
+    }
+
+    /// Estimates the size and worst-case cost of sending the request, without sending it.
+    pub fn estimate(&self, system_prompt: &str) -> Result<Estimate, Box<dyn Error + Send + Sync>> {
+        let config = Config::get();
+        let model = config.resolve_model(self.model.as_deref()).model;
+
+        let tokenizer = tokenizer_for(&model);
+        let prompt_tokens = tokenizer.count(system_prompt)
+            + self.user_message()?.map_or(0, |msg| msg.tokens(tokenizer));
+        let max_tokens = self
+            .max_tokens
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+            .resolve(&model, prompt_tokens);
+
+        let cost = config.pricing(&model).estimate_cost(&Usage {
+            input_tokens: prompt_tokens,
+            output_tokens: max_tokens,
+        });
+
+        Ok(Estimate {
+            model,
+            prompt_tokens,
+            max_tokens,
+            cost,
+        })
+    }
+
+    /// Sends the message to the primary model, failing over to each fallback
+    /// model in turn when a provider returns a retryable error.
+    async fn send_with_fallback(
+        &self,
+        system_prompt: &str,
+        msg: Message,
+    ) -> Result<(ChatCompletionClient, Option<Message>), Box<dyn Error + Send + Sync>> {
+        self.send_with_fallback_to(&Config::get().long_context_models, system_prompt, msg)
+            .await
+    }
+
+    /// Sends the message like [`Self::send_with_fallback`], retrying a prompt
+    /// too long for a model once with its entry in `long_context_models`.
+    async fn send_with_fallback_to(
+        &self,
+        long_context_models: &HashMap<String, String>,
+        system_prompt: &str,
+        msg: Message,
+    ) -> Result<(ChatCompletionClient, Option<Message>), Box<dyn Error + Send + Sync>> {
+        let mut models = std::iter::once(self.model.clone().unwrap_or_default())
+            .chain(self.fallback.iter().cloned())
+            .peekable();
+
+        while let Some(model) = models.next() {
+            let mut client = self.client(&model, system_prompt);
+
+            let mut result = client.send_message(msg.clone()).await;
+
+            let too_long = result.as_ref().is_err_and(|e| {
+                e.downcast_ref::<RequestError>()
+                    .is_some_and(RequestError::is_context_length_exceeded)
+            });
+
+            if too_long {
+                let larger = long_context_models
+                    .get(&model)
+                    .or_else(|| long_context_models.get(client.model().id()));
+
+                if let Some(larger) = larger {
+                    warn!("Prompt exceeds the context window of {model}, retrying with {larger}");
+                    client = self.client(larger, system_prompt);
+                    result = client.send_message(msg.clone()).await;
+                }
+            }
+
+            match result {
+                Ok(response) => return Ok((client, response)),
+                Err(e) => {
+                    let retryable = e
+                        .downcast_ref::<RequestError>()
+                        .is_some_and(RequestError::is_retryable);
+
+                    match models.peek() {
+                        Some(next) if retryable => {
+                            warn!("Request to {model} failed, falling back to {next}: {e}");
+                        }
+                        _ => return Err(e),
+                    }
+                }
+            }
+        }
+
+        unreachable!("the primary model is always tried")
+    }
+
+    /// Generates `best_of` candidate answers, then asks the model in one more
+    /// request which is best.
+    ///
+    /// Returns the client that generated the winner, the winner, and the usage
+    /// of every other request. Falls back to the first candidate when the
+    /// choice cannot be understood.
+    async fn send_best_of(
+        &self,
+        system_prompt: &str,
+        msg: Message,
+    ) -> Result<(ChatCompletionClient, Option<Message>, Usage), Box<dyn Error + Send + Sync>> {
+        let sampling = Self {
+            temperature: self.temperature.or(Some(BEST_OF_TEMPERATURE)),
+            ..self.clone()
+        };
+
+        let mut candidates = Vec::new();
+        for _ in 0..self.best_of {
+            let (client, response) = sampling
+                .send_with_fallback(system_prompt, msg.clone())
+                .await?;
+            if let Some(answer) = response {
+                candidates.push((client, answer));
+            }
+        }
+
+        let mut usage = Usage::default();
+        if candidates.len() < 2 {
+            return Ok(match candidates.pop() {
+                Some((client, answer)) => (client, Some(answer), usage),
+                None => (
+                    self.client(&self.model.clone().unwrap_or_default(), system_prompt),
+                    None,
+                    usage,
+                ),
+            });
+        }
+
+        let texts: Vec<String> = candidates
+            .iter()
+            .map(|(_, answer)| answer.content.clone())
+            .collect();
+        let selection = Message {
+            role: Role::User,
+            content: PromptBuilder::new().build_selection(&msg.content, &texts)?,
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        };
+
+        let mut judge = self
+            .client(
+                &self.model.clone().unwrap_or_default(),
+                SELECTION_SYSTEM_PROMPT,
+            )
+            .temperature(Some(0.0))
+            .max_tokens(Some(MaxTokens::Fixed(SELECTION_MAX_TOKENS)));
+        let choice = judge.send_message(selection).await?;
+        usage += judge.get_usage();
+
+        let winner = choice
+            .as_ref()
+            .and_then(|choice| parse_choice(&choice.content, candidates.len()))
+            .unwrap_or_else(|| {
+                warn!("Could not tell which candidate was picked, using the first");
+                0
+            });
+        debug!("Picked candidate {} of {}", winner + 1, candidates.len());
+
+        let (client, answer) = candidates.swap_remove(winner);
+        for (other, _) in &candidates {
+            usage += other.get_usage();
+        }
+
+        Ok((client, Some(answer), usage))
+    }
+
+    /// Asks the model to continue `answer` while it is cut off by the token
+    /// limit, up to `max_continuations` times, and returns the joined answer.
+    ///
+    /// Stops early when a continuation comes back empty, so a model that
+    /// keeps hitting the limit without producing text cannot loop.
+    async fn continue_truncated(
+        &self,
+        client: &mut ChatCompletionClient,
+        mut answer: Message,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        for _ in 0..self.max_continuations {
+            if !answer.is_truncated() {
+                break;
+            }
+
+            debug!("Answer was truncated, asking the model to continue");
+
+            let follow_up = Message {
+                role: Role::User,
+                content: CONTINUE_PROMPT.to_string(),
+                reasoning: None,
+                tool_call_id: None,
+                finish_reason: None,
+                metadata: None,
+            };
+
+            let Some(continuation) = client.send_message(follow_up).await? else {
+                break;
+            };
+            if continuation.content.is_empty() {
+                break;
+            }
+
+            answer.content.push_str(&continuation.content);
+            answer.finish_reason = continuation.finish_reason;
+        }
+
+        if answer.is_truncated() {
+            warn!(
+                "Answer is still truncated after {} continuations",
+                self.max_continuations
+            );
+        }
+
+        Ok(answer)
+    }
+
+    fn client(&self, model: &str, system_prompt: &str) -> ChatCompletionClient {
+        let config = Config::get();
+        let model_provider = config.resolve_model(Some(model));
+
+        ChatCompletionClient::new(model_provider.provider, model_provider.model, system_prompt)
+            .temperature(self.temperature)
+            .top_p(self.top_p)
+            .max_tokens(self.max_tokens)
+            .reasoning_effort(self.reasoning)
+            .anthropic_beta(config.anthropic_beta.clone())
+    }
+
+    /// Returns the provider of the primary model, which picks provider-specific prompts.
+    pub fn provider(&self) -> Provider {
+        Config::get().resolve_model(self.model.as_deref()).provider
+    }
+
+    /// Builds the user message from the prompt and context, if either has
+    /// any non-whitespace text. Every send method returns `Ok(None)` without a
+    /// request when this does.
+    fn user_message(&self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        let mut data = HashMap::new();
+
+        if let Some(prompt) = non_blank(self.prompt.as_deref()) {
+            data.insert("prompt".to_string(), prompt.to_string());
+        }
+        if let Some(context) = non_blank(self.context.as_deref()) {
+            data.insert("context".to_string(), context.to_string());
+        }
+
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Message {
+            role: Role::User,
+            content: PromptBuilder::new().build(&data)?,
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        }))
+    }
+}
+
+/// Returns the index of the candidate named by the first number in `answer`,
+/// if it is between 1 and `count`.
+fn parse_choice(answer: &str, count: usize) -> Option<usize> {
+    let digits: String = answer
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(char::is_ascii_digit)
+        .collect();
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .filter(|number| (1..=count).contains(number))
+        .map(|number| number - 1)
+}
+
+/// Returns `value` unless it is missing or only whitespace.
+pub fn non_blank(value: Option<&str>) -> Option<&str> {
+    value.filter(|value| !value.trim().is_empty())
+}
+
+/// The estimated size and cost of a request that has not been sent.
+#[derive(Debug)]
+pub struct Estimate {
+    pub model: Model,
+    pub prompt_tokens: u32,
+    pub max_tokens: u32,
+    /// The cost in US dollars if the answer uses all of `max_tokens`.
+    pub cost: f64,
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Model: {}", self.model)?;
+        writeln!(f, "Prompt tokens: {}", self.prompt_tokens)?;
+        writeln!(f, "Max tokens: {}", self.max_tokens)?;
+        write!(f, "Estimated cost: up to ${:.4}", self.cost)
+    }
+}
+
+/// The usage and cost of a request that has been answered.
+#[derive(Debug)]
+pub struct Stats {
+    pub model: Model,
+    pub usage: Usage,
+    pub latency: Duration,
+    /// The estimated cost in US dollars.
+    pub cost: f64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} in, {} out, {:.1}s (${:.4})",
+            self.model,
+            self.usage.input_tokens,
+            self.usage.output_tokens,
+            self.latency.as_secs_f64(),
+            self.cost
+        )
+    }
+}
+
+/// Parses a model answer as JSON, tolerating a surrounding Markdown code fence.
+pub fn parse_json<T: DeserializeOwned>(content: &str) -> Result<T, serde_json::Error> {
+    let trimmed = content.trim();
+    let json = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|body| body.strip_suffix("```"))
+        .unwrap_or(trimmed);
+
+    serde_json::from_str(json)
+}
+
+/// An operation defined by its system prompt, sent as a chat unless it says otherwise.
+pub trait Operation {
+    /// The name of the prompt override file, e.g. `fix` for `prompts/fix.md`.
+    const NAME: &'static str;
+
+    /// The system prompt used when no override exists.
+    const DEFAULT_PROMPT: &'static str;
+
+    fn args(&self) -> &OperationArgs;
+
+    /// Returns the system prompt, preferring a user override.
+    fn system_prompt(&self) -> String {
+        provider_system_prompt(Self::NAME, self.args().provider(), Self::DEFAULT_PROMPT)
+    }
+
+    async fn send(&self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
+        self.args().send(&self.system_prompt()).await
+    }
+
+    /// Sends the request, also returning its usage and cost.
+    async fn send_with_stats(
+        &self,
+    ) -> Result<Option<(Message, Stats)>, Box<dyn Error + Send + Sync>> {
+        self.args().send_with_stats(&self.system_prompt()).await
+    }
+
+    /// Estimates the request this operation would send.
+    fn estimate(&self) -> Result<Estimate, Box<dyn Error + Send + Sync>> {
+        self.args().estimate(&self.system_prompt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        clients::mock_server::{MockResponse, MockServer},
+        operations::Instruct,
+    };
+
+    fn answer(content: &str) -> MockResponse {
+        MockResponse::json(
+            200,
+            &json!({
+                "choices": [{ "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+            }),
+        )
+    }
+
+    fn anthropic_answer(text: &str) -> MockResponse {
+        MockResponse::json(
+            200,
+            &json!({
+                "role": "assistant",
+                "content": [{ "type": "text", "text": text }],
+                "usage": { "input_tokens": 10, "output_tokens": 5 },
+                "stop_reason": "end_turn"
+            }),
+        )
+    }
+
+    fn args(model: &str) -> OperationArgs {
+        DataDir::set_save_history(false);
+        for provider in [Provider::OpenAI, Provider::Anthropic] {
+            std::env::set_var(provider.key_var(), "test-key");
+        }
+        OperationArgs {
+            model: Some(model.to_string()),
+            prompt: Some("List the numbers.".to_string()),
+            ..OperationArgs::default()
+        }
+    }
+
+    fn last_message(body: &Value) -> &str {
+        body["messages"].as_array().unwrap().last().unwrap()["content"]
+            .as_str()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn saves_no_session_when_history_is_off() {
+        let data_dir = std::env::temp_dir().join(format!("acai-no-save-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        DataDir::set_mock(&data_dir);
+        let _server = MockServer::start(vec![answer("One."), answer("Two.")]).await;
+        let instruct = Instruct(args("gpt-4o"));
+
+        DataDir::set_save_history(true);
+        instruct.send().await.unwrap().unwrap();
+        assert_eq!(DataDir::new().sessions().len(), 1);
+
+        DataDir::set_save_history(false);
+        instruct.send().await.unwrap().unwrap();
+        assert_eq!(DataDir::new().sessions().len(), 1);
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrects_invalid_json_once() {
+        let server = MockServer::start(vec![answer("[1, 2,"), answer("[1, 2, 3]")]).await;
+
+        let parsed = args("gpt-4o")
+            .send_json::<Vec<u32>>("Answer in JSON.")
+            .await
+            .unwrap();
+
+        assert_eq!(parsed, Some(Ok(vec![1, 2, 3])));
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(
+            last_message(&requests[1].body).starts_with("Your previous output was invalid JSON")
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_provider() {
+        let server = MockServer::start(vec![
+            MockResponse::json(503, &json!({ "error": "overloaded" })),
+            anthropic_answer("From Claude."),
+        ])
+        .await;
+
+        let answer = OperationArgs {
+            fallback: vec!["sonnet".to_string()],
+            ..args("gpt-4o")
+        }
+        .send("Be brief.")
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(answer.content, "From Claude.");
+        let requests = server.requests();
+        assert_eq!(requests[0].body["model"], "gpt-4o");
+        assert_eq!(requests[1].body["system"], "Be brief.");
+    }
+
+    #[tokio::test]
+    async fn retries_a_prompt_too_long_with_the_larger_model() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                400,
+                &json!({ "error": {
+                    "code": "context_length_exceeded",
+                    "message": "This model's maximum context length is 128000 tokens."
+                } }),
+            ),
+            answer("From the larger model."),
+        ])
+        .await;
+        let args = args("gpt-4o");
+        let long_context_models =
+            HashMap::from([("gpt-4o".to_string(), "gpt-4-turbo-preview".to_string())]);
+
+        let (_, answer) = args
+            .send_with_fallback_to(
+                &long_context_models,
+                "Be brief.",
+                args.user_message().unwrap().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(answer.unwrap().content, "From the larger model.");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body["model"], "gpt-4o");
+        assert_eq!(requests[1].body["model"], "gpt-4-turbo-preview");
+    }
+
+    fn truncated(content: &str) -> MockResponse {
+        MockResponse::json(
+            200,
+            &json!({
+                "choices": [{ "message": { "role": "assistant", "content": content }, "finish_reason": "length" }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn concatenates_the_continuations_of_a_truncated_answer() {
+        let server = MockServer::start(vec![
+            truncated("fn main() {\n"),
+            truncated("    run();\n"),
+            answer("}\n"),
+        ])
+        .await;
+
+        let full = OperationArgs {
+            max_continuations: 3,
+            ..args("gpt-4o")
+        }
+        .send("Be brief.")
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(full.content, "fn main() {\n    run();\n}\n");
+        assert!(!full.is_truncated());
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(last_message(&requests[2].body), CONTINUE_PROMPT);
+    }
+
+    #[tokio::test]
+    async fn stops_continuing_after_the_limit() {
+        let server = MockServer::start(vec![truncated("a"), truncated("b"), truncated("c")]).await;
+
+        let partial = OperationArgs {
+            max_continuations: 1,
+            ..args("gpt-4o")
+        }
+        .send("Be brief.")
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(partial.content, "ab");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sends_n_candidates_and_one_selection() {
+        let server = MockServer::start(vec![
+            answer("let a = 1;"),
+            answer("let b = 2;"),
+            answer("let c = 3;"),
+            answer("Candidate 2 is best."),
+        ])
+        .await;
+
+        let winner = OperationArgs {
+            best_of: 3,
+            ..args("gpt-4o")
+        }
+        .send("Be brief.")
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(winner.content, "let b = 2;");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        let selection = &requests[3].body;
+        assert_eq!(selection["messages"][0]["content"], SELECTION_SYSTEM_PROMPT);
+        assert_eq!(selection["max_tokens"], SELECTION_MAX_TOKENS);
+        assert!(last_message(selection).contains("let c = 3;"));
+    }
+
+    #[test]
+    fn estimates_tokens_and_cost_without_sending() {
+        let args = OperationArgs {
+            max_tokens: Some(MaxTokens::Fixed(1000)),
+            ..args("gpt-4o")
+        };
+
+        let estimate = args.estimate("Be brief.").unwrap();
+
+        let tokenizer = tokenizer_for(&Model::GPT4o);
+        let prompt_tokens =
+            tokenizer.count("Be brief.") + args.user_message().unwrap().unwrap().tokens(tokenizer);
+        assert_eq!(estimate.prompt_tokens, prompt_tokens);
+        assert_eq!(estimate.max_tokens, 1000);
+        let cost = Config::get().pricing(&Model::GPT4o).estimate_cost(&Usage {
+            input_tokens: prompt_tokens,
+            output_tokens: 1000,
+        });
+        assert!(cost > 0.0);
+
+        assert_eq!(
+            estimate.to_string(),
+            format!(
+                "Model: {}\nPrompt tokens: {prompt_tokens}\nMax tokens: 1000\nEstimated cost: up to ${cost:.4}",
+                Model::GPT4o
+            )
+        );
+    }
+}
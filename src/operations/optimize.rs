@@ -1,78 +1,13 @@
-use std::{collections::HashMap, error::Error};
+use super::{Operation, OperationArgs};
 
-use crate::{
-    clients::{
-        providers::{Model, Provider, ProviderModel},
-        ChatCompletionClient,
-    },
-    config::DataDir,
-    models::{Message, Role},
-    prompts::PromptBuilder,
-};
+pub struct Optimize(pub OperationArgs);
 
-pub struct Optimize {
-    /// Sets the model to use
-    pub model: Option<String>,
+impl Operation for Optimize {
+    const NAME: &'static str = "optimize";
 
-    /// Sets the temperature value
-    pub temperature: Option<f32>,
+    const DEFAULT_PROMPT: &'static str = "Review the code snippet below and suggest optimizations to improve performance. Focus on efficiency, speed, and resource usage while maintaining the original functionality. The answer should be in plain text without Markdown formatting. Provide only the optimized code.";
 
-    /// Sets the max tokens value
-    pub max_tokens: Option<u32>,
-
-    /// Sets the top-p value
-    pub top_p: Option<f32>,
-
-    /// Sets the prompt
-    pub prompt: Option<String>,
-
-    /// Sets the context
-    pub context: Option<String>,
-}
-
-const DEFAULT_PROMPT: &str = "Review the code snippet below and suggest optimizations to improve performance. Focus on efficiency, speed, and resource usage while maintaining the original functionality. The answer should be in plain text without Markdown formatting. Provide only the optimized code.";
-
-impl Optimize {
-    pub async fn send(&self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
-        let system_prompt = DEFAULT_PROMPT;
-
-        let model_provider = ProviderModel::get_or_default(
-            self.model.clone().unwrap_or_default().as_str(),
-            (Provider::OpenAI, Model::GPT4o),
-        );
-
-        let provider = model_provider.provider;
-        let model = model_provider.model;
-
-        let mut client = ChatCompletionClient::new(provider, model, system_prompt)
-            .temperature(self.temperature)
-            .top_p(self.top_p)
-            .max_tokens(self.max_tokens);
-
-        let prompt_builder = PromptBuilder::new()?;
-
-        let mut data = HashMap::new();
-
-        if let Some(prompt) = &self.prompt {
-            data.insert("prompt".to_string(), prompt.to_string());
-        }
-        if let Some(context) = &self.context {
-            data.insert("context".to_string(), context.to_string());
-        }
-
-        if !data.is_empty() {
-            let msg = Message {
-                role: Role::User,
-                content: prompt_builder.build(&data)?,
-            };
-
-            let response = client.send_message(msg).await?;
-
-            DataDir::new().save_messages(&client.get_message_history());
-
-            return Ok(response);
-        }
-
-        Ok(None)
+    fn args(&self) -> &OperationArgs {
+        &self.0
     }
 }
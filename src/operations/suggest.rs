@@ -1,78 +1,108 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 
-use crate::{
-    clients::{
-        providers::{Model, Provider, ProviderModel},
-        ChatCompletionClient,
-    },
-    config::DataDir,
-    models::{Message, Role},
-    prompts::PromptBuilder,
-};
+use serde::{Deserialize, Serialize};
 
-pub struct Suggest {
-    /// Sets the model to use
-    pub model: Option<String>,
+use crate::prompts::provider_system_prompt;
 
-    /// Sets the temperature value
-    pub temperature: Option<f32>,
+use super::{Operation, OperationArgs};
 
-    /// Sets the max tokens value
-    pub max_tokens: Option<u32>,
-
-    /// Sets the top-p value
-    pub top_p: Option<f32>,
-
-    /// Sets the prompt
-    pub prompt: Option<String>,
-
-    /// Sets the context
-    pub context: Option<String>,
-}
+pub struct Suggest(pub OperationArgs);
 
 const DEFAULT_PROMPT: &str = "Add todo comments to the provided code snippet. The todo comments are to be added to parts of the code that can be improved or fixed. Each the todo comment should explain what needs to be done and give a short explanation of why the change should be made. The answer should be in plain text without Markdown formatting.";
 
-impl Suggest {
-    pub async fn send(&self) -> Result<Option<Message>, Box<dyn Error + Send + Sync>> {
-        let system_prompt = DEFAULT_PROMPT;
-
-        let model_provider = ProviderModel::get_or_default(
-            self.model.clone().unwrap_or_default().as_str(),
-            (Provider::OpenAI, Model::GPT4o),
-        );
-
-        let provider = model_provider.provider;
-        let model = model_provider.model;
-
-        let mut client = ChatCompletionClient::new(provider, model, system_prompt)
-            .temperature(self.temperature)
-            .top_p(self.top_p)
-            .max_tokens(self.max_tokens);
+const STRUCTURED_PROMPT: &str = "Review the provided code snippet and find the parts of the code that can be improved or fixed. Respond with only a JSON array and no other text. Each element of the array is an object with a `line` field holding the 1-based line number in the snippet and a `comment` field explaining what needs to be done and giving a short explanation of why the change should be made.";
 
-        let prompt_builder = PromptBuilder::new()?;
+/// A single TODO suggested for a line of the snippet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TodoSuggestion {
+    /// The 1-based line number within the snippet.
+    pub line: u32,
+    pub comment: String,
+}
 
-        let mut data = HashMap::new();
+/// The result of a structured suggest request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Suggestions {
+    /// The model returned a parsable list of suggestions.
+    Structured(Vec<TodoSuggestion>),
+    /// The model's answer could not be parsed and is returned as-is.
+    Text(String),
+}
 
-        if let Some(prompt) = &self.prompt {
-            data.insert("prompt".to_string(), prompt.to_string());
-        }
-        if let Some(context) = &self.context {
-            data.insert("context".to_string(), context.to_string());
-        }
+impl Operation for Suggest {
+    const NAME: &'static str = "suggest";
 
-        if !data.is_empty() {
-            let msg = Message {
-                role: Role::User,
-                content: prompt_builder.build(&data)?,
-            };
+    const DEFAULT_PROMPT: &'static str = DEFAULT_PROMPT;
 
-            let response = client.send_message(msg).await?;
+    fn args(&self) -> &OperationArgs {
+        &self.0
+    }
+}
 
-            DataDir::new().save_messages(&client.get_message_history());
+impl Suggest {
+    /// Requests the suggestions as data instead of an annotated snippet.
+    pub async fn send_structured(
+        &self,
+    ) -> Result<Option<Suggestions>, Box<dyn Error + Send + Sync>> {
+        let response = self
+            .0
+            .send_json::<Vec<TodoSuggestion>>(&provider_system_prompt(
+                "suggest-structured",
+                self.0.provider(),
+                STRUCTURED_PROMPT,
+            ))
+            .await?;
+
+        Ok(response.map(|parsed| parsed.map_or_else(Suggestions::Text, Suggestions::Structured)))
+    }
 
-            return Ok(response);
-        }
+    /// Requests the suggestions as data, calling `on_suggestion` with each one
+    /// as soon as it has streamed in.
+    pub async fn send_structured_streaming<F: FnMut(TodoSuggestion)>(
+        &self,
+        on_suggestion: F,
+    ) -> Result<Option<Suggestions>, Box<dyn Error + Send + Sync>> {
+        let response = self
+            .0
+            .send_json_array_streaming(
+                &provider_system_prompt("suggest-structured", self.0.provider(), STRUCTURED_PROMPT),
+                on_suggestion,
+            )
+            .await?;
+
+        Ok(response.map(|parsed| parsed.map_or_else(Suggestions::Text, Suggestions::Structured)))
+    }
+}
 
-        Ok(None)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::parse_json;
+
+    #[test]
+    fn parses_a_suggestions_payload() {
+        let answer = r#"```json
+[
+  {"line": 3, "comment": "Handle the error instead of unwrapping it."},
+  {"line": 12, "comment": "Use a HashMap for constant-time lookups."}
+]
+```"#;
+
+        let suggestions: Vec<TodoSuggestion> = parse_json(answer).unwrap();
+
+        assert_eq!(
+            suggestions,
+            [
+                TodoSuggestion {
+                    line: 3,
+                    comment: "Handle the error instead of unwrapping it.".to_string(),
+                },
+                TodoSuggestion {
+                    line: 12,
+                    comment: "Use a HashMap for constant-time lookups.".to_string(),
+                },
+            ]
+        );
+        assert!(parse_json::<Vec<TodoSuggestion>>("// TODO: handle errors").is_err());
     }
 }
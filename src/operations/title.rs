@@ -0,0 +1,160 @@
+use std::error::Error;
+
+use log::warn;
+
+use crate::{
+    clients::{api_key, providers::MaxTokens, ChatCompletionClient},
+    config::Config,
+    models::{Message, Role},
+};
+
+/// The number of characters kept of a title taken from the first user message.
+const TITLE_CHARS: usize = 60;
+
+/// The number of characters of each message of the first exchange sent to the
+/// title model, which is all it needs to name the session.
+const EXCHANGE_CHARS: usize = 2000;
+
+/// The most tokens a generated title may use.
+const TITLE_MAX_TOKENS: u32 = 24;
+
+const TITLE_PROMPT: &str = "Write a title of at most six words for the conversation below. Respond with only the title, without quotes or punctuation at the end.";
+
+/// Returns a title for the session: one generated from its first exchange, or
+/// the first user message truncated when the title model fails.
+pub async fn session_title(messages: &[Message]) -> String {
+    match generate_title(messages).await {
+        Ok(Some(title)) => title,
+        Ok(None) => first_message_title(messages),
+        Err(e) => {
+            warn!("Could not generate a session title, using the first message: {e}");
+            first_message_title(messages)
+        }
+    }
+}
+
+/// Asks the configured title model to name the session after its first exchange.
+async fn generate_title(
+    messages: &[Message],
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let Some(user) = messages
+        .iter()
+        .position(|msg| matches!(msg.role, Role::User))
+    else {
+        return Ok(None);
+    };
+    let assistant = messages[user..]
+        .iter()
+        .find(|msg| matches!(msg.role, Role::Assistant));
+
+    let mut exchange = format!("User: {}", head(&messages[user].content));
+    if let Some(assistant) = assistant {
+        exchange.push_str(&format!("\n\nAssistant: {}", head(&assistant.content)));
+    }
+
+    let config = Config::get();
+    let model = config.resolve_model(config.title_model.as_deref());
+    if api_key(model.provider).is_none() {
+        return Err(format!(
+            "{} not set and no key stored with `config set-key`",
+            model.provider.key_var()
+        )
+        .into());
+    }
+
+    let mut client = ChatCompletionClient::new(model.provider, model.model, TITLE_PROMPT)
+        .temperature(Some(0.0))
+        .max_tokens(Some(MaxTokens::Fixed(TITLE_MAX_TOKENS)));
+
+    let response = client
+        .send_message(Message {
+            role: Role::User,
+            content: exchange,
+            reasoning: None,
+            tool_call_id: None,
+            finish_reason: None,
+            metadata: None,
+        })
+        .await?;
+
+    Ok(response
+        .map(|msg| msg.content.trim().trim_matches('"').trim().to_string())
+        .filter(|title| !title.is_empty())
+        .map(|title| truncated_title(&title)))
+}
+
+/// Returns the first user message as a title, truncated.
+pub fn first_message_title(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .find(|msg| matches!(msg.role, Role::User))
+        .map(|msg| truncated_title(&msg.content))
+        .unwrap_or_default()
+}
+
+/// Returns the first non-blank line of `content`, truncated to `TITLE_CHARS`.
+pub fn truncated_title(content: &str) -> String {
+    let line = content.lines().find(|line| !line.trim().is_empty());
+    let line = line.unwrap_or_default().trim();
+
+    if line.chars().count() > TITLE_CHARS {
+        let truncated: String = line.chars().take(TITLE_CHARS).collect();
+        format!("{truncated}...")
+    } else {
+        line.to_string()
+    }
+}
+
+fn head(content: &str) -> String {
+    content.chars().take(EXCHANGE_CHARS).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::clients::{
+        mock_server::{MockResponse, MockServer},
+        providers::Provider,
+    };
+
+    fn exchange() -> Vec<Message> {
+        serde_json::from_str(
+            r#"[
+                {"role": "user", "content": "Why does the parser reject trailing commas in arrays?"},
+                {"role": "assistant", "content": "It checks for a value after every comma."}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn names_the_session_with_the_title_model() {
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            &json!({
+                "choices": [{ "message": { "role": "assistant", "content": "\"Parser trailing commas\"\n" }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 40, "completion_tokens": 4 }
+            }),
+        )])
+        .await;
+
+        assert_eq!(session_title(&exchange()).await, "Parser trailing commas");
+        let sent = &server.requests()[0].body;
+        assert_eq!(sent["max_tokens"], TITLE_MAX_TOKENS);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_first_message_when_titling_fails() {
+        std::env::set_var(Provider::OpenAI.key_var(), "test-key");
+        let _server =
+            MockServer::start(vec![MockResponse::json(500, &json!({ "error": "down" }))]).await;
+
+        assert_eq!(
+            session_title(&exchange()).await,
+            "Why does the parser reject trailing commas in arrays?"
+        );
+    }
+}
@@ -1,12 +1,23 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use handlebars::{no_escape, Handlebars};
+use log::warn;
+use serde_json::json;
 use thiserror::Error;
 
+use crate::{
+    clients::providers::Provider,
+    config::{Config, DataDir},
+};
+
+use super::redact;
+
 #[derive(Error, Debug)]
 pub enum PromptBuilderError {
-    #[error("template error")]
-    TemplateError,
     #[error("render error")]
     RenderError,
 }
@@ -16,24 +27,237 @@ pub struct PromptBuilder<'a> {
 }
 
 impl PromptBuilder<'_> {
-    pub fn new() -> Result<Self, PromptBuilderError> {
-        let default_template = include_str!("prompt.hbs");
+    /// Creates a builder with the built-in prompt templates.
+    ///
+    /// A `prompt.hbs` or `select.hbs` in the data directory's `prompts` folder
+    /// replaces the built-in template of that name. An override that fails to
+    /// parse is ignored with a warning so the builder always has a usable template.
+    pub fn new() -> Self {
+        Self::with_overrides(&DataDir::new().prompts_dir())
+    }
 
+    /// Creates a builder whose templates in `prompts_dir` replace the built-in ones.
+    fn with_overrides(prompts_dir: &Path) -> Self {
         let mut reg = Handlebars::new();
 
         reg.register_escape_fn(no_escape);
 
-        reg.register_template_string("default", default_template)
-            .map_err(|_e| PromptBuilderError::TemplateError)?;
+        register_template(
+            &mut reg,
+            prompts_dir,
+            "default",
+            "prompt.hbs",
+            include_str!("prompt.hbs"),
+        );
+        register_template(
+            &mut reg,
+            prompts_dir,
+            "select",
+            "select.hbs",
+            include_str!("select.hbs"),
+        );
 
-        Ok(Self {
+        Self {
             template_engine: reg,
-        })
+        }
     }
 
+    /// Renders the prompt. Secrets in the `context` entry are redacted unless
+    /// redaction has been turned off.
     pub fn build(&self, data: &HashMap<String, String>) -> Result<String, PromptBuilderError> {
+        let mut data = data.clone();
+        if let Some(context) = data.get_mut("context") {
+            *context = redact(context);
+        }
+
         self.template_engine
             .render("default", &data)
             .map_err(|_e| PromptBuilderError::RenderError)
     }
+
+    /// Renders the prompt asking the model to pick the best of `candidates`,
+    /// which are numbered from 1, as answers to `request`.
+    pub fn build_selection(
+        &self,
+        request: &str,
+        candidates: &[String],
+    ) -> Result<String, PromptBuilderError> {
+        let candidates: Vec<_> = candidates
+            .iter()
+            .enumerate()
+            .map(|(index, text)| json!({ "number": index + 1, "text": text }))
+            .collect();
+
+        self.template_engine
+            .render(
+                "select",
+                &json!({ "request": request, "candidates": candidates }),
+            )
+            .map_err(|_e| PromptBuilderError::RenderError)
+    }
+}
+
+/// Registers the template `name`, preferring `file` in `prompts_dir` over `default`.
+fn register_template(
+    reg: &mut Handlebars,
+    prompts_dir: &Path,
+    name: &str,
+    file: &str,
+    default: &str,
+) {
+    reg.register_template_string(name, default)
+        .expect("built-in prompt template is valid");
+
+    let override_path = prompts_dir.join(file);
+    if let Ok(user_template) = fs::read_to_string(&override_path) {
+        if let Err(e) = reg.register_template_string(name, user_template) {
+            warn!("Ignoring invalid template {}: {e}", override_path.display());
+            reg.register_template_string(name, default)
+                .expect("built-in prompt template is valid");
+        }
+    }
+}
+
+/// The project file holding instructions shared by every operation.
+const PROJECT_INSTRUCTIONS: &str = ".acai/instructions.md";
+
+/// Returns the system prompt named `name`, preferring a user override.
+///
+/// Overrides are read from `<name>.md` in the data directory's `prompts` folder.
+/// Any shared instructions are prepended to the result.
+pub fn system_prompt(name: &str, default: &str) -> String {
+    let override_path = DataDir::new().prompts_dir().join(format!("{name}.md"));
+
+    let prompt = fs::read_to_string(override_path)
+        .ok()
+        .filter(|prompt| !prompt.trim().is_empty())
+        .unwrap_or_else(|| default.to_string());
+
+    with_shared_instructions(prompt)
+}
+
+/// Returns the system prompt named `name` for `provider`, preferring the
+/// provider's prompt from the config file over the generic one.
+pub fn provider_system_prompt(name: &str, provider: Provider, default: &str) -> String {
+    match Config::get().provider_prompt(provider, name) {
+        Some(prompt) => with_shared_instructions(prompt.to_string()),
+        None => system_prompt(name, default),
+    }
+}
+
+/// Prefixes `prompt` with the instructions shared by every operation, such as
+/// coding conventions.
+///
+/// They are read from `shared.md` in the data directory's `prompts` folder and
+/// from `.acai/instructions.md` in the current directory; when both exist the
+/// project instructions follow the user's.
+fn with_shared_instructions(prompt: String) -> String {
+    let sources = [
+        DataDir::new().prompts_dir().join("shared.md"),
+        PathBuf::from(PROJECT_INSTRUCTIONS),
+    ];
+
+    with_instructions_from(&sources, prompt)
+}
+
+/// Prefixes `prompt` with the non-empty files among `sources`, in order.
+fn with_instructions_from(sources: &[PathBuf], prompt: String) -> String {
+    let shared: Vec<String> = sources
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+        .collect();
+
+    if shared.is_empty() {
+        prompt
+    } else {
+        format!("{}\n\n{prompt}", shared.join("\n\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompts_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("acai-prompts-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn data(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| ((*key).to_string(), (*value).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn renders_the_built_in_templates() {
+        let dir = prompts_dir("built-in");
+        let builder = PromptBuilder::with_overrides(&dir);
+
+        let prompt = builder
+            .build(&data(&[
+                ("prompt", "Explain this."),
+                ("context", "fn main() {}"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            prompt.trim(),
+            "Explain this.\n\n\"\"\"\nfn main() {}\n\"\"\""
+        );
+
+        let prompt = builder.build(&data(&[("prompt", "Say hi.")])).unwrap();
+        assert_eq!(prompt.trim(), "Say hi.");
+
+        let selection = builder
+            .build_selection("Say hi.", &["hi".to_string(), "hello".to_string()])
+            .unwrap();
+        assert!(selection.contains("Candidate 1:\n\"\"\"\nhi\n\"\"\""));
+        assert!(selection.contains("Candidate 2:\n\"\"\"\nhello\n\"\"\""));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prefers_valid_user_templates() {
+        let dir = prompts_dir("overrides");
+        fs::write(dir.join("prompt.hbs"), "Task: {{prompt}}").unwrap();
+        fs::write(dir.join("select.hbs"), "{{#each candidates}").unwrap();
+
+        let builder = PromptBuilder::with_overrides(&dir);
+
+        let prompt = builder.build(&data(&[("prompt", "Say hi.")])).unwrap();
+        assert_eq!(prompt, "Task: Say hi.");
+
+        // The broken override falls back to the built-in template.
+        let selection = builder
+            .build_selection("Say hi.", &["hi".to_string()])
+            .unwrap();
+        assert!(selection.starts_with("Below are candidate answers"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prefixes_the_shared_instructions() {
+        let dir = prompts_dir("shared");
+        let user = dir.join("shared.md");
+        let project = dir.join("instructions.md");
+        let missing = dir.join("missing.md");
+        fs::write(&user, "Use tabs.\n").unwrap();
+        fs::write(&project, "Prefer iterators.").unwrap();
+
+        let both = with_instructions_from(
+            &[user.clone(), missing.clone(), project],
+            "Fix the code.".to_string(),
+        );
+        let none = with_instructions_from(&[missing], "Fix the code.".to_string());
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(both, "Use tabs.\n\nPrefer iterators.\n\nFix the code.");
+        assert_eq!(none, "Fix the code.");
+    }
 }
@@ -1,3 +1,5 @@
 mod builder;
+mod redact;
 
 pub use builder::*;
+pub use redact::*;